@@ -0,0 +1,685 @@
+//! The backend as an A2A agent: an agent card plus a JSON-RPC 2.0 endpoint
+//! that accepts orders with `message/send` and tracks each one as a task.
+//!
+//! The wire types mirror `src/a2a/types.py`. A submitted order becomes a task
+//! whose ID is the order ID; its state follows the order through the book
+//! (`submitted` while resting, `working` while in a batch, then `completed`,
+//! `canceled` when cancelled or expired, or `rejected`). A fill is attached as a `fill` artifact carrying the trade
+//! record and the sealed shares of the terms.
+
+use axum::{
+    Json, Router,
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, header::HOST},
+    routing::{get, post},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::info;
+
+use crate::book::{CancelError, OrderEntry, OrderStatus};
+use crate::http_api::{ApiState, Trader};
+use crate::keystore::SignedOrder;
+use crate::validate::RejectCode;
+
+/// Text of the part that carries an order, as sent by `dark_pools_agent.py`.
+pub const SUBMIT_ORDER: &str = "mpc_submit_order";
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const TASK_NOT_FOUND: i64 = -32001;
+pub const TASK_NOT_CANCELABLE: i64 = -32002;
+/// The order failed validation; `data.code` holds the `RejectCode`.
+pub const ORDER_REJECTED: i64 = -32010;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentCard {
+    pub name: String,
+    pub description: String,
+    pub url: String,
+    pub version: String,
+    pub authentication: AgentAuthentication,
+    pub capabilities: AgentCapabilities,
+    pub default_input_modes: Vec<String>,
+    pub default_output_modes: Vec<String>,
+    pub skills: Vec<AgentSkill>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AgentAuthentication {
+    pub schemes: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentCapabilities {
+    pub streaming: bool,
+    pub push_notifications: bool,
+    pub state_transition_history: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentSkill {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub examples: Vec<String>,
+    pub input_modes: Vec<String>,
+    pub output_modes: Vec<String>,
+}
+
+fn agent_card(url: String) -> AgentCard {
+    let json = vec!["application/json".to_owned()];
+    AgentCard {
+        name: "darkpool_backend".into(),
+        description: "Dark pool matching engine: orders are secret-shared between three MPC \
+                      parties and matched in batches."
+            .into(),
+        url,
+        version: env!("CARGO_PKG_VERSION").into(),
+        authentication: AgentAuthentication {
+            schemes: vec!["Bearer".into()],
+        },
+        capabilities: AgentCapabilities {
+            streaming: false,
+            push_notifications: false,
+            state_transition_history: false,
+        },
+        default_input_modes: json.clone(),
+        default_output_modes: json.clone(),
+        skills: vec![AgentSkill {
+            id: "mpc_match_orders".into(),
+            name: "MPC Match Orders".into(),
+            description: format!(
+                "Send a `{}` text part whose metadata carries a signed order (`key_id`, \
+                 `nonce`, `order`, `signature`) as `encrypted_order`, the order sealed to \
+                 the keys at `/party-keys`; the returned task completes when the order \
+                 trades, and any unfilled remainder is cancelled.",
+                SUBMIT_ORDER
+            ),
+            tags: vec!["mpc".into(), "matching".into()],
+            examples: vec!["Buy 100 MSFT at 150 or better, at least 50 at once.".into()],
+            input_modes: json.clone(),
+            output_modes: json,
+        }],
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Agent,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Part {
+    Text {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<Map<String, Value>>,
+    },
+    Data {
+        data: Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<Map<String, Value>>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    pub role: Role,
+    pub parts: Vec<Part>,
+    pub message_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+    /// Always `"message"`; clients may leave it out.
+    #[serde(rename = "type", default = "message_kind")]
+    pub kind: String,
+}
+
+fn message_kind() -> String {
+    "message".into()
+}
+
+impl Message {
+    fn agent(task: &str, context_id: &str, text: String) -> Self {
+        Message {
+            role: Role::Agent,
+            parts: vec![Part::Text {
+                text,
+                metadata: None,
+            }],
+            message_id: format!("{}-status", task),
+            context_id: Some(context_id.to_owned()),
+            task_id: Some(task.to_owned()),
+            metadata: None,
+            kind: message_kind(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TaskState {
+    Submitted,
+    Working,
+    Completed,
+    Canceled,
+    Rejected,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskStatus {
+    pub state: TaskState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<Message>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Artifact {
+    pub artifact_id: String,
+    pub name: String,
+    pub parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Task {
+    pub id: String,
+    pub context_id: String,
+    pub status: TaskStatus,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<Artifact>,
+    /// The submitting part's metadata other than the order, e.g. the
+    /// client's own `order_id` and `agent_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Map<String, Value>>,
+    /// Always `"task"`.
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+#[derive(Deserialize)]
+struct MessageSendParams {
+    message: Message,
+}
+
+/// Params of `tasks/get` and `tasks/cancel`.
+#[derive(Deserialize)]
+struct TaskIdParams {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+impl From<RejectCode> for RpcError {
+    fn from(code: RejectCode) -> Self {
+        RpcError {
+            data: Some(serde_json::json!({ "code": code })),
+            ..RpcError::new(ORDER_REJECTED, code.to_string())
+        }
+    }
+}
+
+impl From<CancelError> for RpcError {
+    fn from(e: CancelError) -> Self {
+        let code = match e {
+            CancelError::UnknownOrder => TASK_NOT_FOUND,
+            _ => TASK_NOT_CANCELABLE,
+        };
+        RpcError::new(code, e.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+impl RpcResponse {
+    fn new(id: Value, result: Result<Value, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        RpcResponse {
+            jsonrpc: "2.0".into(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+/// What the client attached to a task on submit.
+#[derive(Clone, Default)]
+struct TaskContext {
+    context_id: Option<String>,
+    metadata: Option<Map<String, Value>>,
+}
+
+/// Context ID and metadata of each task, as given by the client on submit.
+#[derive(Clone, Default)]
+pub struct Contexts(Arc<Mutex<HashMap<u64, TaskContext>>>);
+
+impl Contexts {
+    /// The context ID, made up when the client gave none, and metadata.
+    fn get(&self, order_id: u64) -> (String, Option<Map<String, Value>>) {
+        let contexts = self.0.lock().unwrap();
+        let context = contexts.get(&order_id).cloned().unwrap_or_default();
+        let context_id = context
+            .context_id
+            .unwrap_or_else(|| format!("order-{}", order_id));
+        (context_id, context.metadata)
+    }
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+/// The signed order carried by the first `mpc_submit_order` part of a
+/// message, and the rest of that part's metadata.
+///
+/// The metadata is what `dark_pools_agent.py` sends: the order under
+/// `encrypted_order`, either as a JSON object or as base64-encoded JSON
+/// (`utils.encrypt_order`), next to the agent's own `order_id`, `agent_id`
+/// and `timestamp`.
+fn order_of(message: &Message) -> Result<(SignedOrder, Map<String, Value>), RpcError> {
+    let metadata = message
+        .parts
+        .iter()
+        .find_map(|part| match part {
+            Part::Text { text, metadata } if text == SUBMIT_ORDER => Some(metadata),
+            _ => None,
+        })
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("no `{}` part", SUBMIT_ORDER)))?;
+    let mut metadata = metadata.clone().unwrap_or_default();
+    let order = metadata.remove("encrypted_order").ok_or_else(|| {
+        RpcError::new(
+            INVALID_PARAMS,
+            "`encrypted_order` missing from part metadata",
+        )
+    })?;
+    let invalid = |e: String| RpcError::new(INVALID_PARAMS, format!("invalid order: {}", e));
+    let order = match order {
+        Value::String(encoded) => {
+            let json = STANDARD
+                .decode(encoded)
+                .map_err(|e| invalid(e.to_string()))?;
+            serde_json::from_slice(&json)
+        }
+        order => serde_json::from_value(order),
+    }
+    .map_err(|e| invalid(e.to_string()))?;
+    Ok((order, metadata))
+}
+
+fn task_id(params: TaskIdParams) -> Result<u64, RpcError> {
+    params
+        .id
+        .parse()
+        .map_err(|_| RpcError::new(TASK_NOT_FOUND, "unknown task"))
+}
+
+/// The task view of one of the caller's orders.
+fn task(state: &ApiState, trader: &str, order_id: u64) -> Result<Task, RpcError> {
+    let entry = state
+        .book
+        .get(order_id)
+        .filter(|entry| entry.order.trader.as_deref() == Some(trader))
+        .ok_or_else(|| RpcError::new(TASK_NOT_FOUND, "unknown task"))?;
+    let id = order_id.to_string();
+    let (context_id, metadata) = state.contexts.get(order_id);
+    let OrderEntry { order, status } = entry;
+    let (status, artifacts) = match status {
+        OrderStatus::Resting => (
+            TaskStatus {
+                state: TaskState::Submitted,
+                message: None,
+                timestamp: Some(order.timestamp),
+            },
+            vec![],
+        ),
+        OrderStatus::InBatch => (
+            TaskStatus {
+                state: TaskState::Working,
+                message: None,
+                timestamp: None,
+            },
+            vec![],
+        ),
+        OrderStatus::Cancelled => (
+            TaskStatus {
+                state: TaskState::Canceled,
+                message: None,
+                timestamp: None,
+            },
+            vec![],
+        ),
+        OrderStatus::Expired => (
+            TaskStatus {
+                state: TaskState::Canceled,
+                message: Some(Message::agent(&id, &context_id, "Order expired".into())),
+                timestamp: None,
+            },
+            vec![],
+        ),
+        OrderStatus::Rejected { code } => (
+            TaskStatus {
+                state: TaskState::Rejected,
+                message: Some(Message::agent(&id, &context_id, code.to_string())),
+                timestamp: None,
+            },
+            vec![],
+        ),
+        OrderStatus::Matched { trade_id } => {
+            let fill = state
+                .book
+                .fill(order_id)
+                .ok_or_else(|| RpcError::new(TASK_NOT_FOUND, "fill not recorded"))?;
+            let text = format!(
+                "Traded in trade {}; any unfilled remainder is cancelled",
+                trade_id
+            );
+            (
+                TaskStatus {
+                    state: TaskState::Completed,
+                    message: Some(Message::agent(&id, &context_id, text)),
+                    timestamp: Some(fill.trade.executed_at),
+                },
+                vec![Artifact {
+                    artifact_id: trade_id,
+                    name: "fill".into(),
+                    parts: vec![Part::Data {
+                        data: serde_json::to_value(fill).unwrap(),
+                        metadata: None,
+                    }],
+                }],
+            )
+        }
+    };
+    Ok(Task {
+        id,
+        context_id,
+        status,
+        artifacts,
+        metadata,
+        kind: "task".into(),
+    })
+}
+
+fn dispatch(state: &ApiState, trader: &str, request: RpcRequest) -> Result<Value, RpcError> {
+    if request.jsonrpc != "2.0" {
+        return Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""));
+    }
+    let task = match request.method.as_str() {
+        "message/send" => {
+            let MessageSendParams { message } = params(request.params)?;
+            let (order, metadata) = order_of(&message)?;
+            let order_id = state.book.submit_signed(order, Some(trader))?;
+            let context = TaskContext {
+                context_id: message.context_id,
+                metadata: Some(metadata).filter(|metadata| !metadata.is_empty()),
+            };
+            state.contexts.0.lock().unwrap().insert(order_id, context);
+            info!("Accepted order {} from {} over A2A", order_id, trader);
+            task(state, trader, order_id)?
+        }
+        "tasks/get" => task(state, trader, task_id(params(request.params)?)?)?,
+        "tasks/cancel" => {
+            let order_id = task_id(params(request.params)?)?;
+            task(state, trader, order_id)?;
+            state.book.cancel(order_id)?;
+            task(state, trader, order_id)?
+        }
+        method => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("method not found: {}", method),
+            ));
+        }
+    };
+    Ok(serde_json::to_value(task).unwrap())
+}
+
+async fn rpc(
+    State(state): State<ApiState>,
+    Trader(trader): Trader,
+    body: Bytes,
+) -> Json<RpcResponse> {
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = RpcError::new(PARSE_ERROR, e.to_string());
+            return Json(RpcResponse::new(Value::Null, Err(error)));
+        }
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let result = match serde_json::from_value::<RpcRequest>(request) {
+        Ok(request) => dispatch(&state, &trader, request),
+        Err(e) => Err(RpcError::new(INVALID_REQUEST, e.to_string())),
+    };
+    Json(RpcResponse::new(id, result))
+}
+
+/// The card points clients at the JSON-RPC endpoint on the host they used.
+async fn card(headers: HeaderMap) -> Json<AgentCard> {
+    let host = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost");
+    Json(agent_card(format!("http://{}/a2a", host)))
+}
+
+/// `GET /.well-known/agent.json` (public) and `POST /a2a` (bearer token),
+/// merged into the HTTP API.
+pub fn routes() -> Router<ApiState> {
+    Router::new()
+        .route("/.well-known/agent.json", get(card))
+        .route("/a2a", post(rpc))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+    };
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use chrono::Utc;
+    use ed25519_dalek::SigningKey;
+    use serde_json::{Value, json};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    use crate::a2a::{
+        AgentCard, METHOD_NOT_FOUND, RpcResponse, TASK_NOT_CANCELABLE, TASK_NOT_FOUND, Task,
+        TaskState,
+    };
+    use crate::batching::BatchPolicy;
+    use crate::book::{OrderBook, unix_nanos};
+    use crate::http_api::{ApiState, router};
+    use crate::keystore::{Keystore, SignedOrder};
+    use crate::report::Trade;
+    use crate::sealed_order::{PartySecret, SealedOrder};
+    use crate::validate::Validator;
+
+    async fn rpc(state: &ApiState, method: &str, params: Value) -> RpcResponse {
+        let body = json!({"jsonrpc": "2.0", "id": 7, "method": method, "params": params});
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/a2a")
+            .header("authorization", "Bearer token-a")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router(state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response: RpcResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(response.id, json!(7));
+        response
+    }
+
+    fn task(response: RpcResponse) -> Task {
+        serde_json::from_value(response.result.unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_order_is_tracked_as_a_task() {
+        let traders = BTreeMap::from([("alice".to_owned(), "token-a".to_owned())]);
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let mut keystore = Keystore::default();
+        keystore.insert("alice-1", "alice", &key.verifying_key());
+        let book = Arc::new(OrderBook::new().with_keystore(keystore));
+        let state = ApiState::new(book.clone(), &traders);
+
+        let request = Request::get("/.well-known/agent.json")
+            .header("host", "pool.example:8081")
+            .body(Body::empty())
+            .unwrap();
+        let response = router(state.clone()).oneshot(request).await.unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let card: AgentCard = serde_json::from_slice(&body).unwrap();
+        assert_eq!(card.url, "http://pool.example:8081/a2a");
+
+        let keys = [0, 1, 2].map(|_| PartySecret::generate().public_key());
+        let seal = |order| {
+            let order = serde_json::from_value(order).unwrap();
+            SealedOrder::seal(&order, &keys, &Validator::default()).unwrap()
+        };
+        let order = seal(json!(
+            {"o_type": false, "symbol": "MSFT", "quantity": 100, "price": 150, "min_execution": 50}
+        ));
+        // As forwarded by `dark_pools_agent.py`: the order base64-encoded
+        // next to the agent's own references.
+        let signed = serde_json::to_vec(&SignedOrder::sign(
+            order,
+            "alice-1",
+            unix_nanos(Utc::now()),
+            &key,
+        ))
+        .unwrap();
+        let message = json!({
+            "role": "user",
+            "messageId": "m1",
+            "contextId": "ctx",
+            "type": "message",
+            "parts": [{"type": "text", "text": "mpc_submit_order", "metadata": {
+                "encrypted_order": STANDARD.encode(signed),
+                "order_id": "agent-order-1",
+                "agent_id": "trading_agent",
+                "timestamp": 1750000000.5,
+            }}]
+        });
+        let submitted = task(rpc(&state, "message/send", json!({ "message": message })).await);
+        assert_eq!(submitted.status.state, TaskState::Submitted);
+        assert_eq!(submitted.context_id, "ctx");
+        let metadata = submitted.metadata.unwrap();
+        assert_eq!(metadata["order_id"], "agent-order-1");
+        assert_eq!(metadata["agent_id"], "trading_agent");
+        assert!(!metadata.contains_key("encrypted_order"));
+        let sell = book
+            .submit(
+                seal(json!({"o_type": true, "symbol": "MSFT", "quantity": 80,
+                "price": 140, "min_execution": 50})),
+                None,
+            )
+            .unwrap();
+
+        let immediately = BatchPolicy {
+            idle_secs: Some(0),
+            ..Default::default()
+        };
+        let batch = book.next_batch(&immediately, "b1").await;
+        let now = Utc::now();
+        book.settle(
+            batch,
+            &[Trade {
+                trade_id: "b1-1".into(),
+                symbol: "MSFT".into(),
+                buy_order_id: submitted.id.parse().unwrap(),
+                sell_order_id: sell,
+                buy_timestamp: now,
+                sell_timestamp: now,
+                executed_at: now,
+            }],
+            &[],
+            None,
+        );
+
+        let filled = task(rpc(&state, "tasks/get", json!({"id": submitted.id})).await);
+        assert_eq!(filled.status.state, TaskState::Completed);
+        assert_eq!(filled.artifacts[0].artifact_id, "b1-1");
+        let error = rpc(&state, "tasks/cancel", json!({"id": submitted.id}))
+            .await
+            .error
+            .unwrap();
+        assert_eq!(error.code, TASK_NOT_CANCELABLE);
+        // The counterparty's order belongs to someone else.
+        let error = rpc(&state, "tasks/get", json!({"id": sell.to_string()}))
+            .await
+            .error
+            .unwrap();
+        assert_eq!(error.code, TASK_NOT_FOUND);
+        let error = rpc(&state, "message/stream", json!({}))
+            .await
+            .error
+            .unwrap();
+        assert_eq!(error.code, METHOD_NOT_FOUND);
+    }
+}
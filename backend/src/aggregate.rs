@@ -0,0 +1,207 @@
+//! Collecting the three parties' results of a batch.
+//!
+//! Every party opens the match set on its own, so an honest run yields the
+//! same trades three times. A party that fails or opens something else is
+//! reported by participant index, and nothing of the batch is published.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+use crate::fill::SealedFill;
+use crate::report::Trade;
+
+/// What one party produced for a batch.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PartyResult {
+    pub trades: Vec<Trade>,
+    /// This party's fill shares, sealed to the counterparties.
+    pub sealed: Vec<SealedFill>,
+    /// Orders dropped by the MPC range check.
+    pub out_of_range: Vec<u64>,
+}
+
+/// The outcome of a batch, or of some of its symbols, all three parties
+/// agree on.
+#[derive(Clone, Debug, Default)]
+pub struct BatchResult {
+    pub trades: Vec<Trade>,
+    /// Every party's sealed fill shares.
+    pub sealed: Vec<SealedFill>,
+    pub out_of_range: Vec<u64>,
+    /// Orders some party could not decrypt its share of; never matched.
+    pub undecryptable: Vec<u64>,
+}
+
+impl BatchResult {
+    /// Add the outcome of other symbols.
+    pub fn merge(&mut self, other: BatchResult) {
+        self.trades.extend(other.trades);
+        self.sealed.extend(other.sealed);
+        self.out_of_range.extend(other.out_of_range);
+        self.undecryptable.extend(other.undecryptable);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AggregateError {
+    /// These parties returned an error or no result at all.
+    PartiesFailed(Vec<(usize, String)>),
+    /// The parties opened different match sets.
+    Diverged(String),
+}
+
+impl std::fmt::Display for AggregateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AggregateError::PartiesFailed(failures) => {
+                write!(f, "parties failed:")?;
+                for (party, error) in failures {
+                    write!(f, " [party {}: {}]", party, error)?;
+                }
+                Ok(())
+            }
+            AggregateError::Diverged(detail) => write!(f, "parties disagree: {}", detail),
+        }
+    }
+}
+
+impl std::error::Error for AggregateError {}
+
+/// The matches a party opened, as order ID pairs.
+fn match_set(trades: &[Trade]) -> BTreeSet<(u64, u64)> {
+    trades
+        .iter()
+        .map(|trade| (trade.buy_order_id, trade.sell_order_id))
+        .collect()
+}
+
+/// Gathers the parties' results as they finish, in any order.
+#[derive(Default)]
+pub struct Collector {
+    results: [Option<Result<PartyResult, String>>; 3],
+}
+
+impl Collector {
+    pub fn record(&mut self, party: usize, result: Result<PartyResult, String>) {
+        self.results[party] = Some(result);
+    }
+
+    /// Check that every party finished and that all opened the same match
+    /// set and range-check failures.
+    pub fn finish(self) -> Result<BatchResult, AggregateError> {
+        let mut results = Vec::with_capacity(3);
+        let mut failures = vec![];
+        for (party, result) in self.results.into_iter().enumerate() {
+            match result {
+                Some(Ok(result)) => results.push((party, result)),
+                Some(Err(error)) => failures.push((party, error)),
+                None => failures.push((party, "no result".to_owned())),
+            }
+        }
+        if !failures.is_empty() {
+            return Err(AggregateError::PartiesFailed(failures));
+        }
+
+        let (_, first) = &results[0];
+        let expected = match_set(&first.trades);
+        for (party, result) in &results[1..] {
+            let opened = match_set(&result.trades);
+            if result.trades.len() != first.trades.len() || opened != expected {
+                return Err(AggregateError::Diverged(format!(
+                    "party 0 opened {} matches {:?}, party {} opened {} matches {:?}",
+                    first.trades.len(),
+                    expected,
+                    party,
+                    result.trades.len(),
+                    opened
+                )));
+            }
+            let ids = |trades: &[Trade]| -> Vec<String> {
+                trades.iter().map(|t| t.trade_id.clone()).collect()
+            };
+            if ids(&result.trades) != ids(&first.trades) {
+                return Err(AggregateError::Diverged(format!(
+                    "party {} numbered the trades differently",
+                    party
+                )));
+            }
+            if result.out_of_range != first.out_of_range {
+                return Err(AggregateError::Diverged(format!(
+                    "party 0 range-rejected {:?}, party {} {:?}",
+                    first.out_of_range, party, result.out_of_range
+                )));
+            }
+        }
+
+        let mut results = results.into_iter().map(|(_, result)| result);
+        let first = results.next().unwrap();
+        let mut sealed = first.sealed;
+        for result in results {
+            sealed.extend(result.sealed);
+        }
+        Ok(BatchResult {
+            trades: first.trades,
+            sealed,
+            out_of_range: first.out_of_range,
+            undecryptable: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::aggregate::{AggregateError, Collector, PartyResult};
+    use crate::report::Trade;
+
+    fn result(matches: &[(u64, u64)]) -> PartyResult {
+        let now = Utc::now();
+        let trades = matches
+            .iter()
+            .enumerate()
+            .map(|(n, &(buy_order_id, sell_order_id))| Trade {
+                trade_id: format!("b1-{}", n + 1),
+                symbol: "MSFT".into(),
+                buy_order_id,
+                sell_order_id,
+                buy_timestamp: now,
+                sell_timestamp: now,
+                executed_at: now,
+            })
+            .collect();
+        PartyResult {
+            trades,
+            sealed: vec![],
+            out_of_range: vec![],
+        }
+    }
+
+    #[test]
+    fn test_failures_and_divergence_block_the_batch() {
+        let mut agreed = Collector::default();
+        for party in [2, 0, 1] {
+            agreed.record(party, Ok(result(&[(1, 2)])));
+        }
+        assert_eq!(agreed.finish().unwrap().trades.len(), 1);
+
+        let mut failed = Collector::default();
+        failed.record(0, Ok(result(&[(1, 2)])));
+        failed.record(2, Err("abort".into()));
+        assert_eq!(
+            failed.finish().unwrap_err(),
+            AggregateError::PartiesFailed(vec![(1, "no result".into()), (2, "abort".into())])
+        );
+
+        for other in [&[(1, 3)][..], &[(1, 2), (4, 5)], &[]] {
+            let mut diverged = Collector::default();
+            diverged.record(0, Ok(result(&[(1, 2)])));
+            diverged.record(1, Ok(result(&[(1, 2)])));
+            diverged.record(2, Ok(result(other)));
+            assert!(matches!(
+                diverged.finish(),
+                Err(AggregateError::Diverged(_))
+            ));
+        }
+    }
+}
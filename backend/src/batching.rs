@@ -0,0 +1,228 @@
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// When the batch loop cuts a batch from the resting orders.
+///
+/// Every configured trigger is armed at once and the first to fire cuts the
+/// batch. Triggers only fire while orders submitted since the last batch are
+/// waiting: orders that already failed to match cannot match each other.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchPolicy {
+    /// Match once no order has arrived for this many seconds. A steady
+    /// trickle of orders keeps postponing it; pair it with `max_latency_secs`.
+    pub idle_secs: Option<u64>,
+    /// Match at the end of every epoch of this many seconds, counted from the
+    /// start of the batch loop.
+    pub interval_secs: Option<u64>,
+    /// Match as soon as this many orders have arrived since the last batch;
+    /// orders that already failed to match do not count. Also caps the size
+    /// of every batch: new orders go first, in order of arrival, and any room
+    /// left goes to the oldest unmatched ones. More new orders than that are
+    /// matched in consecutive batches.
+    pub max_orders: Option<usize>,
+    /// Match at the latest this many seconds after the first order not yet
+    /// seen by a batch arrived.
+    pub max_latency_secs: Option<u64>,
+    /// Daily call auctions at these times (UTC, `"HH:MM:SS"`).
+    pub auction_times: Vec<NaiveTime>,
+}
+
+impl Default for BatchPolicy {
+    fn default() -> Self {
+        BatchPolicy {
+            idle_secs: Some(5),
+            interval_secs: None,
+            max_orders: None,
+            max_latency_secs: None,
+            auction_times: vec![],
+        }
+    }
+}
+
+/// What cut a batch.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    Idle,
+    Interval,
+    MaxOrders,
+    MaxLatency,
+    Auction,
+}
+
+/// Orders waiting for the next batch.
+#[derive(Clone, Copy, Debug)]
+pub struct Pending {
+    /// Arrival of the first order not yet seen by a batch.
+    pub first: Instant,
+    /// Arrival of the latest order.
+    pub last: Instant,
+    /// Orders submitted since the last batch and still resting.
+    pub arrived: usize,
+}
+
+impl BatchPolicy {
+    /// Whether any trigger is configured; without one nothing is ever matched.
+    pub fn is_armed(&self) -> bool {
+        self.idle_secs.is_some()
+            || self.interval_secs.is_some()
+            || self.max_orders.is_some()
+            || self.max_latency_secs.is_some()
+            || !self.auction_times.is_empty()
+    }
+
+    /// When the pending orders are to be matched, and by which trigger.
+    /// `epoch` is the start of the batch loop; `wall` is the current UTC time
+    /// at `now`, for the auction schedule.
+    pub fn next_cut(
+        &self,
+        pending: &Pending,
+        epoch: Instant,
+        now: Instant,
+        wall: DateTime<Utc>,
+    ) -> Option<(Instant, Trigger)> {
+        let secs = Duration::from_secs;
+        let interval = self.interval_secs.filter(|&s| s > 0).map(|s| {
+            let interval = secs(s);
+            let elapsed = now.saturating_duration_since(epoch);
+            let epochs = elapsed.as_nanos().div_ceil(interval.as_nanos()) as u32;
+            epoch + interval * epochs.max(1)
+        });
+        let full = self
+            .max_orders
+            .filter(|&max| pending.arrived >= max)
+            .map(|_| now);
+        let auction = self.next_auction(wall).map(|wait| now + wait);
+        [
+            (full, Trigger::MaxOrders),
+            (
+                self.idle_secs.map(|s| pending.last + secs(s)),
+                Trigger::Idle,
+            ),
+            (interval, Trigger::Interval),
+            (
+                self.max_latency_secs.map(|s| pending.first + secs(s)),
+                Trigger::MaxLatency,
+            ),
+            (auction, Trigger::Auction),
+        ]
+        .into_iter()
+        .filter_map(|(deadline, trigger)| deadline.map(|deadline| (deadline, trigger)))
+        .min_by_key(|(deadline, _)| *deadline)
+    }
+
+    /// Time from `wall` to the next scheduled auction, zero if one is due now.
+    pub fn next_auction(&self, wall: DateTime<Utc>) -> Option<Duration> {
+        let time = wall.time();
+        self.auction_times
+            .iter()
+            .map(|&auction| {
+                let wait = auction - time;
+                if wait < chrono::TimeDelta::zero() {
+                    wait + chrono::TimeDelta::days(1)
+                } else {
+                    wait
+                }
+            })
+            .min()
+            .map(|wait| wait.to_std().unwrap_or_default())
+    }
+}
+
+/// Batch sizes and order waiting times since the start, for status endpoints.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BatchMetrics {
+    pub batches: u64,
+    pub orders: u64,
+    pub largest_batch: usize,
+    pub mean_batch_size: f64,
+    /// Seconds from an order's submission until the batch that took it was
+    /// cut. An order that rests again counts once more in its next batch.
+    pub mean_wait_secs: f64,
+    pub max_wait_secs: f64,
+    /// How many batches each trigger cut.
+    pub triggers: BTreeMap<Trigger, u64>,
+}
+
+impl BatchMetrics {
+    /// Record a batch cut at `cut` of orders submitted at `submitted`.
+    pub fn record(&mut self, trigger: Trigger, submitted: &[DateTime<Utc>], cut: DateTime<Utc>) {
+        let waits: Vec<f64> = submitted
+            .iter()
+            .map(|&at| (cut - at).to_std().unwrap_or_default().as_secs_f64())
+            .collect();
+        let total_wait = self.mean_wait_secs * self.orders as f64 + waits.iter().sum::<f64>();
+        self.batches += 1;
+        self.orders += submitted.len() as u64;
+        self.largest_batch = self.largest_batch.max(submitted.len());
+        self.mean_batch_size = self.orders as f64 / self.batches as f64;
+        if self.orders > 0 {
+            self.mean_wait_secs = total_wait / self.orders as f64;
+        }
+        self.max_wait_secs = waits.into_iter().fold(self.max_wait_secs, f64::max);
+        *self.triggers.entry(trigger).or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveTime, TimeZone, Utc};
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    use crate::batching::{BatchPolicy, Pending, Trigger};
+
+    #[test]
+    fn test_earliest_trigger_wins() {
+        let policy = BatchPolicy {
+            idle_secs: Some(5),
+            interval_secs: Some(60),
+            max_orders: Some(10),
+            max_latency_secs: Some(20),
+            auction_times: vec![NaiveTime::from_hms_opt(9, 30, 0).unwrap()],
+        };
+        let epoch = Instant::now();
+        let wall = Utc.with_ymd_and_hms(2026, 1, 5, 9, 29, 0).unwrap();
+        let secs = Duration::from_secs;
+        let pending = |first, last, arrived| Pending {
+            first: epoch + secs(first),
+            last: epoch + secs(last),
+            arrived,
+        };
+        let cut = |pending: &Pending, now| policy.next_cut(pending, epoch, epoch + secs(now), wall);
+
+        // Orders keep coming: the idle period never ends, the latency cap does.
+        assert_eq!(
+            cut(&pending(0, 18, 3), 18),
+            Some((epoch + secs(20), Trigger::MaxLatency))
+        );
+        assert_eq!(
+            cut(&pending(0, 1, 3), 1),
+            Some((epoch + secs(6), Trigger::Idle))
+        );
+        assert_eq!(
+            cut(&pending(50, 58, 3), 58),
+            Some((epoch + secs(60), Trigger::Interval))
+        );
+        assert_eq!(
+            cut(&pending(0, 1, 10), 1),
+            Some((epoch + secs(1), Trigger::MaxOrders))
+        );
+        // One minute to the auction.
+        let auction_only = BatchPolicy {
+            idle_secs: None,
+            auction_times: policy.auction_times.clone(),
+            ..Default::default()
+        };
+        assert_eq!(
+            auction_only.next_cut(&pending(0, 0, 1), epoch, epoch, wall),
+            Some((epoch + secs(60), Trigger::Auction))
+        );
+        let after = Utc.with_ymd_and_hms(2026, 1, 5, 9, 30, 1).unwrap();
+        assert_eq!(auction_only.next_auction(after), Some(secs(24 * 3600 - 1)));
+    }
+}
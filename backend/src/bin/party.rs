@@ -0,0 +1,164 @@
+use anyhow::{Context, Result};
+use backend::capture::{CaptureKey, CaptureWriter};
+use backend::config::{LogLevel, PartyConfig, TlsConfig, client_tls_config, init_logging, load};
+use backend::evidence::load_evidence_key;
+use backend::party::PartyHost;
+use backend::sealed_order::PartySecret;
+use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+/// One MPC party of the dark pool. Holds only its own secret key and runs
+/// its side of every symbol's session for the backend.
+///
+/// Settings come from the optional TOML `--config` file; flags given on the
+/// command line override it.
+#[derive(Parser)]
+struct Args {
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Participant index: 0, 1 or 2.
+    #[arg(long)]
+    party: Option<usize>,
+    /// The party's secret key, a file of 64 hex digits.
+    #[arg(long)]
+    secret_key: Option<PathBuf>,
+    /// The party's evidence signing key, a file of 64 hex digits.
+    #[arg(long)]
+    evidence_key: Option<PathBuf>,
+    /// Where the backend connects to drive the party.
+    #[arg(long)]
+    control_listen: Option<SocketAddr>,
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Token the backend must present.
+    #[arg(long)]
+    control_token: Option<String>,
+    /// Relay URL the party's sessions connect to.
+    #[arg(long)]
+    relay_url: Option<String>,
+    /// Extra CA certificate (PEM) trusted for a `wss://` relay URL.
+    #[arg(long)]
+    relay_ca_cert: Option<PathBuf>,
+    /// Record the party's relay traffic to this file.
+    #[arg(long)]
+    capture: Option<PathBuf>,
+    /// Hex public key the capture is encrypted to.
+    #[arg(long)]
+    capture_key: Option<CaptureKey>,
+    #[arg(long, value_enum)]
+    log_level: Option<LogLevel>,
+}
+
+impl Args {
+    fn into_config(self) -> Result<PartyConfig> {
+        let mut config: PartyConfig = load(self.config.as_deref())?;
+        if let Some(party) = self.party {
+            config.party = party;
+        }
+        if self.secret_key.is_some() {
+            config.secret_key = self.secret_key;
+        }
+        if self.evidence_key.is_some() {
+            config.evidence_key = self.evidence_key;
+        }
+        if let Some(control_listen) = self.control_listen {
+            config.control_listen = control_listen;
+        }
+        if let (Some(cert_path), Some(key_path)) = (self.tls_cert, self.tls_key) {
+            config.tls = Some(TlsConfig {
+                cert_path,
+                key_path,
+            });
+        }
+        if self.control_token.is_some() {
+            config.control_token = self.control_token;
+        }
+        if let Some(relay_url) = self.relay_url {
+            config.relay_url = relay_url;
+        }
+        if self.relay_ca_cert.is_some() {
+            config.relay_ca_cert = self.relay_ca_cert;
+        }
+        if self.capture.is_some() {
+            config.capture = self.capture;
+        }
+        if self.capture_key.is_some() {
+            config.capture_key = self.capture_key;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        Ok(config)
+    }
+}
+
+async fn run(config: PartyConfig) -> Result<()> {
+    let path = config
+        .secret_key
+        .as_deref()
+        .context("No secret_key configured; the party cannot open its order shares without it")?;
+    let secret = PartySecret::load(path)?;
+    let path = config
+        .evidence_key
+        .as_deref()
+        .context("No evidence_key configured; the party cannot sign evidence without it")?;
+    let evidence_key = load_evidence_key(path)?;
+    let token = config
+        .control_token
+        .clone()
+        .context("No control_token configured")?;
+    let mut host = PartyHost::new(
+        config.party,
+        secret,
+        evidence_key,
+        token,
+        config.relay_url.clone(),
+    )?;
+    host = host.with_recv_timeout(Duration::from_secs(config.recv_timeout_secs));
+    if let Some(ca) = &config.relay_ca_cert {
+        host = host.with_tls(client_tls_config(ca)?);
+    }
+    // Optionally record the party's traffic for offline replay (see `bin/replay.rs`).
+    if let Some(capture) =
+        CaptureWriter::from_config(config.capture.as_deref(), config.capture_key)?
+    {
+        host = host.with_capture(capture);
+    }
+    let tls = config.tls.as_ref().map(TlsConfig::acceptor).transpose()?;
+    let scheme = if tls.is_some() { "tls" } else { "tcp" };
+
+    let listener = TcpListener::bind(config.control_listen)
+        .await
+        .with_context(|| format!("Failed to bind {}", config.control_listen))?;
+    info!(
+        "Party {} listening on {}://{} with key {}",
+        config.party,
+        scheme,
+        config.control_listen,
+        hex::encode(host.hello().key.0)
+    );
+    Arc::new(host).serve(listener, tls).await
+}
+
+#[tokio::main]
+async fn main() {
+    let config = match Args::parse().into_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(2);
+        }
+    };
+    init_logging(config.log_level);
+    if let Err(e) = run(config).await {
+        error!("Party failed: {:#}", e);
+        std::process::exit(1);
+    }
+}
@@ -1,10 +1,129 @@
+use anyhow::Result;
+use backend::capture::{CaptureKey, CaptureWriter};
+use backend::config::{LogLevel, RelayServerConfig, TlsConfig, init_logging, load};
+use backend::relay::{RelayServer, ServerOptions};
+use clap::Parser;
 use std::net::SocketAddr;
-use backend::relay::run_server;
+use std::path::PathBuf;
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tracing::{error, info};
+
+/// Message relay shared by the MPC parties.
+///
+/// Settings come from the optional TOML `--config` file; flags given on the
+/// command line override it.
+#[derive(Parser)]
+struct Args {
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// WebSocket listen address.
+    #[arg(long)]
+    listen: Option<SocketAddr>,
+    /// Length-prefixed TCP listen address.
+    #[arg(long)]
+    tcp_listen: Option<SocketAddr>,
+    /// Length-prefixed Unix domain socket path.
+    #[arg(long)]
+    unix_listen: Option<PathBuf>,
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    #[arg(long)]
+    max_frame_size: Option<usize>,
+    /// Record all relayed traffic to this file.
+    #[arg(long)]
+    capture: Option<PathBuf>,
+    /// Hex public key the capture is encrypted to.
+    #[arg(long)]
+    capture_key: Option<CaptureKey>,
+    #[arg(long, value_enum)]
+    log_level: Option<LogLevel>,
+}
+
+impl Args {
+    fn into_config(self) -> Result<RelayServerConfig> {
+        let mut config: RelayServerConfig = load(self.config.as_deref())?;
+        if let Some(listen) = self.listen {
+            config.listen = listen;
+        }
+        if self.tcp_listen.is_some() {
+            config.tcp_listen = self.tcp_listen;
+        }
+        if self.unix_listen.is_some() {
+            config.unix_listen = self.unix_listen;
+        }
+        if let (Some(cert_path), Some(key_path)) = (self.tls_cert, self.tls_key) {
+            config.tls = Some(TlsConfig {
+                cert_path,
+                key_path,
+            });
+        }
+        if let Some(max_frame_size) = self.max_frame_size {
+            config.limits.max_frame_size = max_frame_size;
+        }
+        if self.capture.is_some() {
+            config.capture = self.capture;
+        }
+        if self.capture_key.is_some() {
+            config.capture_key = self.capture_key;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        Ok(config)
+    }
+}
+
+async fn run(config: RelayServerConfig) -> Result<()> {
+    let options = ServerOptions {
+        tls: config.tls.as_ref().map(TlsConfig::acceptor).transpose()?,
+        faults: config.faults,
+        // Record all relayed traffic for offline replay (see `bin/replay.rs`).
+        capture: CaptureWriter::from_config(config.capture.as_deref(), config.capture_key)?,
+        limits: config.limits,
+    };
+    let scheme = if options.tls.is_some() { "wss" } else { "ws" };
+    // One broker shared by every listener.
+    let server = RelayServer::new(options);
+    let mut listeners = JoinSet::new();
+
+    let listener = TcpListener::bind(config.listen).await?;
+    info!("Relay server listening on {}://{}", scheme, config.listen);
+    listeners.spawn(server.clone().serve_websocket(listener));
+
+    if let Some(addr) = config.tcp_listen {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Relay server listening on tcp://{}", addr);
+        listeners.spawn(server.clone().serve_tcp(listener));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_listen {
+        let listener = tokio::net::UnixListener::bind(path)?;
+        info!("Relay server listening on unix://{}", path.display());
+        listeners.spawn(server.clone().serve_unix(listener));
+    }
+
+    // Listeners only return on error.
+    match listeners.join_next().await {
+        Some(res) => res?,
+        None => Ok(()),
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let addr: SocketAddr = "0.0.0.0:9007".parse().expect("Invalid address");
-    if let Err(e) = run_server(addr).await {
-        eprintln!("Relay server failed: {}", e);
+    let config = match Args::parse().into_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(2);
+        }
+    };
+    init_logging(config.log_level);
+    if let Err(e) = run(config).await {
+        error!("Relay server failed: {:#}", e);
     }
-} 
+}
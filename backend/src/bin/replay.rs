@@ -0,0 +1,191 @@
+//! Re-run one party of a recorded batch offline.
+//!
+//! Usage: `replay run <capture-file> <conn-id> <party-secret-key-file> <capture-secret-key-file>`
+//!
+//! `replay capture-key <capture-secret-key-file>` prints the public key to
+//! configure as `capture_key` for a capture secret (64 hex digits).
+//!
+//! The capture must contain the `session` record the party wrote before its
+//! first frame (captures taken by a party process with `--capture`). The party's
+//! setup and seed are rebuilt from the recorded instance ID, then every
+//! recorded epoch, re-key and preprocessing step of the session is run
+//! again, its order shares decrypted and its preprocessing randomness
+//! derived with its secret key, and the frames it originally received are
+//! fed back to it in order.
+use anyhow::{Context, Result};
+use backend::capture::{CaptureRecord, CaptureSecret, ReplayRelay, read_capture};
+use backend::evidence::Witness;
+use backend::sealed_order::{PartySecret, open_order_shares};
+use backend::v1::{Session, setup_party_v1};
+use clap::{Parser, Subcommand};
+use ed25519_dalek::SigningKey;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use std::path::{Path, PathBuf};
+
+/// Re-run one party of a recorded batch offline.
+#[derive(Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Replay the session of connection `conn` in a capture.
+    Run {
+        capture: PathBuf,
+        /// Connection ID of the party's session in the capture.
+        conn: u64,
+        /// The party's secret key, a file of 64 hex digits.
+        secret_key: PathBuf,
+        /// The capture's secret key, a file of 64 hex digits.
+        capture_secret: PathBuf,
+    },
+    /// Print the public key to configure as `capture_key` for a capture
+    /// secret.
+    CaptureKey { capture_secret: PathBuf },
+}
+
+#[tokio::main]
+async fn main() {
+    let result = match Args::parse().command {
+        Command::Run {
+            capture,
+            conn,
+            secret_key,
+            capture_secret,
+        } => replay(&capture, conn, &secret_key, &capture_secret).await,
+        Command::CaptureKey { capture_secret } => CaptureSecret::load(&capture_secret)
+            .map(|secret| println!("{}", hex::encode(secret.public_key().0))),
+    };
+    if let Err(e) = result {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn replay(path: &Path, conn: u64, key: &Path, capture_key: &Path) -> Result<()> {
+    let secret = PartySecret::load(key).context("Failed to load party key")?;
+    let capture_secret = CaptureSecret::load(capture_key).context("Failed to load capture key")?;
+
+    let records = read_capture(path, &capture_secret).context("Failed to read capture")?;
+    let Some((instance, party, fresh, orders, widths, min_quantity)) =
+        records.iter().find_map(|record| match record {
+            CaptureRecord::Session {
+                conn: c,
+                instance,
+                party,
+                fresh,
+                orders,
+                widths,
+                min_quantity,
+            } if *c == conn => Some((*instance, *party, *fresh, orders, *widths, *min_quantity)),
+            _ => None,
+        })
+    else {
+        anyhow::bail!(
+            "No session record for connection {} in {}",
+            conn,
+            path.display()
+        );
+    };
+
+    // The epochs of the first session in order, with the re-keys and
+    // preprocessing in between.
+    // A single-batch session has its orders in the session record.
+    let mut steps: Vec<_> = records
+        .iter()
+        .skip_while(
+            |record| !matches!(record, CaptureRecord::Session { conn: c, .. } if *c == conn),
+        )
+        .skip(1)
+        .take_while(
+            |record| !matches!(record, CaptureRecord::Session { conn: c, .. } if *c == conn),
+        )
+        .filter(|record| match record {
+            CaptureRecord::Epoch { conn: c, .. }
+            | CaptureRecord::Rekey { conn: c, .. }
+            | CaptureRecord::Preprocess { conn: c, .. } => *c == conn,
+            _ => false,
+        })
+        .cloned()
+        .collect();
+    if steps.is_empty() {
+        steps.push(CaptureRecord::Epoch {
+            conn,
+            instance,
+            orders: orders.clone(),
+        });
+    }
+
+    println!(
+        "Replaying party {} of instance {} ({} steps)",
+        party,
+        hex::encode(instance),
+        steps.len()
+    );
+    let relay = ReplayRelay::new(&records, conn);
+    let (setup, seed) = setup_party_v1(instance, party, fresh);
+    let mut session = match Session::establish(setup, seed, relay).await {
+        Ok(session) => session.with_widths(widths).with_min_quantity(min_quantity),
+        Err(e) => {
+            println!("Replay aborted: {}", e);
+            return Ok(());
+        }
+    };
+    // Evidence of a replay is for reading, not for attribution, so a
+    // throwaway key signs it.
+    let witness = Witness::new(instance, &SigningKey::from_bytes(&rand::random()));
+    let mut rng = ChaCha20Rng::from_seed(secret.preprocessing_seed(&instance));
+    for step in steps {
+        let result = match step {
+            CaptureRecord::Rekey {
+                instance, fresh, ..
+            } => {
+                println!("Re-keying under instance {}", hex::encode(instance));
+                let (setup, seed) = setup_party_v1(instance, party, fresh);
+                session.rekey(setup, seed).await.map(|()| None)
+            }
+            CaptureRecord::Preprocess { triples, .. } => {
+                println!("Preprocessing {} triples", triples);
+                session.preprocess(triples, &mut rng).await.map(|()| None)
+            }
+            CaptureRecord::Epoch {
+                instance, orders, ..
+            } => {
+                // Only this party's shares are known; the others are never used.
+                let share = open_order_shares(party, &secret, &orders)
+                    .context("The capture holds orders the party could not decrypt")?;
+                println!("Epoch {} ({} orders)", hex::encode(instance), orders.len());
+                let witness = witness.for_instance(instance);
+                session.run_epoch(&share, &witness).await.map(Some)
+            }
+            _ => unreachable!(),
+        };
+        match result {
+            Ok(Some(output)) => {
+                println!("Epoch completed with {} matches", output.fills.len());
+                for id in &output.out_of_range {
+                    println!("order {} failed the range check", id);
+                }
+                for fill in &output.fills {
+                    println!(
+                        "{}: buy order {} sell order {}",
+                        fill.symbol, fill.buy_order_id, fill.sell_order_id
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                println!("Replay aborted: {}", e);
+                if let Some(evidence) = e.evidence {
+                    println!("{}", serde_json::to_string_pretty(&evidence)?);
+                }
+                return Ok(());
+            }
+        }
+    }
+    session.close().await;
+    Ok(())
+}
@@ -0,0 +1,910 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::batching::{BatchMetrics, BatchPolicy, Pending, Trigger};
+use crate::feed::{ExecReport, ExecType};
+use crate::fill::SealedFill;
+use crate::instruments::{Instrument, InstrumentMaster};
+use crate::keystore::{Keystore, OrderAuth, RequestKind, SignedOrder, SignedRequest};
+use crate::price::PriceScale;
+use crate::report::Trade;
+use crate::sealed_order::{PartyKey, SealedOrder};
+use crate::supervisor::Attempt;
+use crate::types::BookOrder;
+use crate::validate::{BitWidths, RejectCode, Validator, check_client_key};
+
+/// Where an order is in its life cycle.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum OrderStatus {
+    /// Waiting for the next batch. Unmatched orders return here.
+    Resting,
+    /// Being matched right now; can no longer be cancelled.
+    InBatch,
+    /// Traded in `trade_id`. Final: orders trade once, and any part of the
+    /// quantity the trade did not cover is cancelled with it. Only the
+    /// trader learns whether there was such a part (see `fill::Fill`).
+    Matched {
+        trade_id: String,
+    },
+    Cancelled,
+    /// Rested past the order time-to-live without matching.
+    Expired,
+    /// Dropped from a batch because its shares failed the range check.
+    Rejected {
+        code: RejectCode,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CancelError {
+    UnknownOrder,
+    InBatch,
+    Matched,
+    AlreadyCancelled,
+    Expired,
+    Rejected,
+}
+
+impl std::fmt::Display for CancelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CancelError::UnknownOrder => write!(f, "unknown order"),
+            CancelError::InBatch => write!(f, "order is being matched"),
+            CancelError::Matched => write!(f, "order is already matched"),
+            CancelError::AlreadyCancelled => write!(f, "order is already cancelled"),
+            CancelError::Expired => write!(f, "order has expired"),
+            CancelError::Rejected => write!(f, "order was rejected"),
+        }
+    }
+}
+
+/// An order together with its current status.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrderEntry {
+    #[serde(flatten)]
+    pub order: BookOrder,
+    pub status: OrderStatus,
+}
+
+/// One side of a trade as seen by the trader who placed that order: the
+/// public trade record plus the nodes' sealed shares of the terms.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TraderFill {
+    pub order_id: u64,
+    pub trade: Trade,
+    pub shares: Vec<SealedFill>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchSummary {
+    pub batch_id: String,
+    pub orders: usize,
+    pub trigger: Trigger,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub trades: usize,
+    pub error: Option<String>,
+    /// Every run of the protocol on the batch so far.
+    pub attempts: Vec<Attempt>,
+}
+
+/// What the batch loop is doing, for status endpoints.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BatchState {
+    pub resting_orders: usize,
+    pub batches_run: u64,
+    /// The batch being matched right now, if any.
+    pub current: Option<BatchSummary>,
+    pub last: Option<BatchSummary>,
+    pub metrics: BatchMetrics,
+    /// AND triples the parties have preprocessed for the next batches.
+    pub preprocessed_triples: usize,
+}
+
+#[derive(Default)]
+struct BookState {
+    next_id: u64,
+    orders: HashMap<u64, OrderEntry>,
+    resting: BTreeSet<u64>,
+    trades: HashMap<String, Trade>,
+    sealed: HashMap<u64, Vec<SealedFill>>,
+    batches: BatchState,
+    /// Every (nonce, key ID) pair accepted within the nonce window, oldest
+    /// first.
+    nonces: BTreeSet<(u64, String)>,
+    /// First and last submit not yet seen by a batch. Unmatched orders
+    /// alone do not trigger another batch: nothing new could match them.
+    first_submit: Option<Instant>,
+    last_submit: Option<Instant>,
+    /// Highest order ID a batch has taken; higher IDs are new.
+    seen: u64,
+    /// The last batch was capped and left new orders behind.
+    backlog: bool,
+    /// When the batch loop first waited, the origin of interval epochs.
+    epoch: Option<Instant>,
+    /// Expiry deadline of each order, when orders have a time-to-live.
+    expiries: HashMap<u64, Instant>,
+    by_expiry: BTreeSet<(Instant, u64)>,
+    /// Execution reports not yet published, with the trader they are for.
+    outbox: Vec<(String, ExecReport)>,
+}
+
+impl BookState {
+    /// Change the status of an order and queue the execution report for its
+    /// trader. Fills must be recorded before their orders are matched.
+    fn set_status(&mut self, id: u64, status: OrderStatus) {
+        let Some(entry) = self.orders.get_mut(&id) else {
+            return;
+        };
+        entry.status = status;
+        let Some(trader) = entry.order.trader.clone() else {
+            return;
+        };
+        let exec = match &entry.status {
+            OrderStatus::Resting | OrderStatus::InBatch => return,
+            OrderStatus::Cancelled => ExecType::Cancelled,
+            OrderStatus::Expired => ExecType::Expired,
+            OrderStatus::Rejected { code } => ExecType::Rejected { code: *code },
+            OrderStatus::Matched { .. } => match self.fill(&self.orders[&id]) {
+                Some(fill) => ExecType::Traded { fill },
+                None => return,
+            },
+        };
+        self.outbox.push((trader, ExecReport::new(id, exec)));
+    }
+
+    /// Rest an order from a batch again, unless its time ran out meanwhile.
+    fn rest_again(&mut self, id: u64, now: Instant) {
+        if self.expiries.get(&id).is_some_and(|&expiry| expiry <= now) {
+            self.set_status(id, OrderStatus::Expired);
+        } else {
+            self.set_status(id, OrderStatus::Resting);
+            self.resting.insert(id);
+        }
+    }
+
+    /// Expire resting orders past their deadline and return the next one.
+    fn expire(&mut self, now: Instant) -> Option<Instant> {
+        while let Some(&(expiry, id)) = self.by_expiry.first() {
+            if expiry > now {
+                return Some(expiry);
+            }
+            self.by_expiry.pop_first();
+            // Orders in a batch are checked when they come back.
+            if self.resting.remove(&id) {
+                self.set_status(id, OrderStatus::Expired);
+            }
+        }
+        None
+    }
+
+    fn finish_batch(&mut self, trades: usize, error: Option<String>) {
+        if let Some(mut summary) = self.batches.current.take() {
+            summary.finished_at = Some(Utc::now());
+            summary.trades = trades;
+            summary.error = error;
+            self.batches.last = Some(summary);
+        }
+        self.batches.batches_run += 1;
+    }
+
+    fn cut_batch(
+        &mut self,
+        max_orders: Option<usize>,
+        trigger: Trigger,
+        batch_id: &str,
+    ) -> Vec<BookOrder> {
+        // New orders first, so a backlog of unmatched ones cannot starve
+        // them; the remaining room goes to the oldest unmatched orders.
+        let take = max_orders.unwrap_or(usize::MAX).max(1);
+        let (new, old): (Vec<u64>, Vec<u64>) = self.resting.iter().partition(|&&id| id > self.seen);
+        let ids: Vec<u64> = new.iter().chain(&old).take(take).copied().collect();
+        self.backlog = new.len() > take;
+        if !self.backlog {
+            self.first_submit = None;
+            self.last_submit = None;
+        }
+        if let Some(&last) = new.iter().take(take).next_back() {
+            self.seen = last;
+        }
+        let mut batch = Vec::with_capacity(ids.len());
+        for id in ids {
+            self.resting.remove(&id);
+            self.set_status(id, OrderStatus::InBatch);
+            batch.push(self.orders[&id].order.clone());
+        }
+        let now = Utc::now();
+        let submitted: Vec<_> = batch.iter().map(|order| order.timestamp).collect();
+        self.batches.metrics.record(trigger, &submitted, now);
+        self.batches.current = Some(BatchSummary {
+            batch_id: batch_id.to_owned(),
+            orders: batch.len(),
+            trigger,
+            started_at: now,
+            finished_at: None,
+            trades: 0,
+            error: None,
+            attempts: vec![],
+        });
+        batch
+    }
+
+    fn fill(&self, entry: &OrderEntry) -> Option<TraderFill> {
+        let OrderStatus::Matched { trade_id, .. } = &entry.status else {
+            return None;
+        };
+        let order_id = entry.order.id;
+        Some(TraderFill {
+            order_id,
+            trade: self.trades.get(trade_id)?.clone(),
+            shares: self.sealed.get(&order_id).cloned().unwrap_or_default(),
+        })
+    }
+}
+
+/// Execution reports buffered for each feed subscriber. One that falls this
+/// far behind on its own trader's reports is dropped.
+const REPORT_BUFFER: usize = 1024;
+
+/// How far a nonce may be from the book's clock by default.
+pub const DEFAULT_NONCE_WINDOW: Duration = Duration::from_secs(300);
+
+/// Every order accepted by the intake, shared between the intake sessions,
+/// the HTTP API and the batch loop.
+pub struct OrderBook {
+    state: Mutex<BookState>,
+    changed: Notify,
+    /// Feed subscribers of each trader.
+    feeds: Mutex<HashMap<String, Vec<mpsc::Sender<ExecReport>>>>,
+    /// Replaced when the instrument master is reloaded.
+    validator: RwLock<Validator>,
+    keystore: Keystore,
+    party_keys: Option<[PartyKey; 3]>,
+    order_ttl: Option<Duration>,
+    nonce_window: Duration,
+    /// Nonces from before this were signed for an earlier process, which
+    /// may have accepted them.
+    started: u64,
+}
+
+impl Default for OrderBook {
+    fn default() -> Self {
+        OrderBook {
+            state: Mutex::default(),
+            changed: Notify::new(),
+            feeds: Mutex::default(),
+            validator: RwLock::default(),
+            keystore: Keystore::default(),
+            party_keys: None,
+            order_ttl: None,
+            nonce_window: DEFAULT_NONCE_WINDOW,
+            started: unix_nanos(Utc::now()),
+        }
+    }
+}
+
+impl OrderBook {
+    /// A book accepting any symbol, with no registered signing keys.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_validator(self, validator: Validator) -> Self {
+        OrderBook {
+            validator: RwLock::new(validator),
+            ..self
+        }
+    }
+
+    /// Check new orders against a reloaded instrument master (see
+    /// `InstrumentMaster::check_reload`).
+    pub fn reload_instruments(&self, master: &InstrumentMaster) {
+        let mut validator = self.validator.write().unwrap();
+        *validator = validator.clone().with_instruments(master);
+    }
+
+    pub fn with_keystore(self, keystore: Keystore) -> Self {
+        OrderBook { keystore, ..self }
+    }
+
+    /// The keys traders seal orders to, published by the intake and the
+    /// HTTP API.
+    pub fn with_party_keys(self, keys: [PartyKey; 3]) -> Self {
+        OrderBook {
+            party_keys: Some(keys),
+            ..self
+        }
+    }
+
+    pub fn party_keys(&self) -> Option<[PartyKey; 3]> {
+        self.party_keys
+    }
+
+    /// The instrument master, for traders to check lot and minimum sizes
+    /// before sealing; empty when none is loaded.
+    pub fn instruments(&self) -> BTreeMap<String, Instrument> {
+        self.validator.read().unwrap().instruments().clone()
+    }
+
+    /// How each symbol's prices are encoded, for traders to seal with and
+    /// to decode fills with.
+    pub fn price_scales(&self) -> BTreeMap<String, PriceScale> {
+        self.validator.read().unwrap().price_scales().clone()
+    }
+
+    /// How many bits of each term traders seal and the range check holds
+    /// them to.
+    pub fn widths(&self) -> BitWidths {
+        self.validator.read().unwrap().widths()
+    }
+
+    /// Expire orders that have not matched this long after submission.
+    pub fn with_order_ttl(self, ttl: Duration) -> Self {
+        OrderBook {
+            order_ttl: Some(ttl),
+            ..self
+        }
+    }
+
+    /// Accept nonces at most `window` from the book's clock. Older ones are
+    /// forgotten, so the nonces kept are those of one window.
+    pub fn with_nonce_window(self, window: Duration) -> Self {
+        OrderBook {
+            nonce_window: window,
+            ..self
+        }
+    }
+
+    /// Execution reports of `trader`'s orders, from now on. Each subscriber
+    /// has its own buffer, so other traders' reports never hold it up.
+    pub fn subscribe(&self, trader: &str) -> mpsc::Receiver<ExecReport> {
+        let (sender, receiver) = mpsc::channel(REPORT_BUFFER);
+        let mut feeds = self.feeds.lock().unwrap();
+        feeds.entry(trader.to_owned()).or_default().push(sender);
+        receiver
+    }
+
+    /// Send the queued execution reports. Nobody listening is fine.
+    fn publish(&self, state: &mut BookState) {
+        if state.outbox.is_empty() {
+            return;
+        }
+        let mut feeds = self.feeds.lock().unwrap();
+        for (trader, report) in state.outbox.drain(..) {
+            let Some(senders) = feeds.get_mut(&trader) else {
+                continue;
+            };
+            senders.retain(|sender| match sender.try_send(report.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "Dropping a report feed of {}: {} reports behind",
+                        trader, REPORT_BUFFER
+                    );
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            });
+            if senders.is_empty() {
+                feeds.remove(&trader);
+            }
+        }
+    }
+
+    /// Validate the public fields of an order and, if they pass, accept it
+    /// and return its server-assigned ID. For orders whose trader is already
+    /// established; intake goes through `submit_signed`.
+    pub fn submit(&self, order: SealedOrder, trader: Option<String>) -> Result<u64, RejectCode> {
+        check_client_key(order.client_key.as_ref())?;
+        self.validator.read().unwrap().check_symbol(&order.symbol)?;
+        Ok(self.insert(order, trader, None))
+    }
+
+    /// Verify a signed order against the keystore, validate it and accept
+    /// it for the trader owning the key. `trader`, when given, is who the
+    /// client authenticated as some other way; the key must be theirs.
+    pub fn submit_signed(
+        &self,
+        signed: SignedOrder,
+        trader: Option<&str>,
+    ) -> Result<u64, RejectCode> {
+        let owner = self.keystore.verify(&signed)?;
+        if trader.is_some_and(|trader| trader != owner) {
+            return Err(RejectCode::WrongTrader);
+        }
+        check_client_key(signed.order.client_key.as_ref())?;
+        self.validator
+            .read()
+            .unwrap()
+            .check_symbol(&signed.order.symbol)?;
+        self.use_nonce(&signed.key_id, signed.nonce)?;
+        let auth = signed.auth();
+        Ok(self.insert(signed.order, Some(owner.to_owned()), Some(auth)))
+    }
+
+    /// Verify a signed cancel or query and return the trader asking. Its
+    /// nonce is spent whether or not the request then succeeds.
+    pub fn authenticate(
+        &self,
+        kind: RequestKind,
+        signed: &SignedRequest,
+    ) -> Result<String, RejectCode> {
+        let trader = self.keystore.verify_request(kind, signed)?.to_owned();
+        self.use_nonce(&signed.key_id, signed.nonce)?;
+        Ok(trader)
+    }
+
+    /// Nonces are signing times: one outside the window around now, or from
+    /// before the book started, is refused as stale. That bounds the nonces
+    /// to remember to one window's worth, and a restart cannot replay ones
+    /// the previous process saw.
+    fn use_nonce(&self, key_id: &str, nonce: u64) -> Result<(), RejectCode> {
+        let now = unix_nanos(Utc::now());
+        let window = self.nonce_window.as_nanos() as u64;
+        let oldest = now.saturating_sub(window).max(self.started);
+        if nonce < oldest || nonce > now.saturating_add(window) {
+            return Err(RejectCode::StaleNonce);
+        }
+        let mut state = self.state.lock().unwrap();
+        state.nonces = state.nonces.split_off(&(oldest, String::new()));
+        if !state.nonces.insert((nonce, key_id.to_owned())) {
+            return Err(RejectCode::ReusedNonce);
+        }
+        Ok(())
+    }
+
+    fn insert(&self, order: SealedOrder, trader: Option<String>, auth: Option<OrderAuth>) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        if let Some(trader) = &trader {
+            let report = ExecReport::new(id, ExecType::Accepted);
+            state.outbox.push((trader.clone(), report));
+        }
+        let order = BookOrder {
+            id,
+            trader,
+            order,
+            timestamp: Utc::now(),
+            auth,
+        };
+        state.orders.insert(
+            id,
+            OrderEntry {
+                order,
+                status: OrderStatus::Resting,
+            },
+        );
+        state.resting.insert(id);
+        let now = Instant::now();
+        state.first_submit.get_or_insert(now);
+        state.last_submit = Some(now);
+        if let Some(ttl) = self.order_ttl {
+            state.expiries.insert(id, now + ttl);
+            state.by_expiry.insert((now + ttl, id));
+        }
+        self.publish(&mut state);
+        drop(state);
+        self.changed.notify_waiters();
+        id
+    }
+
+    pub fn cancel(&self, id: u64) -> Result<(), CancelError> {
+        let mut state = self.state.lock().unwrap();
+        match state.orders.get(&id).map(|entry| &entry.status) {
+            None => Err(CancelError::UnknownOrder),
+            Some(OrderStatus::InBatch) => Err(CancelError::InBatch),
+            Some(OrderStatus::Matched { .. }) => Err(CancelError::Matched),
+            Some(OrderStatus::Cancelled) => Err(CancelError::AlreadyCancelled),
+            Some(OrderStatus::Expired) => Err(CancelError::Expired),
+            Some(OrderStatus::Rejected { .. }) => Err(CancelError::Rejected),
+            Some(OrderStatus::Resting) => {
+                state.resting.remove(&id);
+                state.set_status(id, OrderStatus::Cancelled);
+                self.publish(&mut state);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn status(&self, id: u64) -> Option<OrderStatus> {
+        self.get(id).map(|entry| entry.status)
+    }
+
+    /// `get`, but only if the order is `trader`'s. Orders of other traders
+    /// look missing, so IDs reveal nothing.
+    pub fn get_own(&self, id: u64, trader: &str) -> Option<OrderEntry> {
+        self.get(id)
+            .filter(|entry| entry.order.trader.as_deref() == Some(trader))
+    }
+
+    pub fn get(&self, id: u64) -> Option<OrderEntry> {
+        self.state.lock().unwrap().orders.get(&id).cloned()
+    }
+
+    /// All orders placed by `trader`, oldest first.
+    pub fn orders_of(&self, trader: &str) -> Vec<OrderEntry> {
+        let state = self.state.lock().unwrap();
+        let mut orders: Vec<_> = state
+            .orders
+            .values()
+            .filter(|entry| entry.order.trader.as_deref() == Some(trader))
+            .cloned()
+            .collect();
+        orders.sort_by_key(|entry| entry.order.id);
+        orders
+    }
+
+    /// Fills of the orders placed by `trader`, oldest order first.
+    pub fn fills_of(&self, trader: &str) -> Vec<TraderFill> {
+        let state = self.state.lock().unwrap();
+        let mut fills = BTreeMap::new();
+        for entry in state.orders.values() {
+            if entry.order.trader.as_deref() == Some(trader)
+                && let Some(fill) = state.fill(entry)
+            {
+                fills.insert(fill.order_id, fill);
+            }
+        }
+        fills.into_values().collect()
+    }
+
+    /// The fill of one order, once it has matched.
+    pub fn fill(&self, id: u64) -> Option<TraderFill> {
+        let state = self.state.lock().unwrap();
+        state.orders.get(&id).and_then(|entry| state.fill(entry))
+    }
+
+    pub fn batch_state(&self) -> BatchState {
+        let state = self.state.lock().unwrap();
+        BatchState {
+            resting_orders: state.resting.len(),
+            ..state.batches.clone()
+        }
+    }
+
+    /// Wait until `policy` calls for a batch, then take the resting orders
+    /// for matching as batch `batch_id`: all of them, or `max_orders` when
+    /// capped, new orders first (see `BatchPolicy::max_orders`).
+    pub async fn next_batch(&self, policy: &BatchPolicy, batch_id: &str) -> Vec<BookOrder> {
+        loop {
+            // Registered before the state is checked so no submit is missed.
+            let changed = self.changed.notified();
+            let deadline = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                let next_expiry = state.expire(now);
+                self.publish(&mut state);
+                let epoch = *state.epoch.get_or_insert(now);
+                let next = match (state.first_submit, state.last_submit) {
+                    (Some(first), Some(last)) if !state.resting.is_empty() => {
+                        let pending = Pending {
+                            first,
+                            last,
+                            arrived: state.resting.range(state.seen + 1..).count(),
+                        };
+                        policy.next_cut(&pending, epoch, now, Utc::now())
+                    }
+                    _ => None,
+                };
+                // Work through a capped backlog without waiting.
+                let next = match next {
+                    Some(_) if state.backlog => Some((now, Trigger::MaxOrders)),
+                    next => next,
+                };
+                match next {
+                    Some((deadline, trigger)) if deadline <= now => {
+                        return state.cut_batch(policy.max_orders, trigger, batch_id);
+                    }
+                    next => [next.map(|(deadline, _)| deadline), next_expiry]
+                        .into_iter()
+                        .flatten()
+                        .min(),
+                }
+            };
+            match deadline {
+                Some(deadline) => {
+                    tokio::select! {
+                        _ = changed => {}
+                        _ = tokio::time::sleep_until(deadline) => {}
+                    }
+                }
+                None => changed.await,
+            }
+        }
+    }
+
+    /// Reject orders of the current batch that failed the range check.
+    /// Call before `settle`, which would otherwise put them back to rest.
+    pub fn reject(&self, ids: &[u64], code: RejectCode) {
+        let mut state = self.state.lock().unwrap();
+        for &id in ids {
+            state.set_status(id, OrderStatus::Rejected { code });
+        }
+        self.publish(&mut state);
+    }
+
+    /// Note a finished attempt at the current batch.
+    pub fn record_attempt(&self, attempt: Attempt) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(current) = &mut state.batches.current {
+            current.attempts.push(attempt);
+        }
+    }
+
+    /// Note how many AND triples the parties have in stock.
+    pub fn set_preprocessed(&self, triples: usize) {
+        self.state.lock().unwrap().batches.preprocessed_triples = triples;
+    }
+
+    /// Record the outcome of a batch. Orders that did not trade rest again
+    /// for the next one, including those of symbols the parties failed on;
+    /// `error` says why, if any did.
+    pub fn settle(
+        &self,
+        batch: Vec<BookOrder>,
+        trades: &[Trade],
+        sealed: &[SealedFill],
+        error: Option<String>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        for fill in sealed {
+            state
+                .sealed
+                .entry(fill.order_id)
+                .or_default()
+                .push(fill.clone());
+        }
+        for trade in trades {
+            state.trades.insert(trade.trade_id.clone(), trade.clone());
+            for id in [trade.buy_order_id, trade.sell_order_id] {
+                state.set_status(
+                    id,
+                    OrderStatus::Matched {
+                        trade_id: trade.trade_id.clone(),
+                    },
+                );
+            }
+        }
+        let now = Instant::now();
+        for order in &batch {
+            if state.orders[&order.id].status == OrderStatus::InBatch {
+                state.rest_again(order.id, now);
+            }
+        }
+        state.finish_batch(trades.len(), error);
+        self.publish(&mut state);
+    }
+
+    /// Put the orders of a failed batch back for the next one.
+    pub fn fail(&self, batch: Vec<BookOrder>, error: String) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        for order in &batch {
+            state.rest_again(order.id, now);
+        }
+        state.finish_batch(0, Some(error));
+        self.publish(&mut state);
+    }
+}
+
+/// Nanoseconds since the Unix epoch, the unit of nonces.
+pub fn unix_nanos(time: DateTime<Utc>) -> u64 {
+    time.timestamp_nanos_opt().unwrap_or_default().max(0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use ed25519_dalek::SigningKey;
+    use rust_decimal::Decimal;
+    use std::time::Duration;
+
+    use crate::batching::{BatchPolicy, Trigger};
+    use crate::book::{CancelError, OrderBook, OrderStatus, unix_nanos};
+    use crate::feed::{ExecReport, ExecType};
+    use crate::keystore::{Keystore, SignedOrder};
+    use crate::report::Trade;
+    use crate::sealed_order::{PartySecret, SealedOrder};
+    use crate::types::Order;
+    use crate::validate::{RejectCode, Validator};
+
+    fn order(o_type: bool) -> SealedOrder {
+        let order = Order {
+            o_type,
+            symbol: "MSFT".into(),
+            quantity: 100,
+            price: Decimal::from(150),
+            min_execution: 50,
+            client_key: None,
+        };
+        let keys = [0, 1, 2].map(|_| PartySecret::generate().public_key());
+        SealedOrder::seal(&order, &keys, &Validator::default()).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_waits_for_idle_period() {
+        let book = OrderBook::new();
+        let policy = BatchPolicy::default();
+        let idle = Duration::from_secs(5);
+        book.submit(order(false), None).unwrap();
+        tokio::time::advance(Duration::from_secs(3)).await;
+        book.submit(order(true), None).unwrap();
+
+        let start = tokio::time::Instant::now();
+        let batch = book.next_batch(&policy, "b1").await;
+
+        assert_eq!(start.elapsed(), idle);
+        assert_eq!(batch.iter().map(|o| o.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(book.status(1), Some(OrderStatus::InBatch));
+        assert_eq!(book.cancel(1), Err(CancelError::InBatch));
+        let state = book.batch_state();
+        assert_eq!(state.current.unwrap().trigger, Trigger::Idle);
+        assert_eq!(state.metrics.largest_batch, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_orders_caps_batches() {
+        let book = OrderBook::new();
+        let policy = BatchPolicy {
+            idle_secs: None,
+            max_orders: Some(2),
+            max_latency_secs: Some(10),
+            ..Default::default()
+        };
+        for o_type in [false, true, false] {
+            book.submit(order(o_type), None).unwrap();
+        }
+
+        let first = book.next_batch(&policy, "b1").await;
+        let second = book.next_batch(&policy, "b2").await;
+        assert_eq!(first.iter().map(|o| o.id).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(second.iter().map(|o| o.id).collect::<Vec<_>>(), [3]);
+        let metrics = book.batch_state().metrics;
+        assert_eq!(metrics.batches, 2);
+        assert_eq!(metrics.mean_batch_size, 1.5);
+        assert_eq!(metrics.triggers[&Trigger::MaxOrders], 2);
+
+        // Unmatched orders do not count towards the cap's trigger, and a new
+        // order goes ahead of them.
+        book.settle(first, &[], &[], None);
+        book.settle(second, &[], &[], None);
+        book.submit(order(true), None).unwrap();
+        let start = tokio::time::Instant::now();
+        let third = book.next_batch(&policy, "b3").await;
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert_eq!(third.iter().map(|o| o.id).collect::<Vec<_>>(), [4, 1]);
+        assert_eq!(
+            book.batch_state().current.unwrap().trigger,
+            Trigger::MaxLatency
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resting_orders_expire() {
+        let book = OrderBook::new().with_order_ttl(Duration::from_secs(30));
+        let mut reports = book.subscribe("alice");
+        let id = book.submit(order(false), Some("alice".into())).unwrap();
+        let policy = BatchPolicy {
+            idle_secs: None,
+            max_orders: Some(2),
+            ..Default::default()
+        };
+
+        let no_batch =
+            tokio::time::timeout(Duration::from_secs(60), book.next_batch(&policy, "b1"));
+        assert!(no_batch.await.is_err());
+        assert_eq!(book.status(id), Some(OrderStatus::Expired));
+        assert_eq!(book.cancel(id), Err(CancelError::Expired));
+        let exec_types: Vec<_> = std::iter::from_fn(|| reports.try_recv().ok())
+            .map(|report| report.exec)
+            .collect();
+        assert!(matches!(
+            exec_types.as_slice(),
+            [ExecType::Accepted, ExecType::Expired]
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unmatched_orders_rest_again() {
+        let book = OrderBook::new();
+        let mut alices = book.subscribe("alice");
+        let mut bobs = book.subscribe("bob");
+        let (buy, sell, other) = (
+            book.submit(order(false), Some("alice".into())).unwrap(),
+            book.submit(order(true), Some("bob".into())).unwrap(),
+            book.submit(order(false), Some("alice".into())).unwrap(),
+        );
+        let policy = BatchPolicy {
+            idle_secs: Some(1),
+            ..Default::default()
+        };
+        let batch = book.next_batch(&policy, "b1").await;
+        let now = Utc::now();
+        book.settle(
+            batch,
+            &[Trade {
+                trade_id: "b1-1".into(),
+                symbol: "MSFT".into(),
+                buy_order_id: buy,
+                sell_order_id: sell,
+                buy_timestamp: now,
+                sell_timestamp: now,
+                executed_at: now,
+            }],
+            &[],
+            None,
+        );
+
+        assert_eq!(
+            book.status(buy),
+            Some(OrderStatus::Matched {
+                trade_id: "b1-1".into(),
+            })
+        );
+        let last = |reports: &mut tokio::sync::mpsc::Receiver<_>| {
+            std::iter::from_fn(|| reports.try_recv().ok()).last()
+        };
+        assert!(matches!(
+            last(&mut alices).map(|report: ExecReport| report.exec),
+            Some(ExecType::Traded { fill }) if fill.order_id == buy
+        ));
+        assert!(matches!(
+            last(&mut bobs).map(|report| report.exec),
+            Some(ExecType::Traded { fill }) if fill.order_id == sell
+        ));
+        assert_eq!(book.status(other), Some(OrderStatus::Resting));
+        assert_eq!(book.orders_of("alice").len(), 2);
+        assert_eq!(book.fills_of("alice")[0].order_id, buy);
+        assert_eq!(book.batch_state().last.unwrap().trades, 1);
+        let no_new_orders =
+            tokio::time::timeout(Duration::from_secs(60), book.next_batch(&policy, "b2"));
+        assert!(no_new_orders.await.is_err());
+        assert_eq!(book.cancel(other), Ok(()));
+        assert_eq!(book.cancel(other), Err(CancelError::AlreadyCancelled));
+        assert_eq!(book.cancel(99), Err(CancelError::UnknownOrder));
+    }
+
+    #[test]
+    fn test_nonces_are_timestamps_within_the_window() {
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let mut keystore = Keystore::default();
+        keystore.insert("alice-1", "alice", &key.verifying_key());
+        let window = Duration::from_millis(500);
+        let book = OrderBook::new()
+            .with_keystore(keystore)
+            .with_nonce_window(window);
+        let order = order(false);
+        let submit = |nonce| {
+            book.submit_signed(
+                SignedOrder::sign(order.clone(), "alice-1", nonce, &key),
+                None,
+            )
+        };
+
+        let window_nanos = window.as_nanos() as u64;
+        let nonce = unix_nanos(Utc::now()) + window_nanos / 2;
+        assert_eq!(submit(1), Err(RejectCode::StaleNonce));
+        assert_eq!(
+            submit(nonce + 2 * window_nanos),
+            Err(RejectCode::StaleNonce)
+        );
+        assert!(submit(nonce).is_ok());
+        assert_eq!(submit(nonce), Err(RejectCode::ReusedNonce));
+
+        // Once out of the window a nonce is refused as stale, so it is no
+        // longer kept.
+        std::thread::sleep(2 * window);
+        assert_eq!(submit(nonce), Err(RejectCode::StaleNonce));
+        assert!(submit(unix_nanos(Utc::now())).is_ok());
+        assert_eq!(book.state.lock().unwrap().nonces.len(), 1);
+    }
+}
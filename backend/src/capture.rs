@@ -0,0 +1,432 @@
+use anyhow::Context as _;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Utc};
+use futures_util::{Sink, Stream};
+use serde::{Deserialize, Serialize};
+use sl_mpc_mate::coord::{MessageSendError, Relay};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tracing::error;
+
+use crate::ecies::{self, Envelope};
+use crate::types::BookOrder;
+use crate::validate::BitWidths;
+
+/// HKDF info of capture record envelopes.
+const CAPTURE_INFO: &[u8] = b"darkpool capture record";
+
+/// X25519 public key every capture record is encrypted to. Processes that
+/// write captures only hold this; reading one takes the `CaptureSecret`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureKey(#[serde(with = "hex::serde")] pub [u8; 32]);
+
+impl FromStr for CaptureKey {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut key = [0u8; 32];
+        hex::decode_to_slice(s.trim(), &mut key)?;
+        Ok(CaptureKey(key))
+    }
+}
+
+/// The side of a `CaptureKey` that decrypts captures, for `bin/replay.rs`.
+pub struct CaptureSecret([u8; 32]);
+
+impl CaptureSecret {
+    pub fn generate() -> Self {
+        CaptureSecret(rand::random())
+    }
+
+    /// Read a secret stored as 64 hex digits.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut secret = [0u8; 32];
+        hex::decode_to_slice(text.trim(), &mut secret)
+            .with_context(|| format!("Invalid capture key in {}", path.display()))?;
+        Ok(CaptureSecret(secret))
+    }
+
+    pub fn public_key(&self) -> CaptureKey {
+        CaptureKey(ecies::public_key(&self.0))
+    }
+}
+
+/// Which way a frame travelled, seen from the party owning the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Party → relay.
+    Sent,
+    /// Relay → party.
+    Received,
+}
+
+/// One line of a capture file (JSON Lines).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum CaptureRecord {
+    /// Everything needed to rebuild the party's setup and seed: written by
+    /// the party itself before its first frame. The relay server cannot see
+    /// this and only writes `Frame` records. `orders` is the batch of a
+    /// single-batch session; sessions of several epochs leave it empty and
+    /// write an `Epoch` record per batch.
+    Session {
+        conn: u64,
+        #[serde(with = "hex::serde")]
+        instance: [u8; 32],
+        party: usize,
+        /// The party's own randomness mixed into its seed. Captures are
+        /// encrypted, so recording it here keeps it from the relay.
+        #[serde(with = "hex::serde")]
+        fresh: [u8; 32],
+        #[serde(default)]
+        orders: Vec<BookOrder>,
+        /// Widths the session range-checks orders against.
+        #[serde(default)]
+        widths: BitWidths,
+        /// Smallest quantity the session's range check accepts.
+        #[serde(default = "default_min_quantity")]
+        min_quantity: u64,
+    },
+    /// The party starts matching a batch over its session.
+    Epoch {
+        conn: u64,
+        /// Instance the epoch's evidence is bound to.
+        #[serde(with = "hex::serde")]
+        instance: [u8; 32],
+        orders: Vec<BookOrder>,
+    },
+    /// The party re-keys its session under a new instance.
+    Rekey {
+        conn: u64,
+        #[serde(with = "hex::serde")]
+        instance: [u8; 32],
+        /// The party's fresh randomness for the new seed.
+        #[serde(with = "hex::serde")]
+        fresh: [u8; 32],
+    },
+    /// The party preprocesses `triples` AND triples between epochs.
+    Preprocess { conn: u64, triples: usize },
+    Frame {
+        ts: DateTime<Utc>,
+        conn: u64,
+        dir: Direction,
+        #[serde(with = "base64_frame")]
+        frame: Vec<u8>,
+    },
+}
+
+/// Captures from before instruments carried a minimum quantity checked 1.
+fn default_min_quantity() -> u64 {
+    1
+}
+
+mod base64_frame {
+    use base64::{Engine, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(frame: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&STANDARD.encode(frame))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        STANDARD
+            .decode(String::deserialize(d)?)
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Append-only capture file shared by every connection that records into it.
+///
+/// Each record is flushed as soon as it is written so that a capture taken
+/// from a crashed or aborted batch is complete up to the failure. Records
+/// are encrypted one by one to the capture key, each line holding the JSON
+/// `Envelope` of one JSON record, and the file is only readable by its owner.
+#[derive(Clone)]
+pub struct CaptureWriter {
+    out: Arc<Mutex<BufWriter<File>>>,
+    key: CaptureKey,
+}
+
+impl std::fmt::Debug for CaptureWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptureWriter").finish_non_exhaustive()
+    }
+}
+
+impl CaptureWriter {
+    pub fn create<P: AsRef<Path>>(path: P, key: CaptureKey) -> std::io::Result<Self> {
+        let mut options = std::fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+            options.mode(0o600);
+            let file = options.open(path)?;
+            // The mode only applies to new files.
+            file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            Ok(CaptureWriter {
+                out: Arc::new(Mutex::new(BufWriter::new(file))),
+                key,
+            })
+        }
+        #[cfg(not(unix))]
+        Ok(CaptureWriter {
+            out: Arc::new(Mutex::new(BufWriter::new(options.open(path)?))),
+            key,
+        })
+    }
+
+    /// The writer for the configured `capture` file, if any. Capturing
+    /// without a key to encrypt to is refused.
+    pub fn from_config(
+        path: Option<&Path>,
+        key: Option<CaptureKey>,
+    ) -> anyhow::Result<Option<Self>> {
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        let key = key.context("capture needs a capture_key to encrypt records to")?;
+        let writer = CaptureWriter::create(path, key)
+            .with_context(|| format!("Failed to open capture file {}", path.display()))?;
+        Ok(Some(writer))
+    }
+
+    pub fn write(&self, record: &CaptureRecord) -> std::io::Result<()> {
+        let envelope = ecies::seal(&self.key.0, CAPTURE_INFO, b"", &serde_json::to_vec(record)?)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "capture key is a low-order point",
+                )
+            })?;
+        let mut line = serde_json::to_vec(&envelope)?;
+        line.push(b'\n');
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&line)?;
+        out.flush()
+    }
+
+    fn frame(&self, conn: u64, dir: Direction, frame: &[u8]) {
+        let record = CaptureRecord::Frame {
+            ts: Utc::now(),
+            conn,
+            dir,
+            frame: frame.to_vec(),
+        };
+        if let Err(e) = self.write(&record) {
+            error!("Capture write failed for connection {}: {}", conn, e);
+        }
+    }
+}
+
+/// Read and decrypt every record of a capture file, in file order.
+pub fn read_capture<P: AsRef<Path>>(
+    path: P,
+    secret: &CaptureSecret,
+) -> std::io::Result<Vec<CaptureRecord>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let envelope: Envelope = serde_json::from_str(&line)?;
+        let plaintext = ecies::open(&secret.0, &envelope, CAPTURE_INFO, b"").ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "capture record does not decrypt with this key",
+            )
+        })?;
+        records.push(serde_json::from_slice(&plaintext)?);
+    }
+    Ok(records)
+}
+
+/// Wraps any `Relay` and writes every frame passing through it to a capture.
+pub struct RecordingRelay<R> {
+    inner: R,
+    capture: CaptureWriter,
+    conn: u64,
+}
+
+impl<R: Relay> RecordingRelay<R> {
+    pub fn new(inner: R, capture: CaptureWriter, conn: u64) -> Self {
+        RecordingRelay {
+            inner,
+            capture,
+            conn,
+        }
+    }
+}
+
+impl<R: Relay> Sink<Vec<u8>> for RecordingRelay<R> {
+    type Error = MessageSendError;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        this.capture.frame(this.conn, Direction::Sent, &item);
+        Pin::new(&mut this.inner).start_send(item)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+impl<R: Relay> Stream for RecordingRelay<R> {
+    type Item = Vec<u8>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let next = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(Some(frame)) = &next {
+            this.capture.frame(this.conn, Direction::Received, frame);
+        }
+        next
+    }
+}
+impl<R: Relay> Relay for RecordingRelay<R> {}
+
+/// A `Relay` that plays back the frames one connection received in a
+/// capture, so the party on that connection can be re-run offline.
+///
+/// Frames the party sends are compared against the ones it sent originally;
+/// the first divergence is reported on stderr, since from that point on the
+/// recorded replies no longer answer what the party is asking.
+pub struct ReplayRelay {
+    received: VecDeque<Vec<u8>>,
+    expected: VecDeque<Vec<u8>>,
+    sent: usize,
+    diverged: bool,
+}
+
+impl ReplayRelay {
+    pub fn new(records: &[CaptureRecord], conn: u64) -> Self {
+        let mut received = VecDeque::new();
+        let mut expected = VecDeque::new();
+        for record in records {
+            if let CaptureRecord::Frame {
+                conn: c,
+                dir,
+                frame,
+                ..
+            } = record
+            {
+                if *c != conn {
+                    continue;
+                }
+                match dir {
+                    Direction::Received => received.push_back(frame.clone()),
+                    Direction::Sent => expected.push_back(frame.clone()),
+                }
+            }
+        }
+        ReplayRelay {
+            received,
+            expected,
+            sent: 0,
+            diverged: false,
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for ReplayRelay {
+    type Error = MessageSendError;
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let expected = this.expected.pop_front();
+        if !this.diverged && expected.as_deref() != Some(item.as_slice()) {
+            this.diverged = true;
+            eprintln!(
+                "Replay diverged at sent frame {}: expected {}, got {}",
+                this.sent,
+                expected
+                    .map(|f| STANDARD.encode(f))
+                    .unwrap_or_else(|| "end of capture".into()),
+                STANDARD.encode(&item),
+            );
+        }
+        this.sent += 1;
+        Ok(())
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+impl Stream for ReplayRelay {
+    type Item = Vec<u8>;
+    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        // End of the recording ends the stream, which aborts the party.
+        Poll::Ready(self.get_mut().received.pop_front())
+    }
+}
+impl Relay for ReplayRelay {}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+
+    use crate::capture::{
+        CaptureRecord, CaptureSecret, CaptureWriter, Direction, ReplayRelay, read_capture,
+    };
+    use crate::validate::BitWidths;
+
+    #[tokio::test]
+    async fn test_capture_round_trip() {
+        let path = std::env::temp_dir().join(format!("capture-{}.jsonl", rand::random::<u64>()));
+        let secret = CaptureSecret::generate();
+        let capture = CaptureWriter::create(&path, secret.public_key()).unwrap();
+        capture
+            .write(&CaptureRecord::Session {
+                conn: 1,
+                instance: [3; 32],
+                party: 1,
+                fresh: [5; 32],
+                orders: vec![],
+                widths: BitWidths::default(),
+                min_quantity: 1,
+            })
+            .unwrap();
+        capture.frame(1, Direction::Sent, b"ask");
+        capture.frame(0, Direction::Received, b"other party");
+        capture.frame(1, Direction::Received, b"reply");
+
+        let raw = std::fs::read_to_string(&path).unwrap();
+        assert!(!raw.contains("session"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(read_capture(&path, &CaptureSecret::generate()).is_err());
+        let records = read_capture(&path, &secret).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(records.len(), 4);
+
+        let mut replay = ReplayRelay::new(&records, 1);
+        replay.send(b"ask".to_vec()).await.unwrap();
+        assert!(!replay.diverged);
+        assert_eq!(replay.next().await, Some(b"reply".to_vec()));
+        assert_eq!(replay.next().await, None);
+    }
+}
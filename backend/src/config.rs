@@ -0,0 +1,344 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{
+    ClientConfig, RootCertStore, ServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer},
+};
+use tracing::Level;
+
+use crate::batching::BatchPolicy;
+use crate::book::DEFAULT_NONCE_WINDOW;
+use crate::capture::CaptureKey;
+use crate::fault_relay::FaultProfile;
+use crate::limits::RelayLimits;
+use crate::price::PriceScale;
+use crate::report::ReportFormat;
+use crate::supervisor::RetryPolicy;
+use crate::timeout_relay::DEFAULT_RECV_TIMEOUT;
+use crate::validate::BitWidths;
+
+/// Settings of the relay server (`bin/relay_server.rs`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayServerConfig {
+    /// WebSocket listener (`ws://`, or `wss://` when `tls` is set).
+    pub listen: SocketAddr,
+    /// Optional length-prefixed TCP listener sharing the same broker.
+    pub tcp_listen: Option<SocketAddr>,
+    /// Optional Unix domain socket listener sharing the same broker.
+    pub unix_listen: Option<PathBuf>,
+    pub tls: Option<TlsConfig>,
+    pub limits: RelayLimits,
+    pub faults: Option<FaultProfile>,
+    /// Record all relayed traffic to this file.
+    pub capture: Option<PathBuf>,
+    /// Public key the captured traffic is encrypted to; required with `capture`.
+    pub capture_key: Option<CaptureKey>,
+    pub log_level: LogLevel,
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        RelayServerConfig {
+            listen: "0.0.0.0:9007".parse().unwrap(),
+            tcp_listen: None,
+            unix_listen: None,
+            tls: None,
+            limits: RelayLimits::default(),
+            faults: None,
+            capture: None,
+            capture_key: None,
+            log_level: LogLevel::default(),
+        }
+    }
+}
+
+/// Settings of the matching backend (`main.rs`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    /// Where traders submit orders.
+    pub order_listen: SocketAddr,
+    /// HTTP API listener; the API is off when unset.
+    pub api_listen: Option<SocketAddr>,
+    /// API token of each trader, keyed by trader name.
+    pub api_tokens: BTreeMap<String, String>,
+    /// Control listener of party 0, 1 and 2, in participant order. The
+    /// parties run as processes of their own (`bin/party.rs`), each holding
+    /// only its own secret key (see `party`).
+    pub parties: [PartyLink; 3],
+    /// Extra CA certificate (PEM) trusted for `tls://` control URLs, for
+    /// parties using a private or self-signed certificate.
+    pub party_ca_cert: Option<PathBuf>,
+    /// TOML file of the traders' order-signing keys (see `keystore`). Every
+    /// order must be signed with one of them, so none is accepted without it.
+    pub keystore: Option<PathBuf>,
+    /// Symbols orders may be placed for; any symbol is accepted when empty.
+    pub symbols: BTreeSet<String>,
+    /// Bits each order field's encoding may use (see `validate`).
+    pub widths: BitWidths,
+    /// Decimal places and tick size of each symbol's prices (see `price`),
+    /// e.g. `[price_scales.MSFT]` with `scale = 2` and `tick_size = "0.01"`.
+    /// Symbols without an entry are priced in whole units.
+    pub price_scales: BTreeMap<String, PriceScale>,
+    /// TOML file of the tradable instruments (see `instruments`). When set,
+    /// it takes the place of `symbols` and `price_scales`.
+    pub instruments: Option<PathBuf>,
+    /// When resting orders are matched (see `batching`).
+    pub batching: BatchPolicy,
+    /// Seconds after which an order that has not matched expires; orders
+    /// rest until matched or cancelled when unset.
+    pub order_ttl_secs: Option<u64>,
+    /// How many seconds a nonce, the signing time of an order, cancel or
+    /// query, may be from the backend's clock.
+    pub nonce_window_secs: u64,
+    /// Batches the parties match over one set of session keys before
+    /// re-keying (see `session`); never re-keyed when 0.
+    pub rekey_epochs: u64,
+    /// Orders a symbol's book is expected to hold in a batch: the parties
+    /// preprocess enough AND triples to match that many while idle. The
+    /// symbol's largest book so far when unset; no preprocessing when 0.
+    pub preprocess_orders: Option<usize>,
+    /// How failed or stuck batches are retried (see `supervisor`).
+    pub retry: RetryPolicy,
+    /// Trade report the executed trades of each batch are appended to.
+    pub trades_file: PathBuf,
+    pub report_format: ReportFormat,
+    /// Sealed fill shares, one JSON object per line, for the counterparties
+    /// to collect. Only the buyer and seller can decrypt them.
+    pub fills_file: PathBuf,
+    /// Incident reports of batches that failed verification, one JSON object
+    /// per line, with the parties' signed evidence (see `evidence`).
+    pub incidents_file: PathBuf,
+    pub log_level: LogLevel,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            order_listen: "127.0.0.1:8080".parse().unwrap(),
+            api_listen: None,
+            api_tokens: BTreeMap::new(),
+            parties: std::array::from_fn(|party| PartyLink {
+                control_url: format!("tcp://127.0.0.1:{}", 9101 + party),
+                token: String::new(),
+                evidence_key: None,
+            }),
+            party_ca_cert: None,
+            keystore: None,
+            symbols: BTreeSet::new(),
+            widths: BitWidths::default(),
+            price_scales: BTreeMap::new(),
+            instruments: None,
+            batching: BatchPolicy::default(),
+            order_ttl_secs: None,
+            nonce_window_secs: DEFAULT_NONCE_WINDOW.as_secs(),
+            rekey_epochs: 100,
+            preprocess_orders: None,
+            retry: RetryPolicy::default(),
+            trades_file: "trades.jsonl".into(),
+            report_format: ReportFormat::default(),
+            fills_file: "fills.jsonl".into(),
+            incidents_file: "incidents.jsonl".into(),
+            log_level: LogLevel::default(),
+        }
+    }
+}
+
+/// How the backend reaches one party process.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PartyLink {
+    /// `tcp://host:port`, or `tls://host:port` when the party serves TLS.
+    pub control_url: String,
+    /// The party's `control_token`.
+    pub token: String,
+    /// The public half of the party's `evidence_key`, 64 hex digits. When
+    /// set, a party greeting with another key is refused, so a party cannot
+    /// swap keys to disown its evidence.
+    #[serde(default)]
+    pub evidence_key: Option<String>,
+}
+
+/// Settings of a party process (`bin/party.rs`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartyConfig {
+    /// Participant index: 0, 1 or 2.
+    pub party: usize,
+    /// The party's secret key, a file of 64 hex digits. Traders seal the
+    /// party's share of every order to the matching public key, so it must
+    /// stay the same across restarts; required.
+    pub secret_key: Option<PathBuf>,
+    /// The Ed25519 key the party signs evidence of failed epochs with (see
+    /// `evidence`), a file of 64 hex digits. Kept apart from `secret_key`,
+    /// which opens order shares; required.
+    pub evidence_key: Option<PathBuf>,
+    /// Where the backend connects to drive the party.
+    pub control_listen: SocketAddr,
+    /// Serve the control listener over TLS (`tls://` control URLs).
+    pub tls: Option<TlsConfig>,
+    /// Token the backend must present on every control connection; required.
+    pub control_token: Option<String>,
+    /// Relay the party's sessions talk through, one room per symbol.
+    pub relay_url: String,
+    /// Extra CA certificate (PEM) trusted for a `wss://` relay URL, for relays
+    /// using a private or self-signed certificate.
+    pub relay_ca_cert: Option<PathBuf>,
+    /// Record the party's relay traffic to this file for `bin/replay.rs`.
+    pub capture: Option<PathBuf>,
+    /// Public key the captured traffic is encrypted to; required with `capture`.
+    pub capture_key: Option<CaptureKey>,
+    /// How long a session waits for a frame from the other parties before
+    /// it fails the step it is in.
+    pub recv_timeout_secs: u64,
+    pub log_level: LogLevel,
+}
+
+impl Default for PartyConfig {
+    fn default() -> Self {
+        PartyConfig {
+            party: 0,
+            secret_key: None,
+            evidence_key: None,
+            control_listen: "127.0.0.1:9101".parse().unwrap(),
+            tls: None,
+            control_token: None,
+            relay_url: "ws://localhost:9007".to_owned(),
+            relay_ca_cert: None,
+            capture: None,
+            capture_key: None,
+            recv_timeout_secs: DEFAULT_RECV_TIMEOUT.as_secs(),
+            log_level: LogLevel::default(),
+        }
+    }
+}
+
+/// PEM files for a TLS listener.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let certs = read_certs(&self.cert_path)?;
+        let key = read_key(&self.key_path)?;
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("Invalid TLS certificate or key")?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Client TLS settings trusting the web PKI roots plus `extra_ca`.
+pub fn client_tls_config(extra_ca: &Path) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    for cert in read_certs(extra_ca)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Invalid CA certificate in {}", extra_ca.display()))?;
+    }
+    Ok(Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("Invalid certificate PEM in {}", path.display()))
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let pem = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .with_context(|| format!("Invalid private key PEM in {}", path.display()))?
+        .with_context(|| format!("No private key in {}", path.display()))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Level::ERROR,
+            LogLevel::Warn => Level::WARN,
+            LogLevel::Info => Level::INFO,
+            LogLevel::Debug => Level::DEBUG,
+            LogLevel::Trace => Level::TRACE,
+        }
+    }
+}
+
+/// Install the global `tracing` subscriber.
+pub fn init_logging(level: LogLevel) {
+    tracing_subscriber::fmt()
+        .with_max_level(Level::from(level))
+        .init();
+}
+
+/// Read a TOML config file, or fall back to the defaults when no path is given.
+pub fn load<T>(path: Option<&Path>) -> Result<T>
+where
+    T: Default + for<'de> Deserialize<'de>,
+{
+    let Some(path) = path else {
+        return Ok(T::default());
+    };
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("Invalid config in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{BackendConfig, LogLevel, RelayServerConfig};
+
+    #[test]
+    fn test_partial_config_keeps_defaults() {
+        let config: RelayServerConfig = toml::from_str(
+            r#"
+            listen = "127.0.0.1:9100"
+            log_level = "debug"
+
+            [limits]
+            max_frame_size = 4096
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen.port(), 9100);
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.limits.max_frame_size, 4096);
+        assert_eq!(config.limits.outbound_queue, 1024);
+        assert!(config.tls.is_none());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        let res: Result<BackendConfig, _> = toml::from_str("order_listn = \"127.0.0.1:1\"");
+        assert!(res.is_err());
+    }
+}
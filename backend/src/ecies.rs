@@ -0,0 +1,160 @@
+//! Hybrid public-key encryption to an X25519 key: X25519 between a fresh
+//! ephemeral key and the recipient's key, HKDF-SHA256 salted with both public
+//! keys, then AES-256-GCM. Used for fill shares sent to traders and for order
+//! shares sent to the parties.
+
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, KeyInit, Payload},
+};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// One message encrypted to one recipient.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(with = "hex::serde")]
+    pub ephemeral_key: [u8; 32],
+    #[serde(with = "hex::serde")]
+    pub nonce: [u8; 12],
+    #[serde(with = "hex::serde")]
+    pub ciphertext: Vec<u8>,
+}
+
+pub fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    MontgomeryPoint::mul_base_clamped(*secret).to_bytes()
+}
+
+/// Whether `key` is in the small subgroup, so that X25519 with it gives the
+/// all-zero shared secret whatever the other side's secret. An envelope to
+/// or from such a key would be readable by anyone.
+pub fn is_low_order(key: &[u8; 32]) -> bool {
+    shared_secret(key, [1; 32]).is_none()
+}
+
+/// X25519, or `None` when the result is all zeros (RFC 7748, section 6.1).
+fn shared_secret(key: &[u8; 32], secret: [u8; 32]) -> Option<MontgomeryPoint> {
+    let shared = MontgomeryPoint(*key).mul_clamped(secret);
+    (shared.to_bytes() != [0; 32]).then_some(shared)
+}
+
+fn cipher(
+    shared: MontgomeryPoint,
+    ephemeral_key: &[u8; 32],
+    recipient: &[u8; 32],
+    info: &[u8],
+) -> Aes256Gcm {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral_key);
+    salt[32..].copy_from_slice(recipient);
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(info, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+}
+
+/// Encrypt `plaintext` to `recipient`. `info` separates the uses of the
+/// scheme; `aad` is authenticated but not encrypted. `None` if `recipient` is
+/// a low-order point.
+pub fn seal(recipient: &[u8; 32], info: &[u8], aad: &[u8], plaintext: &[u8]) -> Option<Envelope> {
+    let ephemeral_secret: [u8; 32] = rand::random();
+    let ephemeral_key = public_key(&ephemeral_secret);
+    let shared = shared_secret(recipient, ephemeral_secret)?;
+    let nonce: [u8; 12] = rand::random();
+    let ciphertext = cipher(shared, &ephemeral_key, recipient, info)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("AES-GCM encryption cannot fail for in-memory buffers");
+    Some(Envelope {
+        ephemeral_key,
+        nonce,
+        ciphertext,
+    })
+}
+
+/// Decrypt an envelope with the recipient's secret; `None` if it was not
+/// sealed to this key with the same `info` and `aad`, was tampered with, or
+/// its ephemeral key is a low-order point.
+pub fn open(secret: &[u8; 32], envelope: &Envelope, info: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    let shared = shared_secret(&envelope.ephemeral_key, *secret)?;
+    cipher(shared, &envelope.ephemeral_key, &public_key(secret), info)
+        .decrypt(
+            Nonce::from_slice(&envelope.nonce),
+            Payload {
+                msg: &envelope.ciphertext,
+                aad,
+            },
+        )
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::{
+        Aes256Gcm, Nonce,
+        aead::{Aead, KeyInit, Payload},
+    };
+    use hkdf::Hkdf;
+    use sha2::Sha256;
+
+    use crate::ecies::{Envelope, is_low_order, open, public_key, seal};
+
+    /// A point of order 8 on Curve25519.
+    const ORDER_EIGHT: [u8; 32] = [
+        0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f, 0xc4,
+        0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16, 0x5f, 0x49,
+        0xb8, 0x00,
+    ];
+
+    #[test]
+    fn test_seal_and_open() {
+        let secret: [u8; 32] = rand::random();
+        let envelope = seal(&public_key(&secret), b"test", b"aad", b"hello").unwrap();
+        assert_eq!(open(&secret, &envelope, b"test", b"aad").unwrap(), b"hello");
+        assert_eq!(open(&secret, &envelope, b"test", b"other"), None);
+        assert!(!is_low_order(&public_key(&secret)));
+    }
+
+    #[test]
+    fn test_low_order_points_are_refused() {
+        for key in [[0; 32], ORDER_EIGHT] {
+            assert!(is_low_order(&key));
+            assert_eq!(seal(&key, b"test", b"", b"hello"), None);
+        }
+
+        // An envelope whose ephemeral key is of order 8 has the all-zero
+        // shared secret, so anyone could have made it; open refuses it.
+        let secret: [u8; 32] = rand::random();
+        let mut salt = [0u8; 64];
+        salt[..32].copy_from_slice(&ORDER_EIGHT);
+        salt[32..].copy_from_slice(&public_key(&secret));
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&salt), &[0; 32])
+            .expand(b"test", &mut key)
+            .unwrap();
+        let ciphertext = Aes256Gcm::new_from_slice(&key)
+            .unwrap()
+            .encrypt(
+                Nonce::from_slice(&[0; 12]),
+                Payload {
+                    msg: b"forged",
+                    aad: b"",
+                },
+            )
+            .unwrap();
+        let envelope = Envelope {
+            ephemeral_key: ORDER_EIGHT,
+            nonce: [0; 12],
+            ciphertext,
+        };
+        assert_eq!(open(&secret, &envelope, b"test", b""), None);
+    }
+}
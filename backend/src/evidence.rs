@@ -0,0 +1,497 @@
+//! Signed evidence of a failed verification and the incident report built
+//! from it.
+//!
+//! `run_verify` only says that some party deviated. Every party therefore
+//! keeps a transcript of its session: the message ID and SHA-256 digest of
+//! each frame it sent and received, and a running hash over all of them. When
+//! verification fails, each party signs its transcript with its evidence key,
+//! an Ed25519 key of its own kept apart from its `PartySecret`, and the
+//! backend cross-checks the signed transcripts. That pins down a party that
+//! fails without evidence, forges it, or sends the other two different
+//! versions of one message. A party that sends consistent but wrong shares
+//! looks like everyone else on the wire, so it is only caught by the abort,
+//! not named.
+
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use futures_util::{Sink, Stream};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sl_mpc_mate::coord::{MessageSendError, Relay};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::sealed_order::put_bytes;
+
+/// Domain separator at the start of every signed evidence encoding.
+const EVIDENCE_DOMAIN: &[u8] = b"darkpool evidence v1\0";
+
+/// Every relay frame starts with the 32-byte ID of the message it carries
+/// or asks for.
+const MSG_ID_SIZE: usize = 32;
+
+/// One frame of a transcript.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameDigest {
+    #[serde(with = "hex::serde")]
+    pub message_id: [u8; 32],
+    /// SHA-256 of the whole frame.
+    #[serde(with = "hex::serde")]
+    pub digest: [u8; 32],
+}
+
+impl FrameDigest {
+    fn of(frame: &[u8]) -> Self {
+        let mut message_id = [0u8; MSG_ID_SIZE];
+        let len = frame.len().min(MSG_ID_SIZE);
+        message_id[..len].copy_from_slice(&frame[..len]);
+        FrameDigest {
+            message_id,
+            digest: Sha256::digest(frame).into(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Log {
+    sent: Vec<FrameDigest>,
+    received: Vec<FrameDigest>,
+    hash: Sha256,
+}
+
+/// The frames of one party's session, shared with the relay recording them.
+#[derive(Clone, Default)]
+pub struct Transcript(Arc<Mutex<Log>>);
+
+impl Transcript {
+    /// Start over, for the next epoch of a session.
+    pub fn clear(&self) {
+        *self.0.lock().unwrap() = Log::default();
+    }
+
+    fn record(&self, sent: bool, frame: &[u8]) {
+        let digest = FrameDigest::of(frame);
+        let mut log = self.0.lock().unwrap();
+        log.hash.update([sent as u8]);
+        log.hash.update(digest.digest);
+        if sent {
+            log.sent.push(digest);
+        } else {
+            log.received.push(digest);
+        }
+    }
+}
+
+/// Wraps any `Relay` and records every frame passing through it.
+pub struct TranscriptRelay<R> {
+    inner: R,
+    transcript: Transcript,
+}
+
+impl<R: Relay> TranscriptRelay<R> {
+    pub fn new(inner: R, transcript: Transcript) -> Self {
+        TranscriptRelay { inner, transcript }
+    }
+}
+
+impl<R: Relay> Sink<Vec<u8>> for TranscriptRelay<R> {
+    type Error = MessageSendError;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        this.transcript.record(true, &item);
+        Pin::new(&mut this.inner).start_send(item)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+impl<R: Relay> Stream for TranscriptRelay<R> {
+    type Item = Vec<u8>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let next = Pin::new(&mut this.inner).poll_next(cx);
+        if let Poll::Ready(Some(frame)) = &next {
+            this.transcript.record(false, frame);
+        }
+        next
+    }
+}
+impl<R: Relay> Relay for TranscriptRelay<R> {}
+
+/// Read a party's evidence key, stored as 64 hex digits.
+pub fn load_evidence_key(path: &Path) -> Result<SigningKey> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(text.trim(), &mut key)
+        .with_context(|| format!("Invalid evidence key in {}", path.display()))?;
+    Ok(SigningKey::from_bytes(&key))
+}
+
+/// What a party signs its evidence with, bound to one protocol instance.
+#[derive(Clone)]
+pub struct Witness {
+    instance: [u8; 32],
+    key: SigningKey,
+}
+
+impl Witness {
+    pub fn new(instance: [u8; 32], key: &SigningKey) -> Self {
+        Witness {
+            instance,
+            key: key.clone(),
+        }
+    }
+
+    /// The same key, bound to another instance.
+    pub fn for_instance(&self, instance: [u8; 32]) -> Self {
+        Witness {
+            instance,
+            key: self.key.clone(),
+        }
+    }
+
+    /// Sign the transcript of `party`'s failed session.
+    pub fn testify(&self, party: usize, transcript: &Transcript, error: String) -> Evidence {
+        let log = transcript.0.lock().unwrap();
+        let mut evidence = Evidence {
+            party,
+            instance: self.instance,
+            error,
+            transcript_hash: log.hash.clone().finalize().into(),
+            sent: log.sent.clone(),
+            received: log.received.clone(),
+            signature: [0; 64],
+        };
+        evidence.signature = self.key.sign(&evidence.signing_bytes()).to_bytes();
+        evidence
+    }
+}
+
+/// One party's signed account of a session that failed verification.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Evidence {
+    pub party: usize,
+    #[serde(with = "hex::serde")]
+    pub instance: [u8; 32],
+    pub error: String,
+    /// Hash over the direction and digest of every frame, in order.
+    #[serde(with = "hex::serde")]
+    pub transcript_hash: [u8; 32],
+    pub sent: Vec<FrameDigest>,
+    pub received: Vec<FrameDigest>,
+    #[serde(with = "hex::serde")]
+    pub signature: [u8; 64],
+}
+
+impl Evidence {
+    /// `EVIDENCE_DOMAIN`, the party index as one byte, the instance ID, the
+    /// error as a big-endian `u32` length and UTF-8 bytes, the transcript
+    /// hash, then the sent and received frames, each list as a `u32` count
+    /// followed by message ID and digest of every frame.
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut out = EVIDENCE_DOMAIN.to_vec();
+        out.push(self.party as u8);
+        out.extend_from_slice(&self.instance);
+        put_bytes(&mut out, self.error.as_bytes());
+        out.extend_from_slice(&self.transcript_hash);
+        for frames in [&self.sent, &self.received] {
+            out.extend_from_slice(&(frames.len() as u32).to_be_bytes());
+            for frame in frames {
+                out.extend_from_slice(&frame.message_id);
+                out.extend_from_slice(&frame.digest);
+            }
+        }
+        out
+    }
+
+    pub fn verify(&self, key: &VerifyingKey) -> bool {
+        key.verify(
+            &self.signing_bytes(),
+            &Signature::from_bytes(&self.signature),
+        )
+        .is_ok()
+    }
+}
+
+/// What a party handed in for an epoch that failed.
+#[derive(Clone, Debug)]
+pub enum Account {
+    /// It finished the epoch, having passed its own checks.
+    Passed,
+    /// It failed a check and signed its transcript.
+    Testified(Box<Evidence>),
+    /// It failed without evidence, or never answered.
+    Silent,
+}
+
+/// Something the cross-check of the transcripts turned up.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "finding", rename_all = "snake_case")]
+pub enum Finding {
+    /// The party failed without evidence or never answered: it stopped
+    /// before its checks.
+    NoEvidence { party: usize },
+    /// The evidence is not signed by the party's evidence key, or is for
+    /// another instance.
+    BadEvidence { party: usize },
+    /// The party sent two versions of one message: the other two signed
+    /// that they received different ones, or the party signed both itself.
+    Equivocation {
+        party: usize,
+        #[serde(with = "hex::serde")]
+        message_id: [u8; 32],
+    },
+    /// The party received a frame no party signed as sent: the relay altered
+    /// or injected it, or its sender left it out of its transcript.
+    Unattributed {
+        receiver: usize,
+        #[serde(with = "hex::serde")]
+        message_id: [u8; 32],
+    },
+}
+
+/// What operators get when a batch fails verification.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IncidentReport {
+    pub batch_id: String,
+    #[serde(with = "hex::serde")]
+    pub instance: [u8; 32],
+    pub reported_at: DateTime<Utc>,
+    /// Parties the findings point at; empty when the transcripts agree and
+    /// the deviation cannot be attributed.
+    pub suspects: BTreeSet<usize>,
+    pub findings: Vec<Finding>,
+    pub evidence: Vec<Evidence>,
+}
+
+impl IncidentReport {
+    /// Cross-check the accounts of the parties, by participant index, against
+    /// their evidence keys.
+    ///
+    /// A message that two parties signed as received in different versions
+    /// can only have come from the third, as no party receives its own
+    /// messages. That trusts the relay not to alter frames, which the
+    /// parties reach over TLS; the altered frame is also reported as
+    /// `Unattributed` unless its sender signed it.
+    pub fn assess(
+        batch_id: &str,
+        instance: [u8; 32],
+        accounts: [Account; 3],
+        keys: &[VerifyingKey; 3],
+    ) -> Self {
+        let mut findings = vec![];
+        let mut valid = vec![];
+        for (party, account) in accounts.iter().enumerate() {
+            match account {
+                Account::Passed => {}
+                Account::Silent => findings.push(Finding::NoEvidence { party }),
+                Account::Testified(e)
+                    if e.party != party || e.instance != instance || !e.verify(&keys[party]) =>
+                {
+                    findings.push(Finding::BadEvidence { party })
+                }
+                Account::Testified(e) => valid.push(e),
+            }
+        }
+
+        // Who signed each (message, digest) as sent, and who received each
+        // digest of each message.
+        let mut senders: BTreeMap<([u8; 32], [u8; 32]), BTreeSet<usize>> = BTreeMap::new();
+        let mut delivered: BTreeMap<[u8; 32], BTreeMap<[u8; 32], BTreeSet<usize>>> =
+            BTreeMap::new();
+        for e in &valid {
+            for frame in &e.sent {
+                senders
+                    .entry((frame.message_id, frame.digest))
+                    .or_default()
+                    .insert(e.party);
+            }
+        }
+        for e in &valid {
+            for frame in &e.received {
+                delivered
+                    .entry(frame.message_id)
+                    .or_default()
+                    .entry(frame.digest)
+                    .or_default()
+                    .insert(e.party);
+                let sent_by_other = senders
+                    .get(&(frame.message_id, frame.digest))
+                    .is_some_and(|parties| parties.iter().any(|&p| p != e.party));
+                if !sent_by_other {
+                    findings.push(Finding::Unattributed {
+                        receiver: e.party,
+                        message_id: frame.message_id,
+                    });
+                }
+            }
+        }
+        let mut equivocated = BTreeSet::new();
+        for (message_id, digests) in delivered.iter().filter(|(_, d)| d.len() > 1) {
+            let receivers: Vec<&BTreeSet<usize>> = digests.values().collect();
+            for (n, first) in receivers.iter().enumerate() {
+                for second in &receivers[n + 1..] {
+                    for a in *first {
+                        for b in *second {
+                            if a != b {
+                                equivocated.insert((3 - a - b, *message_id));
+                            }
+                        }
+                    }
+                }
+            }
+            for e in &valid {
+                let versions = e
+                    .sent
+                    .iter()
+                    .filter(|f| f.message_id == *message_id && digests.contains_key(&f.digest))
+                    .map(|f| f.digest)
+                    .collect::<BTreeSet<_>>();
+                if versions.len() > 1 {
+                    equivocated.insert((e.party, *message_id));
+                }
+            }
+        }
+        findings.extend(
+            equivocated
+                .into_iter()
+                .map(|(party, message_id)| Finding::Equivocation { party, message_id }),
+        );
+
+        let suspects = findings
+            .iter()
+            .filter_map(|finding| match finding {
+                Finding::NoEvidence { party }
+                | Finding::BadEvidence { party }
+                | Finding::Equivocation { party, .. } => Some(*party),
+                Finding::Unattributed { .. } => None,
+            })
+            .collect();
+        IncidentReport {
+            batch_id: batch_id.to_owned(),
+            instance,
+            reported_at: Utc::now(),
+            suspects,
+            findings,
+            evidence: accounts
+                .into_iter()
+                .filter_map(|account| match account {
+                    Account::Testified(evidence) => Some(*evidence),
+                    _ => None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Append the report to `path` as one JSON line.
+    pub fn append(&self, path: &Path) -> anyhow::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+
+    use crate::evidence::{Account, Finding, IncidentReport, Transcript, Witness};
+
+    #[test]
+    fn test_equivocation_and_missing_evidence_are_blamed() {
+        let instance = [9; 32];
+        let secrets = [0, 1, 2].map(|_| SigningKey::from_bytes(&rand::random()));
+        let keys = secrets.each_ref().map(SigningKey::verifying_key);
+        let transcripts = [0, 1, 2].map(|_| Transcript::default());
+        let frame = |id: u8, body: u8| {
+            let mut frame = vec![id; 32];
+            frame.push(body);
+            frame
+        };
+
+        // Party 0 sends two versions of message 1 but signs only one; party
+        // 1 and 2 each get one. Party 1 answers party 2 honestly.
+        transcripts[0].record(true, &frame(1, 0));
+        transcripts[1].record(false, &frame(1, 0));
+        transcripts[2].record(false, &frame(1, 1));
+        transcripts[1].record(true, &frame(2, 0));
+        transcripts[2].record(false, &frame(2, 0));
+        let evidence = |party: usize| {
+            Witness::new(instance, &secrets[party]).testify(
+                party,
+                &transcripts[party],
+                "verification failed".into(),
+            )
+        };
+
+        let testified = |party: usize| Account::Testified(Box::new(evidence(party)));
+
+        let report = IncidentReport::assess("b1", instance, [0, 1, 2].map(testified), &keys);
+        assert_eq!(report.suspects, [0].into());
+        assert_eq!(
+            report.findings,
+            [
+                Finding::Unattributed {
+                    receiver: 2,
+                    message_id: [1; 32]
+                },
+                Finding::Equivocation {
+                    party: 0,
+                    message_id: [1; 32]
+                }
+            ]
+        );
+
+        // The receivers alone are enough, even if party 0 passed.
+        let report = IncidentReport::assess(
+            "b1",
+            instance,
+            [Account::Passed, testified(1), testified(2)],
+            &keys,
+        );
+        assert_eq!(report.suspects, [0].into());
+
+        // Having passed, party 1 is not suspected for having no evidence.
+        let report = IncidentReport::assess(
+            "b1",
+            instance,
+            [testified(0), Account::Passed, testified(2)],
+            &keys,
+        );
+        assert!(report.suspects.is_empty());
+
+        // Party 1's evidence is missing, party 2's altered after signing.
+        let mut altered = evidence(2);
+        altered.received.clear();
+        let report = IncidentReport::assess(
+            "b1",
+            instance,
+            [
+                testified(0),
+                Account::Silent,
+                Account::Testified(Box::new(altered)),
+            ],
+            &keys,
+        );
+        assert_eq!(report.suspects, [1, 2].into());
+        assert!(report.findings.contains(&Finding::NoEvidence { party: 1 }));
+        assert!(report.findings.contains(&Finding::BadEvidence { party: 2 }));
+    }
+}
@@ -0,0 +1,243 @@
+use futures_util::{Sink, SinkExt, Stream, StreamExt, stream::SplitStream};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use sl_mpc_mate::coord::{MessageSendError, Relay};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, sleep_until};
+
+/// Faults injected into the outgoing frames of a `FaultyRelay`.
+///
+/// Every decision is drawn from a ChaCha8 stream seeded with `seed`, so a
+/// given profile applied to the same sequence of frames always drops,
+/// duplicates and reorders the same frames.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultProfile {
+    pub seed: u64,
+    /// Fixed delay added to every frame.
+    #[serde(with = "millis")]
+    pub latency: Duration,
+    /// Upper bound of the uniformly distributed extra delay per frame.
+    #[serde(with = "millis")]
+    pub jitter: Duration,
+    /// Probability in `[0, 1]` that a frame is silently discarded.
+    pub drop_rate: f64,
+    /// Probability that a frame is delivered twice.
+    pub duplicate_rate: f64,
+    /// Probability that a frame is held back for an extra `reorder_window`,
+    /// letting the frames sent after it overtake it.
+    pub reorder_rate: f64,
+    #[serde(with = "millis")]
+    pub reorder_window: Duration,
+}
+
+impl FaultProfile {
+    /// The same profile with a seed unique to connection `conn_id`, so that
+    /// parties sharing a profile do not suffer identical fault patterns.
+    pub fn for_connection(&self, conn_id: u64) -> Self {
+        FaultProfile {
+            seed: self.seed.wrapping_add(conn_id),
+            ..self.clone()
+        }
+    }
+}
+
+mod millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(d.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        u64::deserialize(d).map(Duration::from_millis)
+    }
+}
+
+/// A frame waiting in the fault scheduler, ordered by delivery time and then
+/// by scheduling order.
+type Scheduled = Reverse<(Instant, u64, Vec<u8>)>;
+
+/// Wraps any `Relay` and applies a `FaultProfile` to every frame sent
+/// through it. Received frames are passed through untouched; wrap the peer's
+/// relay (or use the relay server's fault mode) to disturb the other
+/// direction.
+pub struct FaultyRelay<R: Relay> {
+    tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    stream: SplitStream<R>,
+}
+
+impl<R> FaultyRelay<R>
+where
+    R: Relay + Send + 'static,
+{
+    pub fn new(inner: R, profile: FaultProfile) -> Self {
+        let (sink, stream) = inner.split();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_scheduler(sink, rx, profile));
+        FaultyRelay {
+            tx: Some(tx),
+            stream,
+        }
+    }
+}
+
+fn chance(rng: &mut ChaCha8Rng, p: f64) -> bool {
+    p > 0.0 && rng.gen_bool(p.min(1.0))
+}
+
+/// Drive delayed frames into `sink` until the `FaultyRelay` is dropped or
+/// closed and every scheduled frame has been delivered.
+async fn run_scheduler<S>(
+    mut sink: S,
+    mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    profile: FaultProfile,
+) where
+    S: Sink<Vec<u8>> + Unpin,
+{
+    let mut rng = ChaCha8Rng::seed_from_u64(profile.seed);
+    let mut queue: BinaryHeap<Scheduled> = BinaryHeap::new();
+    let mut seq = 0u64;
+    let mut open = true;
+
+    while open || !queue.is_empty() {
+        let next_due = queue.peek().map(|Reverse((at, _, _))| *at);
+        tokio::select! {
+            frame = rx.recv(), if open => {
+                let Some(frame) = frame else {
+                    open = false;
+                    continue;
+                };
+                if chance(&mut rng, profile.drop_rate) {
+                    continue;
+                }
+                let mut at = Instant::now() + profile.latency;
+                if !profile.jitter.is_zero() {
+                    at += profile.jitter.mul_f64(rng.gen_range(0.0..=1.0));
+                }
+                if chance(&mut rng, profile.reorder_rate) {
+                    at += profile.reorder_window;
+                }
+                let copies = if chance(&mut rng, profile.duplicate_rate) { 2 } else { 1 };
+                for _ in 0..copies {
+                    queue.push(Reverse((at, seq, frame.clone())));
+                    seq += 1;
+                }
+            }
+            _ = sleep_until(next_due.unwrap_or_else(Instant::now)), if next_due.is_some() => {
+                let now = Instant::now();
+                while let Some(Reverse((at, _, _))) = queue.peek() {
+                    if *at > now {
+                        break;
+                    }
+                    let Reverse((_, _, frame)) = queue.pop().unwrap();
+                    if sink.send(frame).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+    let _ = sink.close().await;
+}
+
+impl<R: Relay> Sink<Vec<u8>> for FaultyRelay<R> {
+    type Error = MessageSendError;
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match &self.tx {
+            Some(tx) if !tx.is_closed() => Poll::Ready(Ok(())),
+            _ => Poll::Ready(Err(MessageSendError)),
+        }
+    }
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        match &self.tx {
+            Some(tx) => tx.send(item).map_err(|_| MessageSendError),
+            None => Err(MessageSendError),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Frames are handed to the scheduler task immediately; delivery is
+        // deliberately decoupled from the caller.
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Dropping the sender lets the scheduler drain and close the inner relay.
+        self.get_mut().tx = None;
+        Poll::Ready(Ok(()))
+    }
+}
+impl<R: Relay> Stream for FaultyRelay<R> {
+    type Item = Vec<u8>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().stream.poll_next_unpin(cx)
+    }
+}
+impl<R: Relay> Relay for FaultyRelay<R> {}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::Duration;
+
+    use crate::fault_relay::{FaultProfile, FaultyRelay};
+    use crate::socket_relay::SocketRelay;
+
+    /// Push `count` numbered frames through a faulty loopback and return the
+    /// numbers in the order they arrived.
+    async fn deliver(profile: FaultProfile, count: u8) -> Vec<u8> {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let mut sender = FaultyRelay::new(SocketRelay::new(a), profile);
+        let mut receiver = SocketRelay::new(b);
+        for i in 0..count {
+            sender.send(vec![i]).await.unwrap();
+        }
+        sender.close().await.unwrap();
+
+        let mut received = vec![];
+        while let Ok(Some(frame)) =
+            tokio::time::timeout(Duration::from_millis(200), receiver.next()).await
+        {
+            received.push(frame[0]);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn test_faults_are_deterministic_per_seed() {
+        let profile = FaultProfile {
+            seed: 42,
+            drop_rate: 0.2,
+            duplicate_rate: 0.2,
+            ..Default::default()
+        };
+
+        let first = deliver(profile.clone(), 100).await;
+        let second = deliver(profile, 100).await;
+
+        assert_eq!(first, second);
+        assert_ne!(first, (0..100).collect::<Vec<u8>>());
+    }
+
+    #[tokio::test]
+    async fn test_reordering_keeps_every_frame() {
+        let profile = FaultProfile {
+            seed: 7,
+            reorder_rate: 0.3,
+            reorder_window: Duration::from_millis(5),
+            ..Default::default()
+        };
+
+        let received = deliver(profile, 50).await;
+        let mut sorted = received.clone();
+        sorted.sort();
+
+        assert_eq!(sorted, (0..50).collect::<Vec<u8>>());
+    }
+}
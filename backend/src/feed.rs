@@ -0,0 +1,184 @@
+//! Execution reports pushed to traders over WebSocket.
+//!
+//! `GET /reports` upgrades to a WebSocket that carries one JSON
+//! `ExecReport` per text message for the caller's own orders, from the
+//! moment of connecting: `accepted` on submit, `traded` when a batch matches
+//! the order, `cancelled`, `expired` and `rejected`; all but `accepted` are
+//! final.
+//! The request needs the same bearer token as the rest of the HTTP API.
+//! Every connection buffers its own trader's reports only, so other
+//! traders' traffic cannot slow it down; one that falls a full buffer
+//! behind is disconnected and can catch up with `GET /orders`.
+
+use axum::{
+    Router,
+    extract::{
+        State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::Response,
+    routing::get,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::book::TraderFill;
+use crate::http_api::{ApiState, Trader};
+use crate::validate::RejectCode;
+
+/// What happened to an order.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "exec_type", rename_all = "snake_case")]
+pub enum ExecType {
+    Accepted,
+    /// The order traded in a batch. Orders trade once, so any remainder is
+    /// cancelled. The executed quantity, and whether it was the whole
+    /// order, is secret to the counterparties: decrypt `fill.shares` (see
+    /// `fill::reconstruct_fill`) to learn it.
+    Traded {
+        fill: TraderFill,
+    },
+    Cancelled,
+    Expired,
+    Rejected {
+        code: RejectCode,
+    },
+}
+
+/// One execution report, as sent on the feed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExecReport {
+    pub order_id: u64,
+    #[serde(flatten)]
+    pub exec: ExecType,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl ExecReport {
+    pub fn new(order_id: u64, exec: ExecType) -> Self {
+        ExecReport {
+            order_id,
+            exec,
+            timestamp: Utc::now(),
+        }
+    }
+}
+
+async fn reports(
+    State(state): State<ApiState>,
+    Trader(trader): Trader,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    // Subscribe before the upgrade so nothing between the two is missed.
+    let reports = state.book.subscribe(&trader);
+    upgrade.on_upgrade(move |socket| send_reports(socket, trader, reports))
+}
+
+async fn send_reports(
+    mut socket: WebSocket,
+    trader: String,
+    mut reports: mpsc::Receiver<ExecReport>,
+) {
+    debug!("{} subscribed to execution reports", trader);
+    loop {
+        tokio::select! {
+            // The book drops a subscriber that fell behind.
+            report = reports.recv() => match report {
+                Some(report) => {
+                    let text = serde_json::to_string(&report).unwrap();
+                    if socket.send(Message::text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            // Only close frames and errors matter; anything else is ignored.
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    let _ = socket.send(Message::Close(None)).await;
+}
+
+pub fn routes() -> Router<ApiState> {
+    Router::new().route("/reports", get(reports))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+    use rust_decimal::Decimal;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::HeaderValue};
+
+    use crate::book::OrderBook;
+    use crate::feed::{ExecReport, ExecType};
+    use crate::http_api::{ApiState, router};
+    use crate::sealed_order::{PartySecret, SealedOrder};
+    use crate::types::Order;
+    use crate::validate::{RejectCode, Validator};
+
+    #[tokio::test]
+    async fn test_traders_only_see_their_own_orders() {
+        let traders = BTreeMap::from([
+            ("alice".to_owned(), "token-a".to_owned()),
+            ("bob".to_owned(), "token-b".to_owned()),
+        ]);
+        let book = Arc::new(OrderBook::new());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = router(ApiState::new(book.clone(), &traders));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let mut request = format!("ws://{}/reports", addr)
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert("authorization", HeaderValue::from_static("Bearer token-a"));
+        let (mut feed, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+        let keys = [0, 1, 2].map(|_| PartySecret::generate().public_key());
+        let order = Order {
+            o_type: false,
+            symbol: "MSFT".into(),
+            quantity: 100,
+            price: Decimal::from(150),
+            min_execution: 50,
+            client_key: None,
+        };
+        let order = SealedOrder::seal(&order, &keys, &Validator::default()).unwrap();
+        // A subscriber of bob's that never reads falls behind without
+        // holding up alice's feed.
+        let _stalled = book.subscribe("bob");
+        for _ in 0..2000 {
+            book.submit(order.clone(), Some("bob".into())).unwrap();
+        }
+        let bobs = book.submit(order.clone(), Some("bob".into())).unwrap();
+        let alices = book.submit(order, Some("alice".into())).unwrap();
+        book.cancel(bobs).unwrap();
+        book.reject(&[alices], RejectCode::OutOfRange);
+
+        let mut next = async || {
+            let message = feed.next().await.unwrap().unwrap();
+            serde_json::from_str::<ExecReport>(message.to_text().unwrap()).unwrap()
+        };
+        let accepted = next().await;
+        assert_eq!(accepted.order_id, alices);
+        assert!(matches!(accepted.exec, ExecType::Accepted));
+        let rejected = next().await;
+        assert_eq!(rejected.order_id, alices);
+        assert!(matches!(
+            rejected.exec,
+            ExecType::Rejected {
+                code: RejectCode::OutOfRange
+            }
+        ));
+    }
+}
@@ -0,0 +1,351 @@
+use anyhow::{Context, Result, bail, ensure};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sl_compute::types::{ArithmeticShare, BinaryShare, FieldElement};
+use std::io::Write;
+use std::path::Path;
+
+use crate::ecies::{self, Envelope};
+use crate::price::PriceScale;
+
+/// HKDF info of fill share envelopes.
+const FILL_INFO: &[u8] = b"darkpool fill share";
+
+/// X25519 public key a trader attaches to its orders to receive fills.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientKey(#[serde(with = "hex::serde")] pub [u8; 32]);
+
+/// The trader's side of a `ClientKey`. Never leaves the client.
+pub struct ClientSecret([u8; 32]);
+
+impl ClientSecret {
+    pub fn generate() -> Self {
+        ClientSecret(rand::random())
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        ClientSecret(bytes)
+    }
+
+    pub fn public_key(&self) -> ClientKey {
+        ClientKey(ecies::public_key(&self.0))
+    }
+}
+
+/// One node's share of the terms of a matched buy/sell pair.
+///
+/// Order IDs, symbol and timestamps are public to the nodes; quantity and
+/// price are not, and are only ever reconstructed by the counterparties.
+#[derive(Clone, Debug)]
+pub struct FillShare {
+    pub buy_order_id: u64,
+    pub sell_order_id: u64,
+    pub symbol: String,
+    pub buy_timestamp: DateTime<Utc>,
+    pub sell_timestamp: DateTime<Utc>,
+    pub buy_key: Option<ClientKey>,
+    pub sell_key: Option<ClientKey>,
+    /// Shares of the bits of the executed quantity, the smaller of the two
+    /// order quantities, lowest first.
+    pub quantity: Vec<BinaryShare>,
+    /// Shares of whether the buy and the sell were filled in full. Each is
+    /// sealed to its own side only, so nobody else learns which side was
+    /// larger.
+    pub filled: [BinaryShare; 2],
+    /// Share of the sum of both limit prices. The execution price is the
+    /// midpoint, which cannot be computed on shares without a truncation
+    /// protocol, so the client halves the reconstructed sum instead.
+    pub price_sum: ArithmeticShare,
+}
+
+/// Trade ID of the `n`-th (0-based) match of a batch, identical on every node.
+pub fn trade_id(batch_id: &str, n: usize) -> String {
+    format!("{}-{}", batch_id, n + 1)
+}
+
+/// What a node encrypts to a counterparty. The quantity bits are packed
+/// into words, bit `k` of the quantity in bit `k` of each word; `filled` is
+/// the share for the recipient's own side.
+#[derive(Serialize, Deserialize)]
+struct SharePayload {
+    quantity: [u64; 2],
+    price_sum: [u64; 2],
+    filled: [bool; 2],
+}
+
+impl SharePayload {
+    fn new(fill: &FillShare, order_id: u64) -> Self {
+        let words = |s: &ArithmeticShare| [u64::from(s.value1), u64::from(s.value2)];
+        let mut quantity = [0u64; 2];
+        for (k, bit) in fill.quantity.iter().enumerate() {
+            quantity[0] |= (bit.value1 as u64) << k;
+            quantity[1] |= (bit.value2 as u64) << k;
+        }
+        let filled = if order_id == fill.buy_order_id {
+            fill.filled[0]
+        } else {
+            fill.filled[1]
+        };
+        SharePayload {
+            quantity,
+            price_sum: words(&fill.price_sum),
+            filled: [filled.value1, filled.value2],
+        }
+    }
+}
+
+pub(crate) fn share_from_words([value1, value2]: [u64; 2]) -> ArithmeticShare {
+    ArithmeticShare {
+        value1: FieldElement::from(value1),
+        value2: FieldElement::from(value2),
+    }
+}
+
+/// A node's share of one fill, encrypted to one counterparty (see `ecies`),
+/// with the trade ID, order ID and node index as associated data so
+/// envelopes cannot be swapped around.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SealedFill {
+    pub trade_id: String,
+    /// The recipient's order.
+    pub order_id: u64,
+    pub party: usize,
+    #[serde(flatten)]
+    pub envelope: Envelope,
+}
+
+fn associated_data(trade_id: &str, order_id: u64, party: usize) -> Vec<u8> {
+    format!("{}/{}/{}", trade_id, order_id, party).into_bytes()
+}
+
+impl SealedFill {
+    pub fn seal(
+        party: usize,
+        trade_id: &str,
+        order_id: u64,
+        fill: &FillShare,
+        recipient: &ClientKey,
+    ) -> Option<Self> {
+        let plaintext = serde_json::to_vec(&SharePayload::new(fill, order_id)).unwrap();
+        Some(SealedFill {
+            trade_id: trade_id.to_owned(),
+            order_id,
+            party,
+            envelope: ecies::seal(
+                &recipient.0,
+                FILL_INFO,
+                &associated_data(trade_id, order_id, party),
+                &plaintext,
+            )?,
+        })
+    }
+
+    fn open(&self, secret: &ClientSecret) -> Result<SharePayload> {
+        let plaintext = ecies::open(
+            &secret.0,
+            &self.envelope,
+            FILL_INFO,
+            &associated_data(&self.trade_id, self.order_id, self.party),
+        )
+        .with_context(|| format!("share from party {} does not decrypt", self.party))?;
+        serde_json::from_slice(&plaintext).context("malformed fill share")
+    }
+}
+
+/// Seal every fill of one node to both counterparties. Orders submitted
+/// without a client key, or with a low-order one, get nothing.
+pub fn seal_fills(party: usize, batch_id: &str, fills: &[FillShare]) -> Vec<SealedFill> {
+    let mut sealed = Vec::new();
+    for (n, fill) in fills.iter().enumerate() {
+        let trade_id = trade_id(batch_id, n);
+        for (order_id, key) in [
+            (fill.buy_order_id, &fill.buy_key),
+            (fill.sell_order_id, &fill.sell_key),
+        ] {
+            if let Some(key) = key {
+                sealed.extend(SealedFill::seal(party, &trade_id, order_id, fill, key));
+            }
+        }
+    }
+    sealed
+}
+
+/// Append sealed fills to a JSON Lines file.
+pub fn append_sealed_fills<P: AsRef<Path>>(path: P, sealed: &[SealedFill]) -> std::io::Result<()> {
+    let mut out = Vec::new();
+    for fill in sealed {
+        serde_json::to_writer(&mut out, fill)?;
+        out.push(b'\n');
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&out)
+}
+
+fn raw_value(shares: [&ArithmeticShare; 3]) -> FieldElement {
+    shares.iter().fold(FieldElement::ZERO, |acc, share| {
+        acc.wrapping_add(&share.value1)
+    })
+}
+
+/// Reconstruct the value behind one share from each party.
+///
+/// The `value1` components of the three parties form an additive sharing of
+/// the encoded value. `from_constant` applies a fixed-point encoding, so the
+/// result is scaled back using the encoding of one.
+pub fn reconstruct(shares: [&ArithmeticShare; 3]) -> u64 {
+    let one = [0, 1, 2].map(|party| ArithmeticShare::from_constant(&FieldElement::ONE, party));
+    u64::from(raw_value(shares)) / u64::from(raw_value([&one[0], &one[1], &one[2]]))
+}
+
+/// Trade terms as reconstructed by a counterparty.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fill {
+    pub trade_id: String,
+    pub order_id: u64,
+    /// Executed quantity in shares, not lots.
+    pub quantity: u64,
+    /// Midpoint of the two limit prices, exact even when it falls between
+    /// two ticks.
+    pub price: Decimal,
+    /// Whether the order traded its whole quantity. Orders trade once, so
+    /// when it did not, the rest of the order is cancelled with the trade.
+    pub filled: bool,
+}
+
+/// Client side: decrypt the three nodes' shares of one fill and combine them.
+/// `scale` is the price scale of the fill's symbol, as published by the
+/// backend, and `lot_size` its instrument's lot size (1 without one).
+pub fn reconstruct_fill(
+    secret: &ClientSecret,
+    sealed: &[SealedFill],
+    scale: &PriceScale,
+    lot_size: u64,
+) -> Result<Fill> {
+    let [first, ..] = sealed else {
+        bail!("no fill shares");
+    };
+    let mut payloads: [Option<SharePayload>; 3] = Default::default();
+    for share in sealed {
+        ensure!(
+            share.trade_id == first.trade_id && share.order_id == first.order_id,
+            "fill shares belong to different trades"
+        );
+        let slot = payloads
+            .get_mut(share.party)
+            .with_context(|| format!("unknown party {}", share.party))?;
+        ensure!(slot.is_none(), "duplicate share from party {}", share.party);
+        *slot = Some(share.open(secret)?);
+    }
+    let [Some(p0), Some(p1), Some(p2)] = payloads else {
+        bail!("need a share from each of the 3 parties");
+    };
+
+    // The `value1` bits of the three parties XOR to the lots' bits.
+    let quantity = (p0.quantity[0] ^ p1.quantity[0] ^ p2.quantity[0]) * lot_size;
+    let price_sum = [p0.price_sum, p1.price_sum, p2.price_sum].map(share_from_words);
+    let filled = p0.filled[0] ^ p1.filled[0] ^ p2.filled[0];
+    Ok(Fill {
+        trade_id: first.trade_id.clone(),
+        order_id: first.order_id,
+        quantity,
+        price: scale.decode(reconstruct([&price_sum[0], &price_sum[1], &price_sum[2]]))
+            / Decimal::TWO,
+        filled,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+    use sl_compute::types::{ArithmeticShare, BinaryShare, FieldElement};
+
+    use crate::fill::{ClientSecret, FillShare, reconstruct_fill, seal_fills};
+    use crate::price::PriceScale;
+
+    fn fill_shares(
+        quantity: u64,
+        price_sum: u64,
+        buyer: &ClientSecret,
+        seller: &ClientSecret,
+    ) -> [FillShare; 3] {
+        let now = Utc::now();
+        [0, 1, 2].map(|party| FillShare {
+            buy_order_id: 1,
+            sell_order_id: 2,
+            symbol: "MSFT".into(),
+            buy_timestamp: now,
+            sell_timestamp: now,
+            buy_key: Some(buyer.public_key()),
+            sell_key: Some(seller.public_key()),
+            // Party 0 holds the bits, the others zero.
+            quantity: (0..16)
+                .map(|k| {
+                    let bit = party == 0 && (quantity >> k) & 1 == 1;
+                    BinaryShare {
+                        value1: bit,
+                        value2: bit,
+                    }
+                })
+                .collect(),
+            // Shares of: the buy filled in full, the sell partially.
+            filled: [party == 1, party != 0].map(|bit| BinaryShare {
+                value1: bit,
+                value2: bit,
+            }),
+            price_sum: ArithmeticShare::from_constant(&FieldElement::from(price_sum), party),
+        })
+    }
+
+    #[test]
+    fn test_counterparty_reconstructs_fill() {
+        let (buyer, seller) = (ClientSecret::generate(), ClientSecret::generate());
+        let (sealed, sells): (Vec<_>, Vec<_>) = fill_shares(80, 301, &buyer, &seller)
+            .iter()
+            .enumerate()
+            .flat_map(|(party, fill)| seal_fills(party, "b1", std::slice::from_ref(fill)))
+            .partition(|share| share.order_id == 1);
+
+        assert_eq!((sealed.len(), sells.len()), (3, 3));
+        let cents = PriceScale {
+            scale: 2,
+            tick_size: None,
+        };
+        let fill = reconstruct_fill(&buyer, &sealed, &cents, 1).unwrap();
+        assert_eq!((fill.trade_id.as_str(), fill.order_id), ("b1-1", 1));
+        assert_eq!(fill.quantity, 80);
+        // 80 lots of 100.
+        let lots = reconstruct_fill(&buyer, &sealed, &cents, 100).unwrap();
+        assert_eq!(lots.quantity, 8000);
+        // Limits of 1.50 and 1.51 meet at 1.505, between two cents.
+        assert_eq!(fill.price, "1.505".parse::<Decimal>().unwrap());
+        // Each side learns whether it filled in full, not the other's.
+        assert!(fill.filled);
+        let sell = reconstruct_fill(&seller, &sells, &cents, 1).unwrap();
+        assert_eq!((sell.order_id, sell.quantity, sell.filled), (2, 80, false));
+        assert!(reconstruct_fill(&seller, &sealed, &cents, 1).is_err());
+
+        let outsider = ClientSecret::generate();
+        assert!(reconstruct_fill(&outsider, &sealed, &cents, 1).is_err());
+        assert!(reconstruct_fill(&buyer, &sealed[..2], &cents, 1).is_err());
+    }
+
+    #[test]
+    fn test_tampered_envelope_is_rejected() {
+        let buyer = ClientSecret::generate();
+        let mut sealed: Vec<_> = fill_shares(80, 300, &buyer, &ClientSecret::generate())
+            .iter()
+            .enumerate()
+            .flat_map(|(party, fill)| seal_fills(party, "b1", std::slice::from_ref(fill)))
+            .filter(|share| share.order_id == 1)
+            .collect();
+        sealed[1].party = 0;
+        sealed[0].party = 1;
+
+        assert!(reconstruct_fill(&buyer, &sealed, &PriceScale::default(), 1).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use axum::{
+    Json, Router,
+    extract::{FromRequestParts, Path, State},
+    http::{StatusCode, header::AUTHORIZATION, request::Parts},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::info;
+
+use crate::a2a::{self, Contexts};
+use crate::book::{BatchState, CancelError, OrderBook, OrderEntry, OrderStatus, TraderFill};
+use crate::feed;
+use crate::instruments::Instrument;
+use crate::keystore::SignedOrder;
+use crate::price::PriceScale;
+use crate::sealed_order::PartyKey;
+use crate::validate::{BitWidths, RejectCode};
+
+/// State shared by all HTTP handlers.
+#[derive(Clone)]
+pub struct ApiState {
+    pub book: Arc<OrderBook>,
+    /// Bearer token → trader name.
+    tokens: Arc<HashMap<String, String>>,
+    pub(crate) contexts: Contexts,
+}
+
+impl ApiState {
+    /// `traders` maps each trader name to its API token, as in the config file.
+    pub fn new(book: Arc<OrderBook>, traders: &BTreeMap<String, String>) -> Self {
+        let tokens = traders
+            .iter()
+            .map(|(trader, token)| (token.clone(), trader.clone()))
+            .collect();
+        ApiState {
+            book,
+            tokens: Arc::new(tokens),
+            contexts: Contexts::default(),
+        }
+    }
+}
+
+/// Error body of every non-2xx response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiError {
+    #[serde(skip)]
+    status: StatusCode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<RejectCode>,
+    pub error: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, error: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code: None,
+            error: error.into(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self)).into_response()
+    }
+}
+
+impl From<RejectCode> for ApiError {
+    fn from(code: RejectCode) -> Self {
+        ApiError {
+            code: Some(code),
+            ..ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, code.to_string())
+        }
+    }
+}
+
+impl From<CancelError> for ApiError {
+    fn from(e: CancelError) -> Self {
+        let status = match e {
+            CancelError::UnknownOrder => StatusCode::NOT_FOUND,
+            _ => StatusCode::CONFLICT,
+        };
+        ApiError::new(status, e.to_string())
+    }
+}
+
+/// The trader authenticated by the request's bearer token.
+pub struct Trader(pub String);
+
+impl FromRequestParts<ApiState> for Trader {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ApiState) -> Result<Self, ApiError> {
+        parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| state.tokens.get(token))
+            .map(|trader| Trader(trader.clone()))
+            .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "missing or invalid API token"))
+    }
+}
+
+/// Reply to order submits and cancels.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderAck {
+    pub order_id: u64,
+    pub status: OrderStatus,
+}
+
+/// Look up one of the caller's orders. Orders of other traders are reported
+/// as missing rather than forbidden, so IDs reveal nothing.
+fn own_order(state: &ApiState, trader: &str, order_id: u64) -> Result<OrderEntry, ApiError> {
+    state
+        .book
+        .get_own(order_id, trader)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "unknown order"))
+}
+
+async fn submit_order(
+    State(state): State<ApiState>,
+    Trader(trader): Trader,
+    Json(order): Json<SignedOrder>,
+) -> Result<(StatusCode, Json<OrderAck>), ApiError> {
+    let order_id = state.book.submit_signed(order, Some(&trader))?;
+    info!("Accepted order {} from {}", order_id, trader);
+    Ok((
+        StatusCode::CREATED,
+        Json(OrderAck {
+            order_id,
+            status: OrderStatus::Resting,
+        }),
+    ))
+}
+
+async fn list_orders(
+    State(state): State<ApiState>,
+    Trader(trader): Trader,
+) -> Json<Vec<OrderEntry>> {
+    Json(state.book.orders_of(&trader))
+}
+
+async fn get_order(
+    State(state): State<ApiState>,
+    Trader(trader): Trader,
+    Path(order_id): Path<u64>,
+) -> Result<Json<OrderEntry>, ApiError> {
+    own_order(&state, &trader, order_id).map(Json)
+}
+
+async fn cancel_order(
+    State(state): State<ApiState>,
+    Trader(trader): Trader,
+    Path(order_id): Path<u64>,
+) -> Result<Json<OrderAck>, ApiError> {
+    own_order(&state, &trader, order_id)?;
+    state.book.cancel(order_id)?;
+    Ok(Json(OrderAck {
+        order_id,
+        status: OrderStatus::Cancelled,
+    }))
+}
+
+async fn list_fills(
+    State(state): State<ApiState>,
+    Trader(trader): Trader,
+) -> Json<Vec<TraderFill>> {
+    Json(state.book.fills_of(&trader))
+}
+
+/// Public: traders need the keys before they can place an order.
+async fn party_keys(State(state): State<ApiState>) -> Result<Json<[PartyKey; 3]>, ApiError> {
+    state
+        .book
+        .party_keys()
+        .map(Json)
+        .ok_or_else(|| ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "party keys not configured"))
+}
+
+/// Public, like the party keys.
+async fn price_scales(State(state): State<ApiState>) -> Json<BTreeMap<String, PriceScale>> {
+    Json(state.book.price_scales())
+}
+
+async fn instruments(State(state): State<ApiState>) -> Json<BTreeMap<String, Instrument>> {
+    Json(state.book.instruments())
+}
+
+async fn widths(State(state): State<ApiState>) -> Json<BitWidths> {
+    Json(state.book.widths())
+}
+
+async fn batch_state(State(state): State<ApiState>, _: Trader) -> Json<BatchState> {
+    Json(state.book.batch_state())
+}
+
+/// Routes:
+///
+/// - `POST /orders` submit a `SignedOrder`, signed with one of the caller's
+///   keys; `GET /orders` list the caller's orders
+/// - `GET /orders/{id}`, `DELETE /orders/{id}` look up or cancel one of them
+/// - `GET /fills` the caller's fills, with the sealed shares of the terms
+/// - `GET /batch` the batch loop's state
+/// - `GET /party-keys` the keys to seal orders to, in participant order
+/// - `GET /price-scales` each symbol's price encoding
+/// - `GET /instruments` the instrument master
+/// - `GET /widths` the bit widths to seal the terms at
+/// - `GET /reports` a WebSocket feed of the caller's execution reports
+///   (see `feed`)
+///
+/// Every route but `/party-keys`, `/price-scales`, `/instruments` and
+/// `/widths` needs an `Authorization: Bearer <token>` header. The A2A
+/// agent card and JSON-RPC endpoint are served alongside (see `a2a`).
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .merge(a2a::routes())
+        .merge(feed::routes())
+        .route("/orders", get(list_orders).post(submit_order))
+        .route("/orders/{order_id}", get(get_order).delete(cancel_order))
+        .route("/fills", get(list_fills))
+        .route("/batch", get(batch_state))
+        .route("/party-keys", get(party_keys))
+        .route("/price-scales", get(price_scales))
+        .route("/instruments", get(instruments))
+        .route("/widths", get(widths))
+        .with_state(state)
+}
+
+pub async fn serve(listener: TcpListener, state: ApiState) -> std::io::Result<()> {
+    axum::serve(listener, router(state)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{Method, Request, StatusCode},
+    };
+    use chrono::Utc;
+    use ed25519_dalek::SigningKey;
+    use serde::de::DeserializeOwned;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    use crate::book::{OrderBook, OrderEntry, OrderStatus, unix_nanos};
+    use crate::http_api::{ApiError, ApiState, OrderAck, router};
+    use crate::keystore::{Keystore, SignedOrder};
+    use crate::sealed_order::{PartyKey, PartySecret, SealedOrder};
+    use crate::validate::{RejectCode, Validator};
+
+    async fn call<T: DeserializeOwned>(
+        state: &ApiState,
+        method: Method,
+        uri: &str,
+        token: &str,
+        body: &str,
+    ) -> (StatusCode, T) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_owned()))
+            .unwrap();
+        let response = router(state.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_orders_are_scoped_to_their_trader() {
+        let traders = BTreeMap::from([
+            ("alice".to_owned(), "token-a".to_owned()),
+            ("bob".to_owned(), "token-b".to_owned()),
+        ]);
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let mut keystore = Keystore::default();
+        keystore.insert("alice-1", "alice", &key.verifying_key());
+        let keys = [0, 1, 2].map(|_| PartySecret::generate().public_key());
+        let book = OrderBook::new()
+            .with_keystore(keystore)
+            .with_party_keys(keys);
+        let state = ApiState::new(Arc::new(book), &traders);
+        let (status, published): (_, [PartyKey; 3]) =
+            call(&state, Method::GET, "/party-keys", "", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(published, keys);
+
+        let order = serde_json::from_str(
+            r#"{"o_type":false,"symbol":"MSFT","quantity":100,"price":150,"min_execution":50}"#,
+        )
+        .unwrap();
+        let order = SealedOrder::seal(&order, &published, &Validator::default()).unwrap();
+        let nonce = unix_nanos(Utc::now());
+        let order =
+            serde_json::to_string(&SignedOrder::sign(order, "alice-1", nonce, &key)).unwrap();
+
+        let (status, error): (_, ApiError) =
+            call(&state, Method::POST, "/orders", "token-b", &order).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error.code, Some(RejectCode::WrongTrader));
+        let (status, ack): (_, OrderAck) =
+            call(&state, Method::POST, "/orders", "token-a", &order).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, error): (_, ApiError) =
+            call(&state, Method::POST, "/orders", "token-a", &order).await;
+        assert_eq!(error.code, Some(RejectCode::ReusedNonce));
+
+        let uri = format!("/orders/{}", ack.order_id);
+        let (status, entry): (_, OrderEntry) = call(&state, Method::GET, &uri, "token-a", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(entry.order.trader.as_deref(), Some("alice"));
+
+        let (status, _): (_, serde_json::Value) =
+            call(&state, Method::DELETE, &uri, "token-b", "").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _): (_, serde_json::Value) =
+            call(&state, Method::GET, "/fills", "wrong", "").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, ack): (_, OrderAck) = call(&state, Method::DELETE, &uri, "token-a", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ack.status, OrderStatus::Cancelled);
+    }
+}
@@ -0,0 +1,194 @@
+//! Reference data of the tradable instruments.
+
+use anyhow::{Context, Result, ensure};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::price::PriceScale;
+use crate::types::Order;
+use crate::validate::RejectCode;
+
+/// Whether an instrument takes orders.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TradingStatus {
+    #[default]
+    Active,
+    /// Listed, but orders are refused.
+    Halted,
+}
+
+fn one() -> u64 {
+    1
+}
+
+/// One instrument's trading parameters.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Instrument {
+    /// Decimal places of a price unit (see `price`).
+    #[serde(default)]
+    pub scale: u32,
+    #[serde(default)]
+    pub tick_size: Option<Decimal>,
+    /// Quantities must be a whole multiple of this; orders are sealed in
+    /// lots (see `Validator::encode_quantities`).
+    #[serde(default = "one")]
+    pub lot_size: u64,
+    /// Smallest quantity of an order.
+    #[serde(default = "one")]
+    pub min_quantity: u64,
+    #[serde(default)]
+    pub status: TradingStatus,
+}
+
+impl Instrument {
+    pub fn price_scale(&self) -> PriceScale {
+        PriceScale {
+            scale: self.scale,
+            tick_size: self.tick_size,
+        }
+    }
+
+    /// Check the quantity of `order` and that the instrument is trading.
+    /// The price is checked against `price_scale`.
+    pub fn check(&self, order: &Order) -> Result<(), RejectCode> {
+        if self.status == TradingStatus::Halted {
+            return Err(RejectCode::Halted);
+        }
+        if order.quantity < self.min_quantity {
+            return Err(RejectCode::BelowMinimum);
+        }
+        if !order.quantity.is_multiple_of(self.lot_size) {
+            return Err(RejectCode::OddLot);
+        }
+        Ok(())
+    }
+}
+
+/// The instrument master: every tradable symbol and its parameters.
+///
+/// Loaded from a TOML file of the form
+///
+/// ```toml
+/// [instruments.MSFT]
+/// scale = 2
+/// tick_size = "0.01"
+/// lot_size = 100
+/// min_quantity = 100
+/// status = "active"
+/// ```
+///
+/// Everything but the symbol is optional: whole-unit prices without a tick,
+/// lots of one, and active trading.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstrumentMaster {
+    #[serde(default)]
+    pub instruments: BTreeMap<String, Instrument>,
+}
+
+impl InstrumentMaster {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let master: InstrumentMaster = toml::from_str(&text)
+            .with_context(|| format!("Invalid instrument master {}", path.display()))?;
+        for (symbol, instrument) in &master.instruments {
+            ensure!(
+                instrument.price_scale().is_valid(),
+                "Price scale of {} must be at most {} with a positive tick size of whole units",
+                symbol,
+                PriceScale::MAX_SCALE
+            );
+            ensure!(
+                instrument.lot_size > 0 && instrument.min_quantity > 0,
+                "Lot size and minimum quantity of {} must be positive",
+                symbol
+            );
+        }
+        Ok(master)
+    }
+
+    pub fn get(&self, symbol: &str) -> Option<&Instrument> {
+        self.instruments.get(symbol)
+    }
+
+    /// Check that `next` can replace this master under a live book. Resting
+    /// orders were sealed with their instrument's price scale and lot size,
+    /// so those may not change; statuses, minimum quantities and new
+    /// instruments may.
+    pub fn check_reload(&self, next: &InstrumentMaster) -> Result<()> {
+        for (symbol, instrument) in &self.instruments {
+            if let Some(replacement) = next.get(symbol) {
+                ensure!(
+                    replacement.price_scale() == instrument.price_scale()
+                        && replacement.lot_size == instrument.lot_size,
+                    "{} changes its price scale or lot size, which its resting orders are sealed with; restart to change them",
+                    symbol
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Symbols currently taking orders.
+    pub fn active(&self) -> impl Iterator<Item = &String> {
+        self.instruments
+            .iter()
+            .filter(|(_, instrument)| instrument.status == TradingStatus::Active)
+            .map(|(symbol, _)| symbol)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::instruments::{InstrumentMaster, TradingStatus};
+    use crate::types::Order;
+    use crate::validate::RejectCode;
+
+    #[test]
+    fn test_loads_master_and_checks_quantities() {
+        let path = std::env::temp_dir().join(format!("instruments-{}.toml", rand::random::<u64>()));
+        std::fs::write(
+            &path,
+            "[instruments.MSFT]\nscale = 2\ntick_size = \"0.01\"\nlot_size = 100\n\
+             min_quantity = 200\n\n[instruments.GME]\nstatus = \"halted\"\n",
+        )
+        .unwrap();
+        let master = InstrumentMaster::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let msft = master.get("MSFT").unwrap();
+        assert_eq!(msft.price_scale().scale, 2);
+        let mut order = Order {
+            o_type: false,
+            symbol: "MSFT".into(),
+            quantity: 300,
+            price: Decimal::from(150),
+            min_execution: 100,
+            client_key: None,
+        };
+        assert_eq!(msft.check(&order), Ok(()));
+        order.quantity = 250;
+        assert_eq!(msft.check(&order), Err(RejectCode::OddLot));
+        order.quantity = 100;
+        assert_eq!(msft.check(&order), Err(RejectCode::BelowMinimum));
+
+        let gme = master.get("GME").unwrap();
+        assert_eq!((gme.lot_size, gme.status), (1, TradingStatus::Halted));
+        assert_eq!(gme.check(&order), Err(RejectCode::Halted));
+        assert_eq!(master.active().collect::<Vec<_>>(), ["MSFT"]);
+
+        let mut next = master.clone();
+        next.instruments.get_mut("GME").unwrap().status = TradingStatus::Active;
+        next.instruments.get_mut("MSFT").unwrap().min_quantity = 100;
+        assert!(master.check_reload(&next).is_ok());
+        next.instruments.get_mut("MSFT").unwrap().lot_size = 10;
+        assert!(master.check_reload(&next).is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use tracing::{debug, info, warn};
+
+use crate::book::{CancelError, OrderBook, OrderStatus};
+use crate::instruments::Instrument;
+use crate::keystore::{RequestKind, SignedOrder, SignedRequest};
+use crate::price::PriceScale;
+use crate::sealed_order::PartyKey;
+use crate::validate::{BitWidths, RejectCode};
+
+/// Longest request line a client may send.
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// One line sent by a trader on an intake session (newline-delimited JSON).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Submit {
+        /// Echoed back in the reply so clients can pipeline submits.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_order_id: Option<String>,
+        #[serde(flatten)]
+        signed: Box<SignedOrder>,
+    },
+    /// Signed with one of the trader's keys; only their own orders can be
+    /// cancelled or queried.
+    Cancel {
+        #[serde(flatten)]
+        signed: SignedRequest,
+    },
+    Query {
+        #[serde(flatten)]
+        signed: SignedRequest,
+    },
+    /// The keys to seal orders to, in participant order.
+    PartyKeys,
+    /// The price encoding of each symbol.
+    PriceScales,
+    /// The instrument master.
+    Instruments,
+    /// The bit widths to seal the terms at.
+    Widths,
+}
+
+/// The reply to each request line, in request order.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    /// Submit or cancel accepted.
+    Ack {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_order_id: Option<String>,
+        order_id: u64,
+    },
+    /// Request refused; nothing changed.
+    Nack {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_order_id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        order_id: Option<u64>,
+        /// Set when the order itself failed validation.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<RejectCode>,
+        reason: String,
+    },
+    Status {
+        order_id: u64,
+        status: OrderStatus,
+    },
+    PartyKeys {
+        keys: [PartyKey; 3],
+    },
+    /// Symbols missing from `scales` are priced in whole units.
+    PriceScales {
+        scales: BTreeMap<String, PriceScale>,
+    },
+    /// Empty when no instrument master is loaded.
+    Instruments {
+        instruments: BTreeMap<String, Instrument>,
+    },
+    Widths {
+        widths: BitWidths,
+    },
+}
+
+pub fn handle_request(book: &OrderBook, request: Request) -> Response {
+    match request {
+        Request::Submit {
+            client_order_id,
+            signed,
+        } => match book.submit_signed(*signed, None) {
+            Ok(order_id) => {
+                info!("Accepted order {}", order_id);
+                Response::Ack {
+                    client_order_id,
+                    order_id,
+                }
+            }
+            Err(code) => Response::Nack {
+                client_order_id,
+                order_id: None,
+                code: Some(code),
+                reason: code.to_string(),
+            },
+        },
+        Request::Cancel { signed } => {
+            let order_id = signed.order_id;
+            let cancelled = own_order(book, RequestKind::Cancel, &signed).and_then(|_| {
+                book.cancel(order_id)
+                    .map_err(|e| order_nack(order_id, None, e))
+            });
+            match cancelled {
+                Ok(()) => Response::Ack {
+                    client_order_id: None,
+                    order_id,
+                },
+                Err(nack) => nack,
+            }
+        }
+        Request::Query { signed } => match own_order(book, RequestKind::Query, &signed) {
+            Ok(status) => Response::Status {
+                order_id: signed.order_id,
+                status,
+            },
+            Err(nack) => nack,
+        },
+        Request::PartyKeys => match book.party_keys() {
+            Some(keys) => Response::PartyKeys { keys },
+            None => nack("party keys not configured".into()),
+        },
+        Request::PriceScales => Response::PriceScales {
+            scales: book.price_scales(),
+        },
+        Request::Instruments => Response::Instruments {
+            instruments: book.instruments(),
+        },
+        Request::Widths => Response::Widths {
+            widths: book.widths(),
+        },
+    }
+}
+
+/// Authenticate a cancel or query and look up the order, if it is the
+/// signer's. Orders of other traders are reported as unknown, so IDs reveal
+/// nothing.
+fn own_order(
+    book: &OrderBook,
+    kind: RequestKind,
+    signed: &SignedRequest,
+) -> Result<OrderStatus, Response> {
+    let order_id = signed.order_id;
+    let trader = book
+        .authenticate(kind, signed)
+        .map_err(|code| order_nack(order_id, Some(code), code))?;
+    book.get_own(order_id, &trader)
+        .map(|entry| entry.status)
+        .ok_or_else(|| order_nack(order_id, None, CancelError::UnknownOrder))
+}
+
+fn order_nack(order_id: u64, code: Option<RejectCode>, reason: impl ToString) -> Response {
+    Response::Nack {
+        client_order_id: None,
+        order_id: Some(order_id),
+        code,
+        reason: reason.to_string(),
+    }
+}
+
+fn nack(reason: String) -> Response {
+    Response::Nack {
+        client_order_id: None,
+        order_id: None,
+        code: None,
+        reason,
+    }
+}
+
+/// Serve one trader connection until it closes.
+///
+/// Malformed lines get a `nack` and the session carries on; a line over
+/// `MAX_LINE_LENGTH` gets a `nack` and ends the session, since the stream
+/// can no longer be split into requests reliably.
+pub async fn serve_session<S>(io: S, book: Arc<OrderBook>) -> Result<(), LinesCodecError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut lines = Framed::new(io, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+    while let Some(line) = lines.next().await {
+        let response = match line {
+            Ok(line) if line.trim().is_empty() => continue,
+            Ok(line) => match serde_json::from_str(&line) {
+                Ok(request) => handle_request(&book, request),
+                Err(e) => nack(format!("invalid request: {}", e)),
+            },
+            Err(LinesCodecError::MaxLineLengthExceeded) => {
+                let reason = format!("request longer than {} bytes", MAX_LINE_LENGTH);
+                lines
+                    .send(serde_json::to_string(&nack(reason)).unwrap())
+                    .await?;
+                return Err(LinesCodecError::MaxLineLengthExceeded);
+            }
+            Err(e) => return Err(e),
+        };
+        lines
+            .send(serde_json::to_string(&response).unwrap())
+            .await?;
+    }
+    Ok(())
+}
+
+/// Accept trader connections on `listener` forever.
+pub async fn serve(listener: TcpListener, book: Arc<OrderBook>) -> std::io::Result<()> {
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        let book = book.clone();
+        tokio::spawn(async move {
+            debug!("Intake session from {}", peer);
+            if let Err(e) = serve_session(socket, book).await {
+                warn!("Intake session from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use ed25519_dalek::SigningKey;
+    use futures_util::{SinkExt, StreamExt};
+    use rust_decimal::Decimal;
+    use std::sync::Arc;
+    use tokio_util::codec::{Framed, LinesCodec};
+
+    use crate::book::{OrderBook, OrderStatus, unix_nanos};
+    use crate::intake::{Request, Response, serve_session};
+    use crate::keystore::{Keystore, RequestKind, SignedOrder, SignedRequest};
+    use crate::sealed_order::{PartySecret, SealedOrder};
+    use crate::types::Order;
+    use crate::validate::{RejectCode, Validator};
+
+    #[tokio::test]
+    async fn test_session_acks_submit_cancel_and_query() {
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let bob_key = SigningKey::generate(&mut rand::thread_rng());
+        let mut keystore = Keystore::default();
+        keystore.insert("alice-1", "alice", &key.verifying_key());
+        keystore.insert("bob-1", "bob", &bob_key.verifying_key());
+        let keys = [0, 1, 2].map(|_| PartySecret::generate().public_key());
+        let book = OrderBook::new()
+            .with_validator(Validator::new(["MSFT".to_owned()].into()))
+            .with_keystore(keystore)
+            .with_party_keys(keys);
+        let now = unix_nanos(Utc::now());
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(serve_session(server, Arc::new(book)));
+        let mut client = Framed::new(client, LinesCodec::new());
+
+        let submit = |client_order_id: &str, nonce, symbol: &str| {
+            let order = Order {
+                o_type: false,
+                symbol: symbol.into(),
+                quantity: 100,
+                price: Decimal::from(150),
+                min_execution: 0,
+                client_key: None,
+            };
+            let order = SealedOrder::seal(&order, &keys, &Validator::default()).unwrap();
+            serde_json::to_string(&Request::Submit {
+                client_order_id: Some(client_order_id.into()),
+                signed: Box::new(SignedOrder::sign(order, "alice-1", nonce, &key)),
+            })
+            .unwrap()
+        };
+        let request = |kind, key_id: &str, nonce, key: &SigningKey| {
+            let signed = SignedRequest::sign(kind, 1, key_id, nonce, key);
+            serde_json::to_string(&match kind {
+                RequestKind::Cancel => Request::Cancel { signed },
+                RequestKind::Query => Request::Query { signed },
+            })
+            .unwrap()
+        };
+        for line in [
+            r#"{"type":"party_keys"}"#.into(),
+            submit("a", now + 1, "MSFT"),
+            request(RequestKind::Query, "alice-1", now + 2, &key),
+            request(RequestKind::Query, "bob-1", now + 1, &bob_key),
+            request(RequestKind::Cancel, "bob-1", now + 2, &bob_key),
+            request(RequestKind::Cancel, "alice-1", now + 3, &key),
+            request(RequestKind::Cancel, "alice-1", now + 4, &key),
+            request(RequestKind::Cancel, "alice-1", now + 4, &key),
+            r#"{"type":"cancel","order_id":1}"#.into(),
+            r#"{"type":"submit","order":{"o_type":"buy"}}"#.into(),
+            submit("b", now + 5, "AAPL"),
+            submit("c", now + 1, "MSFT"),
+        ] {
+            client.send(line).await.unwrap();
+        }
+        let mut replies = Vec::new();
+        for _ in 0..12 {
+            let line = client.next().await.unwrap().unwrap();
+            replies.push(serde_json::from_str::<Response>(&line).unwrap());
+        }
+
+        assert_eq!(replies.remove(0), Response::PartyKeys { keys });
+        assert_eq!(
+            replies[0],
+            Response::Ack {
+                client_order_id: Some("a".into()),
+                order_id: 1
+            }
+        );
+        assert_eq!(
+            replies[1],
+            Response::Status {
+                order_id: 1,
+                status: OrderStatus::Resting
+            }
+        );
+        // Bob's requests for Alice's order look like requests for no order.
+        for reply in &replies[2..4] {
+            assert!(matches!(
+                reply,
+                Response::Nack { order_id: Some(1), code: None, reason, .. } if reason == "unknown order"
+            ));
+        }
+        assert!(matches!(replies[4], Response::Ack { order_id: 1, .. }));
+        assert!(matches!(
+            replies[5],
+            Response::Nack {
+                order_id: Some(1),
+                code: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            replies[6],
+            Response::Nack {
+                code: Some(RejectCode::ReusedNonce),
+                ..
+            }
+        ));
+        assert!(matches!(replies[7], Response::Nack { order_id: None, .. }));
+        assert!(matches!(replies[8], Response::Nack { order_id: None, .. }));
+        assert!(matches!(
+            replies[9],
+            Response::Nack {
+                code: Some(RejectCode::UnknownSymbol),
+                ..
+            }
+        ));
+        assert!(matches!(
+            replies[10],
+            Response::Nack {
+                code: Some(RejectCode::ReusedNonce),
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::sealed_order::{SealedOrder, put_bytes};
+use crate::validate::RejectCode;
+
+/// Domain separator at the start of every signed order encoding.
+const ORDER_DOMAIN: &[u8] = b"darkpool order v1\0";
+/// Domain separator at the start of every signed cancel or query encoding.
+const REQUEST_DOMAIN: &[u8] = b"darkpool request v1\0";
+
+/// An order as a trader submits it: signed with one of its registered keys.
+///
+/// The signature is Ed25519 over `signing_bytes(key_id, nonce, order)`.
+/// The nonce is the signing time in nanoseconds since the Unix epoch and is
+/// single-use per key; the book only accepts nonces within its nonce window
+/// (see `OrderBook::with_nonce_window`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedOrder {
+    pub key_id: String,
+    pub nonce: u64,
+    pub order: SealedOrder,
+    #[serde(with = "hex::serde")]
+    pub signature: [u8; 64],
+}
+
+/// How an accepted order was authenticated, kept with it for audits.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderAuth {
+    pub key_id: String,
+    pub nonce: u64,
+    #[serde(with = "hex::serde")]
+    pub signature: [u8; 64],
+}
+
+/// The canonical encoding of an order that gets signed.
+///
+/// `ORDER_DOMAIN`, then the key ID as a big-endian `u32` length and UTF-8
+/// bytes, the nonce as a big-endian `u64`, and the order as given by
+/// `SealedOrder::canonical_bytes`. The signature covers the ciphertexts, so
+/// no share can be swapped on the way to its party.
+pub fn signing_bytes(key_id: &str, nonce: u64, order: &SealedOrder) -> Vec<u8> {
+    let mut out = ORDER_DOMAIN.to_vec();
+    put_bytes(&mut out, key_id.as_bytes());
+    out.extend_from_slice(&nonce.to_be_bytes());
+    out.extend_from_slice(&order.canonical_bytes());
+    out
+}
+
+/// What a signed request asks for. Part of the signed bytes, so a signed
+/// query can't be replayed as a cancel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestKind {
+    Cancel,
+    Query,
+}
+
+/// A cancel or query of an order, signed like an order so the book can tell
+/// which trader is asking. Draws its nonce from the same per-key sequence.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedRequest {
+    pub key_id: String,
+    pub nonce: u64,
+    pub order_id: u64,
+    #[serde(with = "hex::serde")]
+    pub signature: [u8; 64],
+}
+
+/// `REQUEST_DOMAIN`, then the key ID as for orders, the nonce, the kind as
+/// one byte (0 cancel, 1 query) and the order ID as a big-endian `u64`.
+pub fn request_signing_bytes(
+    kind: RequestKind,
+    key_id: &str,
+    nonce: u64,
+    order_id: u64,
+) -> Vec<u8> {
+    let mut out = REQUEST_DOMAIN.to_vec();
+    put_bytes(&mut out, key_id.as_bytes());
+    out.extend_from_slice(&nonce.to_be_bytes());
+    out.push(match kind {
+        RequestKind::Cancel => 0,
+        RequestKind::Query => 1,
+    });
+    out.extend_from_slice(&order_id.to_be_bytes());
+    out
+}
+
+impl SignedRequest {
+    /// Client side: sign a `kind` request for `order_id`.
+    pub fn sign(
+        kind: RequestKind,
+        order_id: u64,
+        key_id: &str,
+        nonce: u64,
+        key: &SigningKey,
+    ) -> Self {
+        let signature = key.sign(&request_signing_bytes(kind, key_id, nonce, order_id));
+        SignedRequest {
+            key_id: key_id.to_owned(),
+            nonce,
+            order_id,
+            signature: signature.to_bytes(),
+        }
+    }
+}
+
+impl SignedOrder {
+    /// Client side: sign `order` with the key registered as `key_id`.
+    pub fn sign(order: SealedOrder, key_id: &str, nonce: u64, key: &SigningKey) -> Self {
+        let signature = key.sign(&signing_bytes(key_id, nonce, &order));
+        SignedOrder {
+            key_id: key_id.to_owned(),
+            nonce,
+            order,
+            signature: signature.to_bytes(),
+        }
+    }
+
+    pub fn auth(&self) -> OrderAuth {
+        OrderAuth {
+            key_id: self.key_id.clone(),
+            nonce: self.nonce,
+            signature: self.signature,
+        }
+    }
+}
+
+/// One registered order-signing key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TraderKey {
+    pub trader: String,
+    #[serde(with = "hex::serde")]
+    pub public_key: [u8; 32],
+}
+
+/// The registered traders' order-signing keys, by key ID.
+///
+/// Loaded from a TOML file of the form
+///
+/// ```toml
+/// [keys.alice-1]
+/// trader = "alice"
+/// public_key = "<64 hex digits>"
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keystore {
+    #[serde(default)]
+    pub keys: BTreeMap<String, TraderKey>,
+}
+
+impl Keystore {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let keystore: Keystore = toml::from_str(&text)
+            .with_context(|| format!("Invalid keystore {}", path.display()))?;
+        for (key_id, key) in &keystore.keys {
+            VerifyingKey::from_bytes(&key.public_key)
+                .with_context(|| format!("Invalid public key for {}", key_id))?;
+        }
+        Ok(keystore)
+    }
+
+    pub fn insert(&mut self, key_id: &str, trader: &str, key: &VerifyingKey) {
+        self.keys.insert(
+            key_id.to_owned(),
+            TraderKey {
+                trader: trader.to_owned(),
+                public_key: key.to_bytes(),
+            },
+        );
+    }
+
+    /// Check the signature of `signed` and return the trader it belongs to.
+    /// Nonce reuse is tracked by the caller (see `OrderBook::submit_signed`).
+    pub fn verify(&self, signed: &SignedOrder) -> Result<&str, RejectCode> {
+        let message = signing_bytes(&signed.key_id, signed.nonce, &signed.order);
+        self.verify_bytes(&signed.key_id, &message, &signed.signature)
+    }
+
+    /// As `verify`, for a signed cancel or query.
+    pub fn verify_request(
+        &self,
+        kind: RequestKind,
+        signed: &SignedRequest,
+    ) -> Result<&str, RejectCode> {
+        let message = request_signing_bytes(kind, &signed.key_id, signed.nonce, signed.order_id);
+        self.verify_bytes(&signed.key_id, &message, &signed.signature)
+    }
+
+    fn verify_bytes(
+        &self,
+        key_id: &str,
+        message: &[u8],
+        signature: &[u8; 64],
+    ) -> Result<&str, RejectCode> {
+        let key = self.keys.get(key_id).ok_or(RejectCode::UnknownKey)?;
+        let public_key =
+            VerifyingKey::from_bytes(&key.public_key).map_err(|_| RejectCode::UnknownKey)?;
+        public_key
+            .verify(message, &Signature::from_bytes(signature))
+            .map_err(|_| RejectCode::BadSignature)?;
+        Ok(&key.trader)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use rust_decimal::Decimal;
+
+    use crate::keystore::{Keystore, SignedOrder};
+    use crate::sealed_order::{PartySecret, SealedOrder};
+    use crate::types::Order;
+    use crate::validate::{RejectCode, Validator};
+
+    #[test]
+    fn test_signature_binds_every_field() {
+        let key = SigningKey::generate(&mut rand::thread_rng());
+        let mut keystore = Keystore::default();
+        keystore.insert("alice-1", "alice", &key.verifying_key());
+        let order = Order {
+            o_type: false,
+            symbol: "MSFT".into(),
+            quantity: 100,
+            price: Decimal::from(150),
+            min_execution: 50,
+            client_key: None,
+        };
+        let keys = [0, 1, 2].map(|_| PartySecret::generate().public_key());
+        let order = SealedOrder::seal(&order, &keys, &Validator::default()).unwrap();
+        let signed = SignedOrder::sign(order, "alice-1", 7, &key);
+        assert_eq!(keystore.verify(&signed), Ok("alice"));
+
+        let mut tampered = signed.clone();
+        tampered.order.shares[1].ciphertext[0] ^= 1;
+        assert_eq!(keystore.verify(&tampered), Err(RejectCode::BadSignature));
+        let mut tampered = signed.clone();
+        tampered.nonce = 8;
+        assert_eq!(keystore.verify(&tampered), Err(RejectCode::BadSignature));
+        let mut tampered = signed;
+        tampered.key_id = "bob-1".into();
+        assert_eq!(keystore.verify(&tampered), Err(RejectCode::UnknownKey));
+    }
+}
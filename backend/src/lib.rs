@@ -1,4 +1,30 @@
+pub mod a2a;
+pub mod aggregate;
+pub mod batching;
+pub mod book;
+pub mod capture;
+pub mod config;
+pub mod ecies;
+pub mod evidence;
+pub mod fault_relay;
+pub mod feed;
+pub mod fill;
+pub mod http_api;
+pub mod instruments;
+pub mod intake;
+pub mod keystore;
+pub mod limits;
+pub mod party;
+pub mod price;
 pub mod relay;
+pub mod report;
+pub mod scheduler;
+pub mod sealed_order;
+pub mod session;
+pub mod socket_relay;
+pub mod supervisor;
+pub mod timeout_relay;
 pub mod types;
 pub mod v1;
+pub mod validate;
 pub mod websocket_relay;
@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::socket_relay::DEFAULT_MAX_FRAME_LENGTH;
+
+/// Per-connection resource limits enforced by the relay server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayLimits {
+    /// Largest frame a client may send, in bytes.
+    pub max_frame_size: usize,
+    /// Sustained frames per second a client may send; bursts of up to one
+    /// second's worth are allowed. `None` disables the limit.
+    pub max_messages_per_sec: Option<u32>,
+    /// Sustained bytes per second a client may send, with the same burst
+    /// allowance. `None` disables the limit.
+    pub max_bytes_per_sec: Option<u64>,
+    /// Frames buffered for a client that is slow to read. While the queue
+    /// is full the relay stops reading from the client and the broker.
+    pub outbound_queue: usize,
+    /// How long a frame may wait for room in a full outbound queue before
+    /// the client is dropped, in milliseconds.
+    pub outbound_timeout_ms: u64,
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        RelayLimits {
+            max_frame_size: DEFAULT_MAX_FRAME_LENGTH,
+            max_messages_per_sec: None,
+            max_bytes_per_sec: None,
+            outbound_queue: 1024,
+            outbound_timeout_ms: 10_000,
+        }
+    }
+}
+
+/// Why the relay server closed a connection.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Disconnect {
+    ClientClosed,
+    BrokerClosed,
+    FrameTooLarge { size: usize, limit: usize },
+    MessageRateExceeded { limit: u32 },
+    ByteRateExceeded { limit: u64 },
+    OutboundQueueFull { queue: usize, timeout_ms: u64 },
+}
+
+impl Disconnect {
+    /// Whether the connection was cut for misbehaving rather than closed normally.
+    pub fn is_violation(&self) -> bool {
+        !matches!(self, Disconnect::ClientClosed | Disconnect::BrokerClosed)
+    }
+}
+
+impl std::fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Disconnect::ClientClosed => write!(f, "client closed the connection"),
+            Disconnect::BrokerClosed => write!(f, "broker closed the connection"),
+            Disconnect::FrameTooLarge { size, limit } => {
+                write!(
+                    f,
+                    "frame of {} bytes exceeds the {} byte limit",
+                    size, limit
+                )
+            }
+            Disconnect::MessageRateExceeded { limit } => {
+                write!(f, "exceeded {} messages per second", limit)
+            }
+            Disconnect::ByteRateExceeded { limit } => {
+                write!(f, "exceeded {} bytes per second", limit)
+            }
+            Disconnect::OutboundQueueFull { queue, timeout_ms } => {
+                write!(
+                    f,
+                    "outbound queue of {} frames stayed full for {} ms",
+                    queue, timeout_ms
+                )
+            }
+        }
+    }
+}
+
+/// Token bucket refilled continuously at `rate` tokens per second, holding
+/// at most `rate` tokens.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take `cost` tokens if available.
+    pub fn try_take(&mut self, cost: u64) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+        if self.tokens >= cost as f64 {
+            self.tokens -= cost as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Inbound checks for one connection.
+#[derive(Debug)]
+pub struct InboundPolicer {
+    max_frame_size: usize,
+    messages: Option<(u32, TokenBucket)>,
+    bytes: Option<(u64, TokenBucket)>,
+}
+
+impl InboundPolicer {
+    pub fn new(limits: &RelayLimits) -> Self {
+        InboundPolicer {
+            max_frame_size: limits.max_frame_size,
+            messages: limits
+                .max_messages_per_sec
+                .map(|limit| (limit, TokenBucket::new(limit as u64))),
+            bytes: limits
+                .max_bytes_per_sec
+                .map(|limit| (limit, TokenBucket::new(limit))),
+        }
+    }
+
+    /// Admit a frame of `size` bytes or say why the connection must go.
+    pub fn admit(&mut self, size: usize) -> Result<(), Disconnect> {
+        if size > self.max_frame_size {
+            return Err(Disconnect::FrameTooLarge {
+                size,
+                limit: self.max_frame_size,
+            });
+        }
+        if let Some((limit, bucket)) = &mut self.messages
+            && !bucket.try_take(1)
+        {
+            return Err(Disconnect::MessageRateExceeded { limit: *limit });
+        }
+        if let Some((limit, bucket)) = &mut self.bytes
+            && !bucket.try_take(size as u64)
+        {
+            return Err(Disconnect::ByteRateExceeded { limit: *limit });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::limits::{Disconnect, InboundPolicer, RelayLimits};
+
+    #[test]
+    fn test_oversized_frame_is_rejected() {
+        let mut policer = InboundPolicer::new(&RelayLimits {
+            max_frame_size: 16,
+            ..Default::default()
+        });
+
+        assert_eq!(policer.admit(16), Ok(()));
+        assert_eq!(
+            policer.admit(17),
+            Err(Disconnect::FrameTooLarge {
+                size: 17,
+                limit: 16
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_message_rate_allows_one_second_burst() {
+        let mut policer = InboundPolicer::new(&RelayLimits {
+            max_messages_per_sec: Some(10),
+            ..Default::default()
+        });
+
+        for _ in 0..10 {
+            assert_eq!(policer.admit(1), Ok(()));
+        }
+        assert_eq!(
+            policer.admit(1),
+            Err(Disconnect::MessageRateExceeded { limit: 10 })
+        );
+
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert_eq!(policer.admit(1), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_byte_rate_counts_frame_sizes() {
+        let mut policer = InboundPolicer::new(&RelayLimits {
+            max_bytes_per_sec: Some(1000),
+            ..Default::default()
+        });
+
+        assert_eq!(policer.admit(600), Ok(()));
+        assert_eq!(
+            policer.admit(600),
+            Err(Disconnect::ByteRateExceeded { limit: 1000 })
+        );
+    }
+}
@@ -1,217 +1,493 @@
-use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use anyhow::{Context, Result};
+use clap::Parser;
+use ed25519_dalek::VerifyingKey;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
+use tracing::{error, info, warn};
 
 use backend::{
-    types::OrderShare,
-    v1::{setup_handle_orders_sock_v1, test_handle_orders_sock_v1},
-    websocket_relay::WebSocketRelay,
-};
-use chrono::Utc;
-use sl_compute::{
-    transport::setup::ProtocolParticipant,
-    types::{ArithmeticShare, FieldElement},
+    aggregate::{BatchResult, Collector},
+    book::OrderBook,
+    config::{BackendConfig, LogLevel, PartyLink, client_tls_config, init_logging, load},
+    evidence::IncidentReport,
+    fill::append_sealed_fills,
+    http_api::{self, ApiState},
+    instruments::InstrumentMaster,
+    intake,
+    keystore::Keystore,
+    party::PartyClient,
+    price::PriceScale,
+    report::{ReportFormat, ReportWriter},
+    scheduler::Scheduler,
+    supervisor::supervise,
+    types::BookOrder,
+    validate::{FRACTION_BITS, RejectCode, VALUE_BITS, Validator},
 };
 use tokio::task::JoinSet;
+use tokio_rustls::rustls::ClientConfig;
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Order {
-    o_type: bool, // false for buy, true for sell
-    symbol: String,
-    quantity: u64,
-    price: u64,
-    min_execution: u64,
+/// Dark pool matching backend: collects orders and matches them with three
+/// MPC party processes (`bin/party.rs`) talking through the relay server.
+///
+/// Settings come from the optional TOML `--config` file; flags given on the
+/// command line override it.
+#[derive(Parser)]
+struct Args {
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Where traders submit orders.
+    #[arg(long)]
+    order_listen: Option<SocketAddr>,
+    /// Serve the HTTP API on this address.
+    #[arg(long)]
+    api_listen: Option<SocketAddr>,
+    /// Registered traders' order-signing keys (TOML).
+    #[arg(long)]
+    keystore: Option<PathBuf>,
+    /// Instrument master (TOML).
+    #[arg(long)]
+    instruments: Option<PathBuf>,
+    /// Extra CA certificate (PEM) trusted for `tls://` party control URLs.
+    #[arg(long)]
+    party_ca_cert: Option<PathBuf>,
+    #[arg(long)]
+    trades_file: Option<PathBuf>,
+    #[arg(long, value_enum)]
+    report_format: Option<ReportFormat>,
+    /// Where the sealed fill shares for the counterparties are appended.
+    #[arg(long)]
+    fills_file: Option<PathBuf>,
+    #[arg(long, value_enum)]
+    log_level: Option<LogLevel>,
 }
 
-async fn handle_server() {
+impl Args {
+    fn into_config(self) -> Result<BackendConfig> {
+        let mut config: BackendConfig = load(self.config.as_deref())?;
+        if let Some(order_listen) = self.order_listen {
+            config.order_listen = order_listen;
+        }
+        if self.api_listen.is_some() {
+            config.api_listen = self.api_listen;
+        }
+        if self.keystore.is_some() {
+            config.keystore = self.keystore;
+        }
+        if self.instruments.is_some() {
+            config.instruments = self.instruments;
+        }
+        if self.party_ca_cert.is_some() {
+            config.party_ca_cert = self.party_ca_cert;
+        }
+        if let Some(trades_file) = self.trades_file {
+            config.trades_file = trades_file;
+        }
+        if let Some(report_format) = self.report_format {
+            config.report_format = report_format;
+        }
+        if let Some(fills_file) = self.fills_file {
+            config.fills_file = fills_file;
+        }
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+        Ok(config)
+    }
+}
+
+/// Connect to a party process, waiting for it to come up.
+async fn connect_party(
+    party: usize,
+    link: &PartyLink,
+    tls: Option<Arc<ClientConfig>>,
+) -> Result<PartyClient> {
+    anyhow::ensure!(
+        !link.token.is_empty(),
+        "No control token configured for party {}",
+        party
+    );
+    loop {
+        match PartyClient::connect(link, tls.clone()).await {
+            Ok(client) => {
+                let key = hex::encode(client.hello().evidence_key);
+                if let Some(pinned) = &link.evidence_key {
+                    anyhow::ensure!(
+                        pinned.eq_ignore_ascii_case(&key),
+                        "Party {} signs evidence with key {}, not the configured {}",
+                        party,
+                        key,
+                        pinned
+                    );
+                }
+                info!("Connected to party {} at {}", party, link.control_url);
+                return Ok(client);
+            }
+            Err(e) => {
+                warn!("Party {}: {:#}; retrying in 2s…", party, e);
+                tokio::time::sleep(Duration::from_secs(2)).await;
+            }
+        }
+    }
+}
+
+async fn handle_server(config: BackendConfig) -> Result<()> {
+    let tls = config
+        .party_ca_cert
+        .as_deref()
+        .map(client_tls_config)
+        .transpose()?;
+
     // Start TCP server to accept orders
-    let listener = TcpListener::bind("127.0.0.1:8080")
+    let listener = TcpListener::bind(config.order_listen)
         .await
-        .expect("Failed to bind");
-    println!("Order server listening on 127.0.0.1:8080");
+        .with_context(|| format!("Failed to bind {}", config.order_listen))?;
+    info!("Order server listening on {}", config.order_listen);
+    if !config.widths.is_valid() {
+        anyhow::bail!(
+            "Bit widths must be between {} and {} bits",
+            FRACTION_BITS + 1,
+            FRACTION_BITS + VALUE_BITS
+        );
+    }
+    if let Some((symbol, _)) = config.price_scales.iter().find(|(_, s)| !s.is_valid()) {
+        anyhow::bail!(
+            "Price scale of {} must be at most {} with a positive tick size of whole units",
+            symbol,
+            PriceScale::MAX_SCALE
+        );
+    }
+    let mut instruments = match &config.instruments {
+        Some(path) => {
+            if !config.symbols.is_empty() || !config.price_scales.is_empty() {
+                anyhow::bail!(
+                    "Symbols and price scales come from the instrument master {}; remove them from the config",
+                    path.display()
+                );
+            }
+            let master = InstrumentMaster::load(path)?;
+            info!(
+                "Loaded {} instruments from {}",
+                master.instruments.len(),
+                path.display()
+            );
+            Some(master)
+        }
+        None => None,
+    };
+    if config.symbols.is_empty() && instruments.is_none() {
+        warn!("No symbols configured; orders for any symbol are accepted");
+    }
+    let keystore = match &config.keystore {
+        Some(path) => Keystore::load(path)?,
+        None => {
+            warn!("No keystore configured; every order will be refused");
+            Keystore::default()
+        }
+    };
+    // The parties hold their own secret keys; the backend learns only the
+    // public keys, from their greetings.
+    let clients = [
+        connect_party(0, &config.parties[0], tls.clone()).await?,
+        connect_party(1, &config.parties[1], tls.clone()).await?,
+        connect_party(2, &config.parties[2], tls.clone()).await?,
+    ];
+    let evidence_keys = [
+        clients[0].hello().evidence_key()?,
+        clients[1].hello().evidence_key()?,
+        clients[2].hello().evidence_key()?,
+    ];
+    let mut validator = Validator::new(config.symbols.clone())
+        .with_widths(config.widths)
+        .with_price_scales(config.price_scales.clone());
+    if let Some(master) = &instruments {
+        validator = validator.with_instruments(master);
+    }
+    let mut book = OrderBook::new()
+        .with_validator(validator)
+        .with_keystore(keystore)
+        .with_party_keys(clients.each_ref().map(|client| client.hello().key))
+        .with_nonce_window(Duration::from_secs(config.nonce_window_secs));
+    if let Some(ttl) = config.order_ttl_secs {
+        book = book.with_order_ttl(Duration::from_secs(ttl));
+    }
+    let book = Arc::new(book);
+    let mut servers = JoinSet::new();
+    servers.spawn(intake::serve(listener, book.clone()));
 
-    let mut orders = VecDeque::new();
-    let timeout = Duration::from_secs(5); // 5 seconds timeout for no new orders
+    if let Some(api_listen) = config.api_listen {
+        let listener = TcpListener::bind(api_listen)
+            .await
+            .with_context(|| format!("Failed to bind {}", api_listen))?;
+        info!("HTTP API listening on http://{}", api_listen);
+        let state = ApiState::new(book.clone(), &config.api_tokens);
+        servers.spawn(http_api::serve(listener, state));
+    }
 
+    if !config.batching.is_armed() {
+        anyhow::bail!("No batching trigger configured; orders would never be matched");
+    }
+    let mut scheduler = Scheduler::new(&config, tls, clients)?;
+    // Sessions of the configured symbols are set up ahead of the first
+    // batch, so that the parties can preprocess for it.
+    match &instruments {
+        Some(master) => {
+            scheduler = scheduler.with_instruments(master);
+            scheduler.establish(master.active()).await;
+        }
+        None => scheduler.establish(&config.symbols).await,
+    }
+    let mut hangup = Hangup::listen()?;
     loop {
-        tokio::select! {
-            // Accept new connections
-            Ok((mut socket, _)) = listener.accept() => {
-                let mut buffer = Vec::new();
-                let mut buf = [0; 1024];
-
-                loop {
-                    match socket.read(&mut buf).await {
-                        Ok(0) => break, // Connection closed
-                        Ok(n) => buffer.extend_from_slice(&buf[..n]),
-                        Err(_) => break,
-                    }
+        let batch_id = hex::encode(rand::random::<[u8; 8]>());
+        let next = book.next_batch(&config.batching, &batch_id);
+        tokio::pin!(next);
+        let mut stocked = false;
+        let batch = loop {
+            tokio::select! {
+                batch = &mut next => break batch,
+                res = servers.join_next() => {
+                    return Err(anyhow::anyhow!("Order server stopped: {:?}", res));
                 }
-
-                if !buffer.is_empty() {
-                    match serde_json::from_slice::<Order>(&buffer) {
-                        Ok(order) => {
-                            println!("Received order: {:?}", order);
-                            orders.push_back((
-                                order.o_type,
-                                order.symbol,
-                                order.quantity,
-                                order.price,
-                                order.min_execution,
-                                Utc::now()
-                            ));
+                () = scheduler.preprocess(|stock| book.set_preprocessed(stock)), if !stocked => {
+                    stocked = true;
+                }
+                () = hangup.recv() => {
+                    match (&config.instruments, &mut instruments) {
+                        (Some(path), Some(master)) => {
+                            if let Err(e) = reload_instruments(path, master, &book, &scheduler).await {
+                                error!("Keeping the instrument master: {:#}", e);
+                            }
+                            stocked = false;
                         }
-                        Err(e) => println!("Failed to parse order: {}", e),
+                        _ => warn!("SIGHUP ignored: no instrument master configured"),
                     }
                 }
             }
-            // Timeout after no new orders
-            _ = tokio::time::sleep(timeout) => {
-                if !orders.is_empty() {
-                    println!("No new orders received for {} seconds, processing {} orders", timeout.as_secs(), orders.len());
-                    break;
+        };
+        if let Some(current) = book.batch_state().current {
+            info!(
+                "Batch {} cut by {:?} trigger, processing {} orders",
+                batch_id,
+                current.trigger,
+                batch.len()
+            );
+        }
+        // Every attempt runs, under a fresh instance, the orders of the
+        // symbols no attempt has agreed on yet.
+        let remaining = RefCell::new(batch.clone());
+        let agreed = RefCell::new(BatchResult::default());
+        let result = supervise(
+            &config.retry,
+            |instance| {
+                let orders = remaining.borrow().clone();
+                let (config, scheduler, evidence_keys, batch_id) =
+                    (&config, &scheduler, &evidence_keys, &batch_id);
+                let (remaining, agreed) = (&remaining, &agreed);
+                async move {
+                    let outcome = run_batch(
+                        config,
+                        scheduler,
+                        evidence_keys,
+                        batch_id,
+                        instance,
+                        &orders,
+                    )
+                    .await?;
+                    let undecryptable = &outcome.agreed.undecryptable;
+                    remaining.borrow_mut().retain(|order| {
+                        outcome.failed.contains_key(&order.order.symbol)
+                            && !undecryptable.contains(&order.id)
+                    });
+                    agreed.borrow_mut().merge(outcome.agreed);
+                    match outcome
+                        .failed
+                        .into_values()
+                        .reduce(|all, e| all + "; " + &e)
+                    {
+                        Some(failed) => Err(anyhow::anyhow!(failed)),
+                        None => Ok(()),
+                    }
+                }
+            },
+            |attempt| book.record_attempt(attempt),
+        )
+        .await;
+        let agreed = agreed.into_inner();
+        match write_batch(&config, &agreed) {
+            Ok(()) => {
+                let error = result.err().map(|e| format!("{:#}", e));
+                if let Some(e) = &error {
+                    error!(
+                        "Batch {} failed on some symbols, whose orders rest again: {}",
+                        batch_id, e
+                    );
                 }
+                book.reject(&agreed.undecryptable, RejectCode::BadShare);
+                book.reject(&agreed.out_of_range, RejectCode::OutOfRange);
+                book.settle(batch, &agreed.trades, &agreed.sealed, error);
+            }
+            Err(e) => {
+                error!("Batch {} failed: {:#}", batch_id, e);
+                book.fail(batch, format!("{:#}", e));
             }
         }
+        book.set_preprocessed(scheduler.stock().await);
     }
+}
 
-    // Convert received orders to OrderShare format
-    let mut orders_p1 = Vec::new();
-    let mut orders_p2 = Vec::new();
-    let mut orders_p3 = Vec::new();
-
-    for (typ, symb, quan, pric, mine, ts) in orders {
-        let quan1 = ArithmeticShare::from_constant(&FieldElement::from(quan), 0);
-        let quan2 = ArithmeticShare::from_constant(&FieldElement::from(quan), 1);
-        let quan3 = ArithmeticShare::from_constant(&FieldElement::from(quan), 2);
-
-        let pric1 = ArithmeticShare::from_constant(&FieldElement::from(pric), 0);
-        let pric2 = ArithmeticShare::from_constant(&FieldElement::from(pric), 1);
-        let pric3 = ArithmeticShare::from_constant(&FieldElement::from(pric), 2);
-
-        let mine1 = ArithmeticShare::from_constant(&FieldElement::from(mine), 0);
-        let mine2 = ArithmeticShare::from_constant(&FieldElement::from(mine), 1);
-        let mine3 = ArithmeticShare::from_constant(&FieldElement::from(mine), 2);
-
-        orders_p1.push(OrderShare {
-            o_type: typ,
-            symbol: symb.clone(),
-            quantity: quan1,
-            price: pric1,
-            min_execution: mine1,
-            timestamp: ts,
-        });
-
-        orders_p2.push(OrderShare {
-            o_type: typ,
-            symbol: symb.clone(),
-            quantity: quan2,
-            price: pric2,
-            min_execution: mine2,
-            timestamp: ts,
-        });
-
-        orders_p3.push(OrderShare {
-            o_type: typ,
-            symbol: symb,
-            quantity: quan3,
-            price: pric3,
-            min_execution: mine3,
-            timestamp: ts,
-        });
-    }
-
-    let shares = [[orders_p1], [orders_p2], [orders_p3]];
-    // 1) Build all (setup, seed) tuples
-    let parties = setup_handle_orders_sock_v1(None, &shares);
-    let mut join = JoinSet::new();
-    println!("Launching {} parties…", parties.len());
-
-    // 2) Spawn one Tokio task per party (each uses WebSocketRelay)
-    for (setup, seed, shares) in parties {
-        join.spawn(async move {
-            let ws_url = "ws://localhost:9007";
-            // 3) Reconnect loop until WebSocketRelay is ready
-            let ws_relay = loop {
-                match WebSocketRelay::connect(ws_url).await {
-                    Ok(r) => break r,
-                    Err(_) => {
-                        eprintln!(
-                            "Party {}: WS connect failed; retrying in 2s…",
-                            setup.participant_index(),
-                        );
-                        tokio::time::sleep(Duration::from_secs(2)).await;
-                    }
-                }
-            };
-            // 4) Run the MPC protocol over ws_relay
-            test_handle_orders_sock_v1(setup, seed, shares[0].clone(), ws_relay).await
-        });
-    }
-
-    let mut results = vec![];
-    while let Some(fini) = join.join_next().await {
-        let fini = fini.unwrap();
-
-        if let Err(ref err) = fini {
-            println!("error {}", err);
-        }
-
-        let res = fini.unwrap();
-        results.push(res);
-    }
-    results.sort_by_key(|r| r.0);
-    let ress: Vec<Vec<(OrderShare, OrderShare)>> = results.into_iter().map(|r| r.1).collect();
-
-    assert_eq!(ress.len(), 3);
-    // Open file for writing matches
-    let mut file = std::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open("order_matches.txt")
-        .expect("Failed to open order_matches.txt");
-
-    for (b, s) in &ress[0] {
-        let output = format!(
-            "Match Details:\n\
-            Buy Order:\n\
-            - Symbol: {}\n\
-            - Quantity: {:?}\n\
-            - Price: {:?}\n\
-            - Min Execution: {:?}\n\
-            - Timestamp: {}\n\
-            Sell Order:\n\
-            - Symbol: {}\n\
-            - Quantity: {:?}\n\
-            - Price: {:?}\n\
-            - Min Execution: {:?}\n\
-            - Timestamp: {}\n\
-            --------------------\n",
-            b.symbol,
-            b.quantity,
-            b.price,
-            b.min_execution,
-            b.timestamp,
-            s.symbol,
-            s.quantity,
-            s.price,
-            s.min_execution,
-            s.timestamp
+/// The reload trigger: SIGHUP where there is one, nothing elsewhere.
+struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    fn listen() -> Result<Self> {
+        Ok(Hangup {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .context("Failed to listen for SIGHUP")?,
+        })
+    }
+
+    /// Wait for the next SIGHUP; never returns without signals.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if self.signal.recv().await.is_some() {
+            return;
+        }
+        std::future::pending().await
+    }
+}
+
+/// Reload the instrument master from `path` into the book and the scheduler,
+/// unless it changes how resting orders were sealed.
+async fn reload_instruments(
+    path: &std::path::Path,
+    master: &mut InstrumentMaster,
+    book: &OrderBook,
+    scheduler: &Scheduler,
+) -> Result<()> {
+    let next = InstrumentMaster::load(path)?;
+    master.check_reload(&next)?;
+    book.reload_instruments(&next);
+    scheduler.reload(&next).await;
+    info!(
+        "Reloaded {} instruments from {}",
+        next.instruments.len(),
+        path.display()
+    );
+    *master = next;
+    Ok(())
+}
+
+/// What one run of a batch settled: the symbols all three parties agree
+/// on, and why each other symbol failed.
+struct BatchOutcome {
+    agreed: BatchResult,
+    failed: BTreeMap<String, String>,
+}
+
+/// Run the MPC protocol once on a batch, every symbol in its own sessions
+/// (see `scheduler`), and merge the results of the symbols the parties
+/// agree on.
+async fn run_batch(
+    config: &BackendConfig,
+    scheduler: &Scheduler,
+    evidence_keys: &[VerifyingKey; 3],
+    batch_id: &str,
+    instance: [u8; 32],
+    orders: &[BookOrder],
+) -> Result<BatchOutcome> {
+    let outputs = scheduler.run(batch_id, instance, orders).await?;
+    for incident in outputs.incidents {
+        let report = IncidentReport::assess(
+            batch_id,
+            incident.instance,
+            incident.accounts,
+            evidence_keys,
+        );
+        error!(
+            "Batch {} failed verification on {}; suspected parties: {:?}; report in {}",
+            batch_id,
+            incident.symbol,
+            report.suspects,
+            config.incidents_file.display()
+        );
+        report
+            .append(&config.incidents_file)
+            .with_context(|| format!("Failed to write {}", config.incidents_file.display()))?;
+    }
+    let mut outcome = BatchOutcome {
+        agreed: BatchResult {
+            undecryptable: outputs.undecryptable,
+            ..Default::default()
+        },
+        failed: BTreeMap::new(),
+    };
+    for (symbol, results) in outputs.books {
+        // Collect every party's outcome, failed or not, before judging the book.
+        let mut collector = Collector::default();
+        for (party, result) in results.into_iter().enumerate() {
+            if let Err(e) = &result {
+                error!("Party {} failed: {}", party, e);
+            }
+            collector.record(party, result);
+        }
+        match collector.finish() {
+            Ok(result) => outcome.agreed.merge(result),
+            Err(e) => {
+                // The parties disagree on this symbol; start it over on new sessions.
+                scheduler.reset(&symbol).await;
+                outcome
+                    .failed
+                    .insert(symbol.clone(), format!("{}: {}", symbol, e));
+            }
+        }
+    }
+    info!("All parties have finished.");
+    Ok(outcome)
+}
+
+/// Write out the trades and sealed fills of a batch the parties agree on.
+fn write_batch(config: &BackendConfig, result: &BatchResult) -> Result<()> {
+    for id in &result.undecryptable {
+        warn!("Order {} has a share some party cannot decrypt", id);
+    }
+    for id in &result.out_of_range {
+        warn!("Order {} failed the range check", id);
+    }
+    for trade in &result.trades {
+        info!(
+            "Trade {}: {} (buy order {}, sell order {})",
+            trade.trade_id, trade.symbol, trade.buy_order_id, trade.sell_order_id
         );
-        println!("output buy:{} sell: {}", b.symbol, s.symbol);
-        std::io::Write::write_all(&mut file, output.as_bytes())
-            .expect("Failed to write to order_matches.txt");
-    }
-    let finish_msg = "All parties have finished.\n";
-    println!("{}", finish_msg.trim());
-    std::io::Write::write_all(&mut file, finish_msg.as_bytes())
-        .expect("Failed to write to order_matches.txt");
+    }
+    ReportWriter::open(&config.trades_file, config.report_format)
+        .and_then(|mut report| report.write(&result.trades))
+        .with_context(|| format!("Failed to write {}", config.trades_file.display()))?;
+    append_sealed_fills(&config.fills_file, &result.sealed)
+        .with_context(|| format!("Failed to write {}", config.fills_file.display()))?;
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() {
-    handle_server().await;
+    let config = match Args::parse().into_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(2);
+        }
+    };
+    init_logging(config.log_level);
+    if let Err(e) = handle_server(config).await {
+        error!("{:#}", e);
+        std::process::exit(1);
+    }
 }
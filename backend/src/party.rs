@@ -0,0 +1,684 @@
+//! Party processes and the control connections the backend drives them over.
+//!
+//! Each MPC party runs as a process of its own (`bin/party.rs`) that holds
+//! only its own `PartySecret`. It decrypts its share of every order itself,
+//! runs its side of each symbol's session (see `session`) and hands back
+//! only what may leave it: the public trade records and its fill shares
+//! sealed to the counterparties. The backend never holds a party secret, so
+//! neither it nor the relay can reconstruct an order.
+//!
+//! A control connection carries newline-delimited JSON, one `ControlReply`
+//! per `ControlRequest`, in order. Every connection opens with a `Hello`
+//! carrying the party's control token, answered with the party's index and
+//! public keys. The backend keeps one connection per party for share checks
+//! and one per symbol session: a session is established over its own
+//! connection and ends with it.
+
+use anyhow::{Context, Result};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::{ClientConfig, pki_types::ServerName};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_util::codec::{Framed, LinesCodec};
+use tokio_util::either::Either;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::aggregate::PartyResult;
+use crate::capture::CaptureWriter;
+use crate::config::PartyLink;
+use crate::evidence::{Evidence, Witness};
+use crate::fill::seal_fills;
+use crate::report::trades_from_fills;
+use crate::scheduler::room_url;
+use crate::sealed_order::{PartyKey, PartySecret, open_order_shares};
+use crate::session::{EpochError, PartyOptions, PartyRunner};
+use crate::timeout_relay::DEFAULT_RECV_TIMEOUT;
+use crate::types::BookOrder;
+use crate::validate::BitWidths;
+
+/// Longest control message accepted, enough for the orders of a large batch.
+const MAX_CONTROL_LINE: usize = 256 * 1024 * 1024;
+
+/// What the backend asks of a party.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// First request of every connection.
+    Hello { token: String },
+    /// Set up this connection's session for `symbol`, under `instance`.
+    Establish {
+        symbol: String,
+        #[serde(with = "hex::serde")]
+        instance: [u8; 32],
+        rekey_epochs: u64,
+        widths: BitWidths,
+        min_quantity: u64,
+    },
+    /// Preprocess more AND triples in this connection's session.
+    Preprocess { triples: usize },
+    /// Match a book of `batch_id` in this connection's session.
+    Epoch {
+        #[serde(with = "hex::serde")]
+        instance: [u8; 32],
+        batch_id: String,
+        orders: Vec<BookOrder>,
+    },
+    /// Which of `orders` the party cannot decrypt its share of.
+    Check { orders: Vec<BookOrder> },
+}
+
+/// Who a party is, as it answers a `Hello`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartyHello {
+    pub party: usize,
+    /// The key traders seal the party's order shares to.
+    pub key: PartyKey,
+    /// The key the party signs incident evidence with.
+    #[serde(with = "hex::serde")]
+    pub evidence_key: [u8; 32],
+}
+
+impl PartyHello {
+    pub fn evidence_key(&self) -> Result<VerifyingKey> {
+        VerifyingKey::from_bytes(&self.evidence_key)
+            .with_context(|| format!("Party {} sent an invalid evidence key", self.party))
+    }
+}
+
+/// A party's answer to a `ControlRequest`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlReply {
+    Hello(PartyHello),
+    /// The request is done; `stock` AND triples are left in the session.
+    Done {
+        stock: usize,
+    },
+    Epoch {
+        result: PartyResult,
+        stock: usize,
+    },
+    Checked {
+        undecryptable: Vec<u64>,
+    },
+    /// The request failed; a failed session stays down.
+    Failed {
+        error: String,
+        evidence: Option<Evidence>,
+    },
+}
+
+impl ControlReply {
+    fn failed(error: impl ToString) -> Self {
+        ControlReply::Failed {
+            error: error.to_string(),
+            evidence: None,
+        }
+    }
+}
+
+impl From<EpochError> for ControlReply {
+    fn from(e: EpochError) -> Self {
+        match e {
+            EpochError::Aborted(abort) => {
+                let error = abort.to_string();
+                ControlReply::Failed {
+                    error,
+                    evidence: abort.evidence,
+                }
+            }
+            EpochError::Failed { error, evidence } => ControlReply::Failed {
+                error,
+                evidence: evidence.map(|evidence| *evidence),
+            },
+            EpochError::Ended => ControlReply::failed(e),
+        }
+    }
+}
+
+/// Party side: serves the backend's control connections.
+pub struct PartyHost {
+    party: usize,
+    secret: PartySecret,
+    evidence_key: SigningKey,
+    token: String,
+    relay_url: String,
+    tls: Option<Arc<ClientConfig>>,
+    capture: Option<CaptureWriter>,
+    recv_timeout: Duration,
+    /// Sessions spawned so far, for unique capture connection IDs.
+    spawned: AtomicU64,
+}
+
+impl PartyHost {
+    /// A host for `party`, accepting connections that present `token` and
+    /// running sessions over the relay at `relay_url`. Evidence of failed
+    /// epochs is signed with `evidence_key`.
+    pub fn new(
+        party: usize,
+        secret: PartySecret,
+        evidence_key: SigningKey,
+        token: String,
+        relay_url: String,
+    ) -> Result<Self> {
+        anyhow::ensure!(party < 3, "Party index must be 0, 1 or 2");
+        anyhow::ensure!(!token.is_empty(), "A control token is required");
+        room_url(&relay_url, "")?;
+        Ok(PartyHost {
+            party,
+            secret,
+            evidence_key,
+            token,
+            relay_url,
+            tls: None,
+            capture: None,
+            recv_timeout: DEFAULT_RECV_TIMEOUT,
+            spawned: AtomicU64::new(0),
+        })
+    }
+
+    /// Client TLS settings for a `wss://` relay.
+    pub fn with_tls(mut self, tls: Arc<ClientConfig>) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Record the relay traffic of every session.
+    pub fn with_capture(mut self, capture: CaptureWriter) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Fail a session step after waiting this long for a frame instead of
+    /// `DEFAULT_RECV_TIMEOUT`.
+    pub fn with_recv_timeout(mut self, timeout: Duration) -> Self {
+        self.recv_timeout = timeout;
+        self
+    }
+
+    pub fn hello(&self) -> PartyHello {
+        PartyHello {
+            party: self.party,
+            key: self.secret.public_key(),
+            evidence_key: self.evidence_key.verifying_key().to_bytes(),
+        }
+    }
+
+    /// Serve control connections from `listener`, over TLS when `tls` is set.
+    pub async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        tls: Option<TlsAcceptor>,
+    ) -> Result<()> {
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            stream.set_nodelay(true)?;
+            info!("Party {}: control connection from {}", self.party, addr);
+            let host = self.clone();
+            let tls = tls.clone();
+            tokio::spawn(async move {
+                let served = match tls {
+                    Some(tls) => match tls.accept(stream).await {
+                        Ok(stream) => host.serve_control(stream).await,
+                        Err(e) => Err(e).context("TLS handshake failed"),
+                    },
+                    None => host.serve_control(stream).await,
+                };
+                if let Err(e) = served {
+                    warn!(
+                        "Party {}: control connection from {}: {:#}",
+                        host.party, addr, e
+                    );
+                }
+            });
+        }
+    }
+
+    /// Serve one control connection. Its session, if it establishes one,
+    /// ends when the connection does.
+    pub async fn serve_control<T>(&self, io: T) -> Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut lines = Framed::new(io, LinesCodec::new_with_max_length(MAX_CONTROL_LINE));
+        match read_message(&mut lines).await? {
+            Some(ControlRequest::Hello { token }) if token == self.token => {}
+            _ => {
+                write_message(&mut lines, &ControlReply::failed("unauthorized")).await?;
+                anyhow::bail!("refused a connection without the control token");
+            }
+        }
+        write_message(&mut lines, &ControlReply::Hello(self.hello())).await?;
+
+        let mut runner: Option<PartyRunner> = None;
+        while let Some(request) = read_message(&mut lines).await? {
+            let reply = match (request, &runner) {
+                (ControlRequest::Check { orders }, _) => ControlReply::Checked {
+                    undecryptable: match open_order_shares(self.party, &self.secret, &orders) {
+                        Ok(_) => vec![],
+                        Err(e) => e.orders,
+                    },
+                },
+                (establish @ ControlRequest::Establish { .. }, None) => {
+                    runner = Some(self.spawn(establish));
+                    ControlReply::Done { stock: 0 }
+                }
+                (ControlRequest::Preprocess { triples }, Some(runner)) => {
+                    match runner.preprocess(triples).await {
+                        Ok(()) => ControlReply::Done {
+                            stock: runner.stock(),
+                        },
+                        Err(e) => e.into(),
+                    }
+                }
+                (
+                    ControlRequest::Epoch {
+                        instance,
+                        batch_id,
+                        orders,
+                    },
+                    Some(runner),
+                ) => self.run_epoch(runner, instance, &batch_id, orders).await,
+                (ControlRequest::Hello { .. }, _) => ControlReply::failed("already greeted"),
+                (ControlRequest::Establish { .. }, Some(_)) => {
+                    ControlReply::failed("session already established")
+                }
+                (_, None) => ControlReply::failed("no session established"),
+            };
+            write_message(&mut lines, &reply).await?;
+        }
+        Ok(())
+    }
+
+    fn spawn(&self, establish: ControlRequest) -> PartyRunner {
+        let ControlRequest::Establish {
+            symbol,
+            instance,
+            rekey_epochs,
+            widths,
+            min_quantity,
+        } = establish
+        else {
+            unreachable!("only called with Establish");
+        };
+        info!(
+            "Party {}: establishing the {} session under instance {}",
+            self.party,
+            symbol,
+            hex::encode(instance)
+        );
+        PartyRunner::spawn(PartyOptions {
+            party: self.party,
+            conn: self.spawned.fetch_add(1, Ordering::Relaxed),
+            instance,
+            url: room_url(&self.relay_url, &symbol)
+                .expect("relay URL is checked by PartyHost::new"),
+            tls: self.tls.clone(),
+            capture: self.capture.clone(),
+            witness: Witness::new(instance, &self.evidence_key),
+            recv_timeout: self.recv_timeout,
+            rekey_epochs,
+            preprocessing_seed: self.secret.preprocessing_seed(&instance),
+            widths,
+            min_quantity,
+        })
+    }
+
+    /// Open this party's share of every order, match them, and seal the fill
+    /// shares to the counterparties; only the public trade records leave the
+    /// party in the clear.
+    async fn run_epoch(
+        &self,
+        runner: &PartyRunner,
+        instance: [u8; 32],
+        batch_id: &str,
+        orders: Vec<BookOrder>,
+    ) -> ControlReply {
+        let share = match open_order_shares(self.party, &self.secret, &orders) {
+            Ok(share) => share,
+            Err(e) => return ControlReply::failed(e),
+        };
+        match runner.run_epoch(instance, orders, share).await {
+            Ok(output) => ControlReply::Epoch {
+                result: PartyResult {
+                    trades: trades_from_fills(batch_id, &output.fills, chrono::Utc::now()),
+                    sealed: seal_fills(output.party, batch_id, &output.fills),
+                    out_of_range: output.out_of_range,
+                },
+                stock: runner.stock(),
+            },
+            Err(e) => e.into(),
+        }
+    }
+}
+
+async fn read_message<T, M>(lines: &mut Framed<T, LinesCodec>) -> Result<Option<M>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    M: for<'de> Deserialize<'de>,
+{
+    match lines.next().await {
+        Some(line) => {
+            let line = line.context("Failed to read control message")?;
+            Ok(Some(
+                serde_json::from_str(&line).context("Malformed control message")?,
+            ))
+        }
+        None => Ok(None),
+    }
+}
+
+async fn write_message<T, M>(lines: &mut Framed<T, LinesCodec>, message: &M) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    M: Serialize,
+{
+    lines
+        .send(serde_json::to_string(message)?)
+        .await
+        .context("Failed to send control message")
+}
+
+type ControlStream = Either<TcpStream, tokio_rustls::client::TlsStream<TcpStream>>;
+
+/// Backend side: a control connection to one party.
+pub struct PartyClient {
+    lines: Framed<ControlStream, LinesCodec>,
+    hello: PartyHello,
+}
+
+impl PartyClient {
+    /// Connect to the party at `link` and greet it. `tls` is required for
+    /// `tls://` control URLs.
+    pub async fn connect(link: &PartyLink, tls: Option<Arc<ClientConfig>>) -> Result<Self> {
+        let url = Url::parse(&link.control_url)
+            .with_context(|| format!("Invalid control URL {}", link.control_url))?;
+        let (Some(host), Some(port)) = (url.host_str(), url.port()) else {
+            anyhow::bail!("Control URL {} needs a host and port", link.control_url);
+        };
+        let stream = TcpStream::connect((host, port))
+            .await
+            .with_context(|| format!("Failed to connect to {}", link.control_url))?;
+        stream.set_nodelay(true)?;
+        let stream = match url.scheme() {
+            "tcp" => Either::Left(stream),
+            "tls" => {
+                let tls = tls.context("tls:// control URLs need party_ca_cert")?;
+                let server_name = ServerName::try_from(host.to_owned())?;
+                let stream = TlsConnector::from(tls)
+                    .connect(server_name, stream)
+                    .await
+                    .with_context(|| format!("TLS handshake with {} failed", link.control_url))?;
+                Either::Right(stream)
+            }
+            scheme => anyhow::bail!("Unsupported control URL scheme {}", scheme),
+        };
+        let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(MAX_CONTROL_LINE));
+        let hello = ControlRequest::Hello {
+            token: link.token.clone(),
+        };
+        write_message(&mut lines, &hello).await?;
+        match read_message(&mut lines).await? {
+            Some(ControlReply::Hello(hello)) => Ok(PartyClient { lines, hello }),
+            Some(ControlReply::Failed { error, .. }) => {
+                anyhow::bail!("{} refused the connection: {}", link.control_url, error)
+            }
+            _ => anyhow::bail!("{} did not greet back", link.control_url),
+        }
+    }
+
+    pub fn hello(&self) -> &PartyHello {
+        &self.hello
+    }
+
+    pub async fn call(&mut self, request: &ControlRequest) -> Result<ControlReply> {
+        write_message(&mut self.lines, request).await?;
+        read_message(&mut self.lines)
+            .await?
+            .context("Party closed the control connection")
+    }
+
+    /// Which of `orders` the party cannot decrypt its share of.
+    pub async fn check(&mut self, orders: &[BookOrder]) -> Result<Vec<u64>> {
+        let request = ControlRequest::Check {
+            orders: orders.to_vec(),
+        };
+        match self.call(&request).await? {
+            ControlReply::Checked { undecryptable } => Ok(undecryptable),
+            ControlReply::Failed { error, .. } => anyhow::bail!("{}", error),
+            reply => anyhow::bail!("Unexpected reply {:?}", reply),
+        }
+    }
+}
+
+type Call = (
+    ControlRequest,
+    oneshot::Sender<Result<ControlReply, EpochError>>,
+);
+
+/// Backend handle of one party's session in the party's process, with the
+/// interface of `PartyRunner`. Dropping it closes the session's connection,
+/// which ends the session.
+pub struct RemoteRunner {
+    party: usize,
+    calls: mpsc::UnboundedSender<Call>,
+    /// AND triples the party has in stock.
+    stock: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for RemoteRunner {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl RemoteRunner {
+    /// Connect to the party at `link`, check that it is still the party that
+    /// greeted the backend with `expected`, and establish a session there.
+    pub fn spawn(
+        link: PartyLink,
+        tls: Option<Arc<ClientConfig>>,
+        expected: PartyHello,
+        establish: ControlRequest,
+    ) -> Self {
+        let party = expected.party;
+        let (calls, mut requests) = mpsc::unbounded_channel::<Call>();
+        let stock = Arc::new(AtomicUsize::new(0));
+        let gauge = stock.clone();
+        let task = tokio::spawn(async move {
+            // Reconnect loop until the party process is up
+            let mut client = loop {
+                match PartyClient::connect(&link, tls.clone()).await {
+                    Ok(client) => break client,
+                    Err(e) => {
+                        warn!("Party {}: {:#}; retrying in 2s…", party, e);
+                        tokio::time::sleep(Duration::from_secs(2)).await;
+                    }
+                }
+            };
+            let established = if *client.hello() != expected {
+                Err(anyhow::anyhow!("party {} presented other keys", party))
+            } else {
+                client.call(&establish).await
+            };
+            if let Err(e) = established.and_then(reply_result) {
+                warn!("Party {}: session not established: {:#}", party, e);
+                if let Some((_, reply)) = requests.recv().await {
+                    let _ = reply.send(Err(EpochError::Failed {
+                        error: format!("{:#}", e),
+                        evidence: None,
+                    }));
+                }
+                return;
+            }
+            while let Some((request, reply)) = requests.recv().await {
+                let result = match client.call(&request).await {
+                    Ok(ControlReply::Failed { error, evidence }) => Err(EpochError::Failed {
+                        error,
+                        evidence: evidence.map(Box::new),
+                    }),
+                    Ok(result) => Ok(result),
+                    Err(e) => {
+                        warn!("Party {}: {:#}", party, e);
+                        let _ = reply.send(Err(EpochError::Ended));
+                        return;
+                    }
+                };
+                if let Ok(ControlReply::Done { stock } | ControlReply::Epoch { stock, .. }) =
+                    &result
+                {
+                    gauge.store(*stock, Ordering::Relaxed);
+                }
+                let _ = reply.send(result);
+            }
+        });
+        RemoteRunner {
+            party,
+            calls,
+            stock,
+            task,
+        }
+    }
+
+    /// AND triples left after the party's last finished request.
+    pub fn stock(&self) -> usize {
+        self.stock.load(Ordering::Relaxed)
+    }
+
+    fn call(
+        &self,
+        request: ControlRequest,
+    ) -> impl Future<Output = Result<ControlReply, EpochError>> + 'static {
+        let (reply, result) = oneshot::channel();
+        let sent = self.calls.send((request, reply));
+        async move {
+            sent.map_err(|_| EpochError::Ended)?;
+            result.await.map_err(|_| EpochError::Ended)?
+        }
+    }
+
+    /// Preprocess `triples` more AND triples. Queued before this returns,
+    /// like `PartyRunner::preprocess`.
+    pub fn preprocess(
+        &self,
+        triples: usize,
+    ) -> impl Future<Output = Result<(), EpochError>> + 'static {
+        let call = self.call(ControlRequest::Preprocess { triples });
+        async move {
+            call.await?;
+            Ok(())
+        }
+    }
+
+    /// Match one book of `batch_id`. Queued before this returns.
+    pub fn run_epoch(
+        &self,
+        instance: [u8; 32],
+        batch_id: String,
+        orders: Vec<BookOrder>,
+    ) -> impl Future<Output = Result<PartyResult, EpochError>> + 'static {
+        let party = self.party;
+        let call = self.call(ControlRequest::Epoch {
+            instance,
+            batch_id,
+            orders,
+        });
+        async move {
+            match call.await? {
+                ControlReply::Epoch { result, .. } => Ok(result),
+                reply => Err(EpochError::Failed {
+                    error: format!("party {} sent an unexpected reply {:?}", party, reply),
+                    evidence: None,
+                }),
+            }
+        }
+    }
+}
+
+fn reply_result(reply: ControlReply) -> Result<ControlReply> {
+    match reply {
+        ControlReply::Failed { error, .. } => Err(anyhow::anyhow!(error)),
+        reply => Ok(reply),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use ed25519_dalek::SigningKey;
+    use rust_decimal::Decimal;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    use crate::config::PartyLink;
+    use crate::party::{PartyClient, PartyHost};
+    use crate::sealed_order::{PartySecret, SealedOrder};
+    use crate::types::{BookOrder, Order};
+    use crate::validate::Validator;
+
+    #[tokio::test]
+    async fn test_party_checks_only_its_own_share() {
+        let secret = PartySecret::generate();
+        let key = secret.public_key();
+        let evidence_key = SigningKey::from_bytes(&rand::random());
+        let evidence = evidence_key.verifying_key();
+        let host = PartyHost::new(
+            1,
+            secret,
+            evidence_key,
+            "s3cret".into(),
+            "ws://localhost:9".into(),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(Arc::new(host).serve(listener, None));
+
+        let link = |token: &str| PartyLink {
+            control_url: format!("tcp://{}", addr),
+            token: token.to_owned(),
+            evidence_key: None,
+        };
+        assert!(PartyClient::connect(&link("guess"), None).await.is_err());
+        let mut client = PartyClient::connect(&link("s3cret"), None).await.unwrap();
+        assert_eq!(client.hello().party, 1);
+        assert_eq!(client.hello().key, key);
+        assert_eq!(client.hello().evidence_key().unwrap(), evidence);
+
+        let others = [0, 1, 2].map(|_| PartySecret::generate().public_key());
+        let orders: Vec<BookOrder> = [(1, [others[0], key, others[2]]), (2, others)]
+            .into_iter()
+            .map(|(id, keys)| BookOrder {
+                id,
+                trader: None,
+                order: SealedOrder::seal(
+                    &Order {
+                        o_type: false,
+                        symbol: "MSFT".into(),
+                        quantity: 10,
+                        price: Decimal::from(20),
+                        min_execution: 1,
+                        client_key: None,
+                    },
+                    &keys,
+                    &Validator::default(),
+                )
+                .unwrap(),
+                timestamp: Utc::now(),
+                auth: None,
+            })
+            .collect();
+        assert_eq!(client.check(&orders).await.unwrap(), vec![2]);
+    }
+}
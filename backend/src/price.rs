@@ -0,0 +1,117 @@
+//! Decimal prices and their integer encoding.
+//!
+//! Orders carry prices as `Decimal`s. Before sealing, a price is turned into
+//! a whole number of ticks of its symbol's scale (12.35 is 247 with a tick
+//! of 0.05, 1235 with the default tick of one unit at scale 2), which is
+//! what gets fixed-point encoded and secret-shared like any other value.
+//! Every integer is an on-tick price, so the parties' range check on the
+//! shares holds client-supplied prices to the tick too. Fill prices are
+//! decoded back with the same scale.
+
+use rust_decimal::Decimal;
+use rust_decimal::prelude::ToPrimitive;
+use serde::{Deserialize, Serialize};
+
+use crate::validate::RejectCode;
+
+/// How one symbol's prices map to integer units.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PriceScale {
+    /// Decimal places of a unit.
+    pub scale: u32,
+    /// Prices must be a whole multiple of this; one unit when unset.
+    #[serde(default)]
+    pub tick_size: Option<Decimal>,
+}
+
+impl PriceScale {
+    /// Largest scale whose unit count still fits a `u64` multiplier.
+    pub const MAX_SCALE: u32 = 18;
+
+    /// Whether units fit the encoding and the tick size is a positive whole
+    /// number of units.
+    pub fn is_valid(&self) -> bool {
+        self.scale <= Self::MAX_SCALE
+            && self
+                .tick_size
+                .is_none_or(|tick| tick > Decimal::ZERO && self.units(tick).is_some())
+    }
+
+    /// `price` in whole units, if it is one.
+    fn units(&self, price: Decimal) -> Option<Decimal> {
+        let units = price.checked_mul(Decimal::from(10u64.pow(self.scale)))?;
+        units.fract().is_zero().then_some(units)
+    }
+
+    /// The tick prices are counted in: `tick_size`, or one unit.
+    pub fn tick(&self) -> Decimal {
+        self.tick_size
+            .unwrap_or(Decimal::from_i128_with_scale(1, self.scale))
+    }
+
+    /// The integer ticks an order carries for `price`.
+    pub fn encode(&self, price: Decimal) -> Result<u64, RejectCode> {
+        if price.is_zero() {
+            return Err(RejectCode::ZeroPrice);
+        }
+        if price.is_sign_negative() {
+            return Err(RejectCode::OutOfRange);
+        }
+        let tick = self.tick();
+        if !(price % tick).is_zero() {
+            return Err(RejectCode::OffTick);
+        }
+        price
+            .checked_div(tick)
+            .and_then(|ticks| ticks.to_u64())
+            .ok_or(RejectCode::OutOfRange)
+    }
+
+    /// The price of `ticks` integer ticks.
+    pub fn decode(&self, ticks: u64) -> Decimal {
+        Decimal::from(ticks) * self.tick()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::price::PriceScale;
+    use crate::validate::RejectCode;
+
+    #[test]
+    fn test_encodes_on_tick_prices_and_decodes_them_back() {
+        let dec = |s: &str| s.parse::<Decimal>().unwrap();
+        let cents = PriceScale {
+            scale: 2,
+            tick_size: Some(dec("0.05")),
+        };
+        assert!(cents.is_valid());
+        assert_eq!(cents.encode(dec("12.35")), Ok(247));
+        assert_eq!(cents.encode(dec("12.3")), Ok(246));
+        assert_eq!(cents.decode(247), dec("12.35"));
+        assert_eq!(cents.encode(dec("12.34")), Err(RejectCode::OffTick));
+        assert_eq!(cents.encode(dec("0")), Err(RejectCode::ZeroPrice));
+        assert_eq!(cents.encode(dec("-1")), Err(RejectCode::OutOfRange));
+
+        let units = PriceScale {
+            tick_size: None,
+            ..cents
+        };
+        assert_eq!(units.encode(dec("12.34")), Ok(1234));
+        assert_eq!(units.encode(dec("12.345")), Err(RejectCode::OffTick));
+
+        let whole = PriceScale::default();
+        assert_eq!(whole.encode(dec("150")), Ok(150));
+        assert_eq!(whole.encode(dec("150.5")), Err(RejectCode::OffTick));
+
+        let sub_unit_tick = PriceScale {
+            scale: 2,
+            tick_size: Some(dec("0.001")),
+        };
+        assert!(!sub_unit_tick.is_valid());
+        assert!(!PriceScale { scale: 19, ..cents }.is_valid());
+    }
+}
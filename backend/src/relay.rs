@@ -1,96 +1,677 @@
-// src/bin/relay_server.rs
 use anyhow::Result;
-use futures_util::{SinkExt, StreamExt};
-use sl_mpc_mate::coord::{MessageRelayService, SimpleMessageRelay, simple::MessageRelay};
+use bytes::Bytes;
+use futures_util::{Sink, SinkExt, Stream, StreamExt, future};
+use sl_mpc_mate::coord::{MessageRelayService, Relay, SimpleMessageRelay, simple::MessageRelay};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{accept_async, tungstenite::Message as WsMessage};
-/// For each new WebSocket client, spawn a task that bridges:
-///   (1) WebSocket ⇄ raw `Vec<u8>` frames,
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::{self, error::SendTimeoutError};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::{
+        Error as WsError, Message as WsMessage,
+        handshake::server::{Request, Response},
+        protocol::WebSocketConfig,
+    },
+};
+use tracing::{debug, info, warn};
+
+use crate::capture::{CaptureWriter, RecordingRelay};
+use crate::fault_relay::{FaultProfile, FaultyRelay};
+use crate::limits::{Disconnect, InboundPolicer, RelayLimits};
+use crate::socket_relay::framed_with_limit;
+
+/// Behaviour shared by every connection a relay server accepts.
+#[derive(Clone, Default)]
+pub struct ServerOptions {
+    /// Terminate TLS on the WebSocket and TCP listeners.
+    pub tls: Option<TlsAcceptor>,
+    /// Inject faults into every frame a client sends through the broker.
+    /// Each connection derives its own seed from the profile's seed and the
+    /// order in which it was accepted.
+    pub faults: Option<FaultProfile>,
+    /// Record every frame of every connection, tagged with the connection ID.
+    pub capture: Option<CaptureWriter>,
+    pub limits: RelayLimits,
+}
+
+/// For each new client connection, spawn a task that bridges:
+///   (1) the transport's framing ⇄ raw `Vec<u8>` frames,
 ///   (2) `MessageRelay` handle ⇄ central `SimpleMessageRelay`.
 ///
 /// We run a single select! loop so we do not need to clone `MessageRelay`.
-async fn spawn_connection(
-    mut relay_handle: MessageRelay,
-    ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>,
-) {
-    let (mut ws_sink, mut ws_stream) = ws_stream.split();
-    loop {
-        tokio::select! {
-            // 1) Read from WebSocket → forward to relay_handle.send(...)
-            ws_msg = ws_stream.next() => {
-                match ws_msg {
-                    Some(Ok(WsMessage::Binary(frame))) => {
-                        // Convert Bytes → Vec<u8>
-                        let bytes: Vec<u8> = frame.into();
-                        if relay_handle.send(bytes).await.is_err() {
-                            break;
+/// Frames bound for the client go through a bounded queue drained by a
+/// separate writer. Once a client falls `outbound_queue` frames behind, the
+/// loop waits for room before reading anything else, which holds back the
+/// client's own frames too; only a client that makes no room within
+/// `outbound_timeout_ms` is dropped, so nothing accumulates in the broker.
+async fn spawn_connection<R, Tx, Rx>(
+    mut relay_handle: R,
+    limits: &RelayLimits,
+    mut conn_sink: Tx,
+    mut conn_stream: Rx,
+) -> Disconnect
+where
+    R: Relay,
+    Tx: Sink<Vec<u8>> + Unpin,
+    Rx: Stream<Item = Vec<u8>> + Unpin,
+{
+    let (out_tx, mut out_rx) = mpsc::channel::<Vec<u8>>(limits.outbound_queue.max(1));
+    let mut policer = InboundPolicer::new(limits);
+    let outbound_timeout = Duration::from_millis(limits.outbound_timeout_ms);
+
+    let pump = async {
+        loop {
+            tokio::select! {
+                // 1) Read from the client → forward to relay_handle.send(...)
+                frame = conn_stream.next() => {
+                    match frame {
+                        Some(bytes) => {
+                            if let Err(reason) = policer.admit(bytes.len()) {
+                                return reason;
+                            }
+                            if relay_handle.send(bytes).await.is_err() {
+                                return Disconnect::BrokerClosed;
+                            }
+                        }
+                        None => {
+                            return Disconnect::ClientClosed;
                         }
-                    }
-                    Some(Ok(WsMessage::Close(_))) | None => {
-                        break;
-                    }
-                    _ => {
-                        // Ignore non‐binary frames
                     }
                 }
-            }
-            // 2) Read from relay_handle.next() → send back to WebSocket
-            maybe_frame = relay_handle.next() => {
-                match maybe_frame {
-                    Some(frame_bytes) => {
-                        if ws_sink
-                            .send(WsMessage::Binary(frame_bytes.into()))
-                            .await
-                            .is_err()
-                        {
-                            break;
+                // 2) Read from relay_handle.next() → queue for the client
+                maybe_frame = relay_handle.next() => {
+                    match maybe_frame {
+                        Some(frame_bytes) => {
+                            // Wait for a slow client; shed one that stalls.
+                            match out_tx.send_timeout(frame_bytes, outbound_timeout).await {
+                                Ok(()) => {}
+                                Err(SendTimeoutError::Timeout(_)) => {
+                                    return Disconnect::OutboundQueueFull {
+                                        queue: limits.outbound_queue,
+                                        timeout_ms: limits.outbound_timeout_ms,
+                                    };
+                                }
+                                Err(SendTimeoutError::Closed(_)) => {
+                                    return Disconnect::ClientClosed;
+                                }
+                            }
+                        }
+                        None => {
+                            return Disconnect::BrokerClosed;
                         }
-                    }
-                    None => {
-                        break;
                     }
                 }
             }
         }
+    };
+
+    let writer = async {
+        while let Some(frame_bytes) = out_rx.recv().await {
+            if conn_sink.send(frame_bytes).await.is_err() {
+                break;
+            }
+        }
+        Disconnect::ClientClosed
+    };
+
+    tokio::select! {
+        reason = pump => reason,
+        reason = writer => reason,
     }
 }
-/// Accept incoming TCP connections, upgrade them to WebSocket, and call `spawn_connection`.
-pub async fn run_server(listen_addr: SocketAddr) -> Result<()> {
-    let listener = TcpListener::bind(listen_addr).await?;
-    println!("Relay server listening on ws://{}", listen_addr);
-    // One global SimpleMessageRelay (shared among all connections)
-    let simple_relay = Arc::new(SimpleMessageRelay::new());
-    loop {
-        let (tcp_stream, _) = listener.accept().await?;
-        let client_addr = tcp_stream
-            .peer_addr()
-            .unwrap_or_else(|_| "unknown".parse().unwrap());
-        // Upgrade to WebSocket
-        match accept_async(tcp_stream).await {
-            Ok(ws_stream) => {
-                println!("New WebSocket connection from {}", client_addr);
-                // Call the async trait method explicitly to get a future:
-                let relay_handle = MessageRelayService::connect(&*simple_relay).await.unwrap(); // unwrap the Option<MessageRelay>
-                // Spawn the broker task (single task with select!).
-                tokio::spawn(spawn_connection(relay_handle, ws_stream));
+
+/// Apply the server-wide `options` to a freshly accepted connection and run
+/// its bridge until either side goes away.
+async fn bridge<Tx, Rx>(
+    relay_handle: MessageRelay,
+    options: &ServerOptions,
+    conn_id: u64,
+    conn_sink: Tx,
+    conn_stream: Rx,
+) where
+    Tx: Sink<Vec<u8>> + Unpin,
+    Rx: Stream<Item = Vec<u8>> + Unpin,
+{
+    let reason = match options.capture.clone() {
+        Some(capture) => {
+            let relay = RecordingRelay::new(relay_handle, capture, conn_id);
+            bridge_with_faults(relay, options, conn_id, conn_sink, conn_stream).await
+        }
+        None => bridge_with_faults(relay_handle, options, conn_id, conn_sink, conn_stream).await,
+    };
+    if reason.is_violation() {
+        warn!("Dropping connection {}: {}", conn_id, reason);
+    } else {
+        debug!("Connection {} closed: {}", conn_id, reason);
+    }
+}
+
+async fn bridge_with_faults<R, Tx, Rx>(
+    relay: R,
+    options: &ServerOptions,
+    conn_id: u64,
+    conn_sink: Tx,
+    conn_stream: Rx,
+) -> Disconnect
+where
+    R: Relay + Send + 'static,
+    Tx: Sink<Vec<u8>> + Unpin,
+    Rx: Stream<Item = Vec<u8>> + Unpin,
+{
+    match &options.faults {
+        Some(profile) => {
+            let relay = FaultyRelay::new(relay, profile.for_connection(conn_id));
+            spawn_connection(relay, &options.limits, conn_sink, conn_stream).await
+        }
+        None => spawn_connection(relay, &options.limits, conn_sink, conn_stream).await,
+    }
+}
+
+/// Bridge a WebSocket: binary messages carry frames, a Close ends the stream
+/// and every other message type is ignored. The path of the request names
+/// the room the client joins.
+async fn spawn_websocket_connection<S>(server: &RelayServer, conn_id: u64, stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Upgrade to WebSocket
+    let options = &server.options;
+    let ws_config = WebSocketConfig::default()
+        .max_message_size(Some(options.limits.max_frame_size))
+        .max_frame_size(Some(options.limits.max_frame_size));
+    let mut room = String::new();
+    // The error type is tungstenite's; this callback never fails.
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| {
+        room = request.uri().path().trim_start_matches('/').to_owned();
+        Ok(response)
+    };
+    let ws_stream = match accept_hdr_async_with_config(stream, callback, Some(ws_config)).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            warn!("WebSocket upgrade error on connection {}: {}", conn_id, e);
+            return;
+        }
+    };
+    if !room.is_empty() {
+        info!("Connection {} joined room {}", conn_id, room);
+    }
+    let (ws_sink, ws_stream) = ws_stream.split();
+    let conn_sink = ws_sink
+        .with(|frame: Vec<u8>| future::ready(Ok::<_, WsError>(WsMessage::Binary(frame.into()))));
+    let conn_stream = ws_stream
+        .take_while(|msg| future::ready(!matches!(msg, Ok(WsMessage::Close(_)))))
+        .filter_map(move |msg| {
+            future::ready(match msg {
+                // Convert Bytes → Vec<u8>
+                Ok(WsMessage::Binary(frame)) => Some(frame.into()),
+                Err(e) => {
+                    warn!("WebSocket error on connection {}: {}", conn_id, e);
+                    None
+                }
+                _ => None,
+            })
+        });
+    let (relay_handle, _member) = server.join(&room).await;
+    bridge(relay_handle, options, conn_id, conn_sink, conn_stream).await
+}
+
+/// Bridge a length-prefixed stream socket (see `socket_relay::framed_with_limit`).
+async fn spawn_framed_connection<T>(
+    relay_handle: MessageRelay,
+    options: &ServerOptions,
+    conn_id: u64,
+    io: T,
+) where
+    T: AsyncRead + AsyncWrite,
+{
+    let (frame_sink, frame_stream) = framed_with_limit(io, options.limits.max_frame_size).split();
+    let conn_sink = frame_sink
+        .with(|frame: Vec<u8>| future::ready(Ok::<_, std::io::Error>(Bytes::from(frame))));
+    let conn_stream = frame_stream
+        .take_while(move |frame| {
+            // The codec refuses oversized frames before buffering them.
+            if let Err(e) = frame {
+                warn!("Dropping connection {}: {}", conn_id, e);
             }
-            Err(e) => {
-                eprintln!("WebSocket upgrade error from {}: {}", client_addr, e);
+            future::ready(frame.is_ok())
+        })
+        .filter_map(|frame| future::ready(frame.ok().map(|bytes| bytes.to_vec())));
+    bridge(relay_handle, options, conn_id, conn_sink, conn_stream).await
+}
+
+/// A `SimpleMessageRelay` broker and the clients connected to it.
+struct Room {
+    broker: Arc<SimpleMessageRelay>,
+    connections: usize,
+}
+
+/// A client's place in a room; the room is dropped with its last member.
+struct Member<'a> {
+    server: &'a RelayServer,
+    room: String,
+}
+
+impl Drop for Member<'_> {
+    fn drop(&mut self) {
+        let mut rooms = self.server.rooms.lock().unwrap();
+        if let Some(room) = rooms.get_mut(&self.room) {
+            room.connections -= 1;
+            if room.connections == 0 {
+                rooms.remove(&self.room);
             }
         }
     }
 }
 
+/// `SimpleMessageRelay` brokers served over any number of listeners.
+///
+/// Clients that talk to each other share a room, with a broker of its own,
+/// so that independent sessions do not share one message queue. WebSocket
+/// clients pick their room by URL path (`ws://host:9007/<room>`); the root
+/// path and the TCP and Unix listeners use the default room. A room lives
+/// while it has clients.
+///
+/// Connection IDs are unique across all listeners of the server, so a
+/// single capture file can hold traffic from every transport.
+pub struct RelayServer {
+    rooms: Mutex<HashMap<String, Room>>,
+    options: ServerOptions,
+    next_conn_id: AtomicU64,
+}
+
+impl RelayServer {
+    pub fn new(options: ServerOptions) -> Arc<Self> {
+        Arc::new(RelayServer {
+            rooms: Mutex::new(HashMap::new()),
+            options,
+            next_conn_id: AtomicU64::new(0),
+        })
+    }
+
+    fn next_conn_id(&self) -> u64 {
+        self.next_conn_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Register a new client with the broker of `room`. The client counts as
+    /// in the room until the returned `Member` is dropped.
+    async fn join(&self, room: &str) -> (MessageRelay, Member<'_>) {
+        let broker = {
+            let mut rooms = self.rooms.lock().unwrap();
+            let room = rooms.entry(room.to_owned()).or_insert_with(|| Room {
+                broker: Arc::new(SimpleMessageRelay::new()),
+                connections: 0,
+            });
+            room.connections += 1;
+            room.broker.clone()
+        };
+        let member = Member {
+            server: self,
+            room: room.to_owned(),
+        };
+        // Call the async trait method explicitly to get a future:
+        let relay_handle = MessageRelayService::connect(&*broker).await.unwrap(); // unwrap the Option<MessageRelay>
+        (relay_handle, member)
+    }
+
+    /// Bridge a framed client in the default room.
+    async fn serve_framed<T>(&self, conn_id: u64, io: T)
+    where
+        T: AsyncRead + AsyncWrite,
+    {
+        let (relay_handle, _member) = self.join("").await;
+        spawn_framed_connection(relay_handle, &self.options, conn_id, io).await
+    }
+
+    /// Serve WebSocket clients from `listener`, over TLS when configured.
+    pub async fn serve_websocket(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (tcp_stream, client_addr) = listener.accept().await?;
+            let conn_id = self.next_conn_id();
+            info!("New WebSocket connection {} from {}", conn_id, client_addr);
+            let server = self.clone();
+            // Spawn the broker task (single task with select!).
+            tokio::spawn(async move {
+                match &server.options.tls {
+                    Some(tls) => match tls.accept(tcp_stream).await {
+                        Ok(tls_stream) => {
+                            spawn_websocket_connection(&server, conn_id, tls_stream).await
+                        }
+                        Err(e) => warn!("TLS handshake error on connection {}: {}", conn_id, e),
+                    },
+                    None => spawn_websocket_connection(&server, conn_id, tcp_stream).await,
+                }
+            });
+        }
+    }
+
+    /// Serve length-prefixed TCP clients from `listener`, over TLS when configured.
+    pub async fn serve_tcp(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (tcp_stream, client_addr) = listener.accept().await?;
+            tcp_stream.set_nodelay(true)?;
+            let conn_id = self.next_conn_id();
+            info!("New TCP connection {} from {}", conn_id, client_addr);
+            let server = self.clone();
+            tokio::spawn(async move {
+                match &server.options.tls {
+                    Some(tls) => match tls.accept(tcp_stream).await {
+                        Ok(tls_stream) => server.serve_framed(conn_id, tls_stream).await,
+                        Err(e) => warn!("TLS handshake error on connection {}: {}", conn_id, e),
+                    },
+                    None => server.serve_framed(conn_id, tcp_stream).await,
+                }
+            });
+        }
+    }
+
+    /// Serve length-prefixed Unix domain socket clients from `listener`.
+    #[cfg(unix)]
+    pub async fn serve_unix(self: Arc<Self>, listener: UnixListener) -> Result<()> {
+        loop {
+            let (unix_stream, _) = listener.accept().await?;
+            let conn_id = self.next_conn_id();
+            info!("New Unix socket connection {}", conn_id);
+            let server = self.clone();
+            tokio::spawn(async move { server.serve_framed(conn_id, unix_stream).await });
+        }
+    }
+}
+
+/// Accept incoming TCP connections, upgrade them to WebSocket, and call `spawn_connection`.
+pub async fn run_server(listen_addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(listen_addr).await?;
+    info!("Relay server listening on ws://{}", listen_addr);
+    // One global SimpleMessageRelay (shared among all connections)
+    RelayServer::new(ServerOptions::default())
+        .serve_websocket(listener)
+        .await
+}
+
+/// Accept plain TCP connections carrying length-prefixed frames.
+pub async fn run_tcp_server(listen_addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(listen_addr).await?;
+    info!("Relay server listening on tcp://{}", listen_addr);
+    RelayServer::new(ServerOptions::default())
+        .serve_tcp(listener)
+        .await
+}
+
+/// Accept Unix domain socket connections carrying length-prefixed frames.
+#[cfg(unix)]
+pub async fn run_unix_server(path: &Path) -> Result<()> {
+    let listener = UnixListener::bind(path)?;
+    info!("Relay server listening on unix://{}", path.display());
+    RelayServer::new(ServerOptions::default())
+        .serve_unix(listener)
+        .await
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::relay::run_server;
+    use chrono::Utc;
+    use ed25519_dalek::SigningKey;
+    use futures_util::{sink, stream};
+    use rust_decimal::Decimal;
+    use sl_mpc_mate::coord::Relay;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::task::JoinSet;
+
+    use crate::capture::{CaptureRecord, Direction, ReplayRelay};
+    use crate::config::{TlsConfig, client_tls_config};
+    use crate::evidence::Witness;
+    use crate::limits::{Disconnect, RelayLimits};
+    use crate::relay::{RelayServer, ServerOptions, spawn_connection};
+    use crate::sealed_order::{PartySecret, SealedOrder, open_all_shares};
+    use crate::socket_relay::SocketRelay;
+    use crate::types::{BookOrder, Order, OrderShare};
+    use crate::v1::{Abort, setup_handle_orders_sock_v1, test_handle_orders_sock_v1};
+    use crate::validate::Validator;
+    use crate::websocket_relay::WebSocketRelay;
+
+    fn sample_shares() -> [[Vec<OrderShare>; 1]; 3] {
+        let secrets = [0, 1, 2].map(|_| PartySecret::generate());
+        let keys = secrets.each_ref().map(PartySecret::public_key);
+        let orders: Vec<BookOrder> = [false, true]
+            .into_iter()
+            .enumerate()
+            .map(|(id, o_type)| BookOrder {
+                id: id as u64,
+                trader: None,
+                order: SealedOrder::seal(
+                    &Order {
+                        o_type,
+                        symbol: "abc".to_owned(),
+                        // The sell is the smaller side.
+                        quantity: if o_type { 80 } else { 100 },
+                        price: Decimal::from(200),
+                        min_execution: 50,
+                        client_key: None,
+                    },
+                    &keys,
+                    &Validator::default(),
+                )
+                .unwrap(),
+                timestamp: Utc::now(),
+                auth: None,
+            })
+            .collect();
+        open_all_shares(&secrets, &orders).unwrap()
+    }
+
+    /// Run all three parties over relays produced by `connect` and check
+    /// that each of them matched the sample buy against the sample sell, for
+    /// the sell's quantity.
+    async fn assert_protocol_completes<R, F, Fut>(connect: F)
+    where
+        R: Relay + Send + 'static,
+        F: Fn() -> Fut,
+        Fut: Future<Output = R>,
+    {
+        let mut join = JoinSet::new();
+        for (setup, seed, share) in setup_handle_orders_sock_v1(Some([5; 32]), &sample_shares()) {
+            let relay = connect().await;
+            let witness = Witness::new([5; 32], &SigningKey::from_bytes(&rand::random()));
+            join.spawn(test_handle_orders_sock_v1(
+                setup,
+                seed,
+                share[0].clone(),
+                relay,
+                witness,
+            ));
+        }
+
+        let mut results = vec![];
+        while let Some(fini) = join.join_next().await {
+            let res: Result<_, Abort> = fini.unwrap();
+            results.push(res.unwrap());
+        }
+
+        assert_eq!(results.len(), 3);
+        for output in &results {
+            assert_eq!(output.fills.len(), 1);
+            assert!(output.out_of_range.is_empty());
+        }
+        let quantity = results.iter().fold(0u64, |acc, output| {
+            let bits = &output.fills[0].quantity;
+            acc ^ bits
+                .iter()
+                .enumerate()
+                .fold(0, |word, (k, bit)| word | (bit.value1 as u64) << k)
+        });
+        assert_eq!(quantity, 80);
+    }
 
     #[tokio::test]
+    async fn test_client_that_stops_reading_is_shed() {
+        // The broker has ten frames for a client whose socket never drains.
+        let records: Vec<_> = (0..10u8)
+            .map(|i| CaptureRecord::Frame {
+                ts: Utc::now(),
+                conn: 0,
+                dir: Direction::Received,
+                frame: vec![i],
+            })
+            .collect();
+        let limits = RelayLimits {
+            outbound_queue: 2,
+            outbound_timeout_ms: 50,
+            ..Default::default()
+        };
+        let stuck = sink::unfold((), |(), _: Vec<u8>| {
+            std::future::pending::<Result<(), ()>>()
+        });
+
+        let reason = spawn_connection(
+            ReplayRelay::new(&records, 0),
+            &limits,
+            Box::pin(stuck),
+            stream::pending(),
+        )
+        .await;
+        assert_eq!(
+            reason,
+            Disconnect::OutboundQueueFull {
+                queue: 2,
+                timeout_ms: 50
+            }
+        );
+        assert!(reason.is_violation());
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_is_waited_for() {
+        // A client that takes 10 ms per frame never has room for the next
+        // one right away, but always within the timeout.
+        let records: Vec<_> = (0..10u8)
+            .map(|i| CaptureRecord::Frame {
+                ts: Utc::now(),
+                conn: 0,
+                dir: Direction::Received,
+                frame: vec![i],
+            })
+            .collect();
+        let limits = RelayLimits {
+            outbound_queue: 1,
+            outbound_timeout_ms: 1000,
+            ..Default::default()
+        };
+        let (delivered_tx, mut delivered) = tokio::sync::mpsc::unbounded_channel();
+        let slow = sink::unfold(delivered_tx, |delivered_tx, frame: Vec<u8>| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            delivered_tx.send(frame[0]).unwrap();
+            Ok::<_, ()>(delivered_tx)
+        });
+
+        let reason = spawn_connection(
+            ReplayRelay::new(&records, 0),
+            &limits,
+            Box::pin(slow),
+            stream::pending(),
+        )
+        .await;
+        // The broker ran out of frames before the client fell too far behind.
+        assert_eq!(reason, Disconnect::BrokerClosed);
+        let mut received = vec![];
+        while let Ok(frame) = delivered.try_recv() {
+            received.push(frame);
+        }
+        assert!(received.len() >= 8, "received {:?}", received);
+        assert_eq!(received, (0..received.len() as u8).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
     async fn test_relay_server() {
-        // You can make this configurable, e.g. via CLI args or an environment variable.
-        let addr = "0.0.0.0:9007".parse().unwrap();
-        let _ = run_server(addr).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server =
+            tokio::spawn(RelayServer::new(ServerOptions::default()).serve_websocket(listener));
+
+        assert_protocol_completes(|| async { WebSocketRelay::connect(&url).await.unwrap() }).await;
+        server.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handle_orders_over_tcp_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(RelayServer::new(ServerOptions::default()).serve_tcp(listener));
+
+        assert_protocol_completes(|| async { SocketRelay::connect_tcp(addr).await.unwrap() }).await;
+        server.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handle_orders_over_tls_tcp_relay() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let dir = std::env::temp_dir().join(format!("relay-tls-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        let tls = TlsConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+        };
+        std::fs::write(&tls.cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&tls.key_path, certified.key_pair.serialize_pem()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = ServerOptions {
+            tls: Some(tls.acceptor().unwrap()),
+            ..Default::default()
+        };
+        let server = tokio::spawn(RelayServer::new(options).serve_tcp(listener));
+
+        // Without the self-signed certificate among its roots the client
+        // refuses the server, and it refuses a name the certificate lacks.
+        let trusted_self_signed = client_tls_config(&tls.cert_path).unwrap();
+        let untrusted = std::sync::Arc::new(
+            tokio_rustls::rustls::ClientConfig::builder()
+                .with_root_certificates(tokio_rustls::rustls::RootCertStore::empty())
+                .with_no_client_auth(),
+        );
+        assert!(
+            SocketRelay::connect_tls(addr, "localhost", untrusted)
+                .await
+                .is_err()
+        );
+        assert!(
+            SocketRelay::connect_tls(addr, "relay.example", trusted_self_signed.clone())
+                .await
+                .is_err()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_protocol_completes(|| async {
+            SocketRelay::connect_tls(addr, "localhost", trusted_self_signed.clone())
+                .await
+                .unwrap()
+        })
+        .await;
+        server.abort();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handle_orders_over_unix_relay() {
+        use tokio::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("relay-{}.sock", rand::random::<u64>()));
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = tokio::spawn(RelayServer::new(ServerOptions::default()).serve_unix(listener));
+
+        assert_protocol_completes(|| async { SocketRelay::connect_unix(&path).await.unwrap() })
+            .await;
+        server.abort();
+        let _ = std::fs::remove_file(&path);
     }
 }
@@ -0,0 +1,246 @@
+use anyhow::{Result, ensure};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::book::TraderFill;
+use crate::fill::{ClientSecret, FillShare, reconstruct_fill, trade_id};
+use crate::price::PriceScale;
+
+/// One executed trade, as written to the operator's trade report. It has no
+/// quantity or price: the terms stay secret from the operator, and only the
+/// counterparties' `Confirmation`s carry them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trade {
+    pub trade_id: String,
+    pub symbol: String,
+    pub buy_order_id: u64,
+    pub sell_order_id: u64,
+    pub buy_timestamp: DateTime<Utc>,
+    pub sell_timestamp: DateTime<Utc>,
+    pub executed_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    /// One JSON object per line.
+    #[default]
+    Jsonl,
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+/// The public record of each fill: which orders traded, without the terms,
+/// which only the counterparties can reconstruct (see `fill`).
+pub fn trades_from_fills(
+    batch_id: &str,
+    fills: &[FillShare],
+    executed_at: DateTime<Utc>,
+) -> Vec<Trade> {
+    fills
+        .iter()
+        .enumerate()
+        .map(|(n, fill)| Trade {
+            trade_id: trade_id(batch_id, n),
+            symbol: fill.symbol.clone(),
+            buy_order_id: fill.buy_order_id,
+            sell_order_id: fill.sell_order_id,
+            buy_timestamp: fill.buy_timestamp,
+            sell_timestamp: fill.sell_timestamp,
+            executed_at,
+        })
+        .collect()
+}
+
+/// One side of a trade as its trader confirms it: the public record with
+/// the terms the trader reconstructed from the sealed shares, the price
+/// decoded back to a decimal.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Confirmation {
+    pub trade_id: String,
+    pub symbol: String,
+    pub order_id: u64,
+    /// Executed quantity in shares.
+    pub quantity: u64,
+    /// Midpoint of the two limit prices.
+    pub price: Decimal,
+    /// Whether the order traded its whole quantity; the rest is cancelled
+    /// otherwise.
+    pub filled: bool,
+    pub executed_at: DateTime<Utc>,
+}
+
+impl Confirmation {
+    /// Client side: open a trader's fill from the feed or `GET /fills`.
+    /// `scale` and `lot_size` are those of the fill's symbol, as published
+    /// by the backend.
+    pub fn confirm(
+        fill: &TraderFill,
+        secret: &ClientSecret,
+        scale: &PriceScale,
+        lot_size: u64,
+    ) -> Result<Self> {
+        let terms = reconstruct_fill(secret, &fill.shares, scale, lot_size)?;
+        ensure!(
+            terms.trade_id == fill.trade.trade_id && terms.order_id == fill.order_id,
+            "fill shares belong to another trade"
+        );
+        Ok(Confirmation {
+            trade_id: terms.trade_id,
+            symbol: fill.trade.symbol.clone(),
+            order_id: terms.order_id,
+            quantity: terms.quantity,
+            price: terms.price,
+            filled: terms.filled,
+            executed_at: fill.trade.executed_at,
+        })
+    }
+}
+
+/// Appends trades, or a trader's confirmations, to a report file.
+pub struct ReportWriter {
+    file: File,
+    format: ReportFormat,
+}
+
+impl ReportWriter {
+    pub fn open<P: AsRef<Path>>(path: P, format: ReportFormat) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(ReportWriter { file, format })
+    }
+
+    pub fn write<T: Serialize>(&mut self, rows: &[T]) -> std::io::Result<()> {
+        match self.format {
+            ReportFormat::Jsonl => {
+                let mut out = Vec::new();
+                for row in rows {
+                    serde_json::to_writer(&mut out, row)?;
+                    out.push(b'\n');
+                }
+                self.file.write_all(&out)?;
+            }
+            ReportFormat::Csv => {
+                // Header only at the top of a new file.
+                let has_headers = self.file.metadata()?.len() == 0;
+                let mut out = csv::WriterBuilder::new()
+                    .has_headers(has_headers)
+                    .from_writer(&mut self.file);
+                for row in rows {
+                    out.serialize(row)?;
+                }
+                out.flush()?;
+            }
+        }
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use sl_compute::types::{ArithmeticShare, BinaryShare, FieldElement};
+
+    use crate::book::TraderFill;
+    use crate::fill::{ClientSecret, FillShare, seal_fills};
+    use crate::price::PriceScale;
+    use crate::report::{Confirmation, ReportFormat, ReportWriter, Trade};
+
+    fn trade(trade_id: &str, buy_order_id: u64) -> Trade {
+        let now = Utc::now();
+        Trade {
+            trade_id: trade_id.into(),
+            symbol: "MSFT".into(),
+            buy_order_id,
+            sell_order_id: 2,
+            buy_timestamp: now,
+            sell_timestamp: now,
+            executed_at: now,
+        }
+    }
+
+    #[test]
+    fn test_csv_header_is_written_once() {
+        let path = std::env::temp_dir().join(format!("trades-{}.csv", rand::random::<u64>()));
+        let trade = trade("b1-1", 1);
+        for _ in 0..2 {
+            ReportWriter::open(&path, ReportFormat::Csv)
+                .unwrap()
+                .write(std::slice::from_ref(&trade))
+                .unwrap();
+        }
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("trade_id,symbol,buy_order_id,sell_order_id,"));
+        assert!(lines[1].starts_with("b1-1,MSFT,1,2,"));
+    }
+
+    #[test]
+    fn test_confirmation_decodes_the_price() {
+        let buyer = ClientSecret::generate();
+        // Party 0 holds 25 lots and the sum of limits of 30.05 and 30.10 in
+        // ticks of 0.05; the others hold zero.
+        let shares: Vec<_> = (0..3)
+            .flat_map(|party| {
+                let bit = |on: bool| BinaryShare {
+                    value1: on,
+                    value2: on,
+                };
+                let fill = FillShare {
+                    buy_order_id: 1,
+                    sell_order_id: 2,
+                    symbol: "MSFT".into(),
+                    buy_timestamp: Utc::now(),
+                    sell_timestamp: Utc::now(),
+                    buy_key: Some(buyer.public_key()),
+                    sell_key: None,
+                    quantity: (0..8)
+                        .map(|k| bit(party == 0 && (25 >> k) & 1 == 1))
+                        .collect(),
+                    filled: [bit(party == 0), bit(false)],
+                    price_sum: ArithmeticShare::from_constant(&FieldElement::from(1203u64), party),
+                };
+                seal_fills(party, "b1", &[fill])
+            })
+            .collect();
+        let fill = TraderFill {
+            order_id: 1,
+            trade: trade("b1-1", 1),
+            shares,
+        };
+        let scale = PriceScale {
+            scale: 2,
+            tick_size: Some("0.05".parse().unwrap()),
+        };
+
+        let confirmation = Confirmation::confirm(&fill, &buyer, &scale, 100).unwrap();
+        assert_eq!(confirmation.quantity, 2500);
+        assert_eq!(confirmation.price, "30.075".parse().unwrap());
+        assert!(confirmation.filled);
+        assert!(Confirmation::confirm(&fill, &ClientSecret::generate(), &scale, 100).is_err());
+
+        let path = std::env::temp_dir().join(format!("fills-{}.csv", rand::random::<u64>()));
+        ReportWriter::open(&path, ReportFormat::Csv)
+            .unwrap()
+            .write(&[confirmation])
+            .unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        // Halving the sum of ticks keeps one more decimal place.
+        assert!(
+            text.lines()
+                .nth(1)
+                .unwrap()
+                .starts_with("b1-1,MSFT,1,2500,30.0750,true,")
+        );
+    }
+}
@@ -0,0 +1,484 @@
+//! Matching a batch as independent per-symbol books.
+//!
+//! Orders only ever match orders of their own symbol, so the scheduler splits
+//! every batch by symbol and matches each book in an MPC session of its own,
+//! all of them at once. A symbol's parties talk in a relay room named after
+//! the symbol (see `relay`), under instances derived from the batch's
+//! instance, the symbol and the book's orders.
+//!
+//! The backend alone splits the batch, once: the three parties of a symbol
+//! are handed the same orders in the same order, and since the epoch's
+//! instance is bound to them, their evidence and captures name the same
+//! partition. The books' results are kept apart by symbol, so the backend
+//! can publish the symbols the parties agree on and retry only the rest.
+//! Each book numbers its trades on its own, under its own
+//! `book_batch_id`, since the parties seal their fill shares per book.
+//!
+//! The sessions run in the party processes (see `party`). Before a batch is
+//! split, every party checks that it can decrypt its share of each order;
+//! orders some party cannot are left out of the batch and rejected. Orders
+//! of halted or delisted instruments are left out too, and rest until their
+//! instrument trades again.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio_rustls::rustls::ClientConfig;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::aggregate::PartyResult;
+use crate::config::{BackendConfig, PartyLink};
+use crate::evidence::Account;
+use crate::instruments::{Instrument, InstrumentMaster, TradingStatus};
+use crate::party::{ControlRequest, PartyClient, PartyHello, RemoteRunner};
+use crate::session::EpochError;
+use crate::types::BookOrder;
+use crate::v1::triples_for_orders;
+use crate::validate::BitWidths;
+
+/// AND triples the parties of a symbol preprocess per round while idle. A
+/// batch cut during a round waits for it to finish.
+const PREPROCESS_CHUNK: usize = 256;
+
+/// Split a batch into one book per symbol, each in batch order.
+pub fn partition(orders: &[BookOrder]) -> BTreeMap<String, Vec<BookOrder>> {
+    let mut books: BTreeMap<String, Vec<BookOrder>> = BTreeMap::new();
+    for order in orders {
+        books
+            .entry(order.order.symbol.clone())
+            .or_default()
+            .push(order.clone());
+    }
+    books
+}
+
+/// Instance of the session or epoch matching `orders` of `symbol` in the run
+/// of a batch under `instance`.
+pub fn book_instance(instance: &[u8; 32], symbol: &str, orders: &[BookOrder]) -> [u8; 32] {
+    let mut hasher = Sha256::new()
+        .chain_update(instance)
+        .chain_update(b"book")
+        .chain_update((symbol.len() as u32).to_be_bytes())
+        .chain_update(symbol.as_bytes())
+        .chain_update((orders.len() as u32).to_be_bytes());
+    for order in orders {
+        hasher.update(order.id.to_be_bytes());
+    }
+    hasher.finalize().into()
+}
+
+/// Batch ID the trades of `symbol`'s book in batch `batch_id` are numbered
+/// under.
+pub fn book_batch_id(batch_id: &str, symbol: &str) -> String {
+    format!("{}-{}", batch_id, symbol)
+}
+
+/// URL of relay room `room` on the relay at `base`.
+pub fn room_url(base: &str, room: &str) -> Result<String> {
+    let mut url = Url::parse(base).with_context(|| format!("Invalid relay URL {}", base))?;
+    url.path_segments_mut()
+        .map_err(|()| anyhow::anyhow!("Relay URL {} cannot have rooms", base))?
+        .pop_if_empty()
+        .push(room);
+    Ok(url.into())
+}
+
+/// A symbol whose epoch failed verification.
+pub struct Incident {
+    pub symbol: String,
+    /// Instance of the failed epoch.
+    pub instance: [u8; 32],
+    pub accounts: [Account; 3],
+}
+
+/// What the parties produced for a batch over all its symbols.
+pub struct BatchOutputs {
+    /// For each symbol matched, each party's trades, sealed fills and
+    /// range-check failures, or why the party failed.
+    pub books: BTreeMap<String, [Result<PartyResult, String>; 3]>,
+    pub incidents: Vec<Incident>,
+    /// Orders left out because some party could not decrypt its share.
+    pub undecryptable: Vec<u64>,
+}
+
+#[derive(Default)]
+struct State {
+    sessions: BTreeMap<String, [RemoteRunner; 3]>,
+    /// Largest book of each symbol so far.
+    largest: BTreeMap<String, usize>,
+}
+
+/// Runs the parties' sessions, one set of three per symbol.
+pub struct Scheduler {
+    links: [PartyLink; 3],
+    tls: Option<Arc<ClientConfig>>,
+    /// How each party greeted the backend at startup; every later connection
+    /// must present the same keys.
+    hellos: [PartyHello; 3],
+    /// Connections of the share checks, set up again after an error.
+    checks: [Mutex<Option<PartyClient>>; 3],
+    rekey_epochs: u64,
+    preprocess_orders: Option<usize>,
+    widths: BitWidths,
+    /// Reference data of the instrument master, when one is loaded.
+    instruments: std::sync::RwLock<BTreeMap<String, Instrument>>,
+    state: Mutex<State>,
+}
+
+impl Scheduler {
+    /// A scheduler driving the parties `clients` are connected to, in
+    /// participant order.
+    pub fn new(
+        config: &BackendConfig,
+        tls: Option<Arc<ClientConfig>>,
+        clients: [PartyClient; 3],
+    ) -> Result<Self> {
+        let hellos = clients.each_ref().map(|client| *client.hello());
+        for (party, hello) in hellos.iter().enumerate() {
+            anyhow::ensure!(
+                hello.party == party,
+                "{} is party {}, not party {}",
+                config.parties[party].control_url,
+                hello.party,
+                party
+            );
+        }
+        Ok(Scheduler {
+            links: config.parties.clone(),
+            tls,
+            hellos,
+            checks: clients.map(|client| Mutex::new(Some(client))),
+            rekey_epochs: config.rekey_epochs,
+            preprocess_orders: config.preprocess_orders,
+            widths: config.widths,
+            instruments: Default::default(),
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Range-check each symbol's orders against its instrument's minimum
+    /// quantity.
+    pub fn with_instruments(self, master: &InstrumentMaster) -> Self {
+        *self.instruments.write().unwrap() = master.instruments.clone();
+        self
+    }
+
+    /// Switch to a reloaded instrument master. Sessions range-checking
+    /// against a minimum quantity that changed, or of instruments no longer
+    /// trading, are dropped; the active instruments get sessions ahead of
+    /// their next batch.
+    pub async fn reload(&self, master: &InstrumentMaster) {
+        let previous = std::mem::replace(
+            &mut *self.instruments.write().unwrap(),
+            master.instruments.clone(),
+        );
+        let mut state = self.state.lock().await;
+        state.sessions.retain(|symbol, _| {
+            let now = master.get(symbol);
+            now.is_some_and(|now| {
+                now.status == TradingStatus::Active
+                    && previous
+                        .get(symbol)
+                        .is_none_or(|before| before.min_quantity == now.min_quantity)
+            })
+        });
+        drop(state);
+        self.establish(master.active()).await;
+    }
+
+    /// Whether `symbol`'s orders are matched: always without an instrument
+    /// master, only while its instrument is active with one.
+    fn trading(&self, symbol: &str) -> bool {
+        let instruments = self.instruments.read().unwrap();
+        instruments.is_empty()
+            || instruments
+                .get(symbol)
+                .is_some_and(|instrument| instrument.status == TradingStatus::Active)
+    }
+
+    fn spawn(&self, symbol: &str, instance: [u8; 32]) -> [RemoteRunner; 3] {
+        // Orders are sealed in lots, so is the minimum.
+        let min_quantity = self
+            .instruments
+            .read()
+            .unwrap()
+            .get(symbol)
+            .map_or(1, |instrument| {
+                instrument.min_quantity.div_ceil(instrument.lot_size)
+            });
+        info!(
+            "Establishing {} sessions under instance {}",
+            symbol,
+            hex::encode(instance)
+        );
+        std::array::from_fn(|party| {
+            RemoteRunner::spawn(
+                self.links[party].clone(),
+                self.tls.clone(),
+                self.hellos[party],
+                ControlRequest::Establish {
+                    symbol: symbol.to_owned(),
+                    instance,
+                    rekey_epochs: self.rekey_epochs,
+                    widths: self.widths,
+                    min_quantity,
+                },
+            )
+        })
+    }
+
+    /// Which of `orders` `party` cannot decrypt its share of.
+    async fn check(&self, party: usize, orders: &[BookOrder]) -> Result<Vec<u64>> {
+        let mut client = self.checks[party].lock().await;
+        let connected = match client.take() {
+            Some(connected) => connected,
+            None => {
+                let connected = PartyClient::connect(&self.links[party], self.tls.clone()).await?;
+                anyhow::ensure!(
+                    *connected.hello() == self.hellos[party],
+                    "party {} presented other keys",
+                    party
+                );
+                connected
+            }
+        };
+        let connected = client.insert(connected);
+        match connected.check(orders).await {
+            Ok(undecryptable) => Ok(undecryptable),
+            Err(e) => {
+                *client = None;
+                Err(e.context(format!("Party {} failed to check its shares", party)))
+            }
+        }
+    }
+
+    /// Set up sessions for `symbols` ahead of their first batch, so that the
+    /// parties can preprocess for it.
+    pub async fn establish<'a>(&self, symbols: impl IntoIterator<Item = &'a String>) {
+        let mut state = self.state.lock().await;
+        for symbol in symbols {
+            if !state.sessions.contains_key(symbol) {
+                let parties = self.spawn(symbol, rand::random());
+                state.sessions.insert(symbol.clone(), parties);
+            }
+        }
+    }
+
+    /// Drop the sessions of `symbol`; its next batch sets up new ones.
+    pub async fn reset(&self, symbol: &str) {
+        self.state.lock().await.sessions.remove(symbol);
+    }
+
+    /// AND triples the parties have in stock over all symbols.
+    pub async fn stock(&self) -> usize {
+        let state = self.state.lock().await;
+        state
+            .sessions
+            .values()
+            .map(|parties| parties[0].stock())
+            .sum()
+    }
+
+    /// Have the parties of every symbol preprocess AND triples for the
+    /// book they expect: `preprocess_orders` orders, or the symbol's largest
+    /// so far. `report` sees the total stock after every round.
+    ///
+    /// May be cancelled at any point: every round is queued to all three
+    /// parties of a symbol before it is awaited, so they keep running the same
+    /// commands.
+    pub async fn preprocess(&self, report: impl Fn(usize)) {
+        let mut state = self.state.lock().await;
+        let symbols: Vec<String> = state.sessions.keys().cloned().collect();
+        for symbol in symbols {
+            let expected = self
+                .preprocess_orders
+                .unwrap_or_else(|| state.largest.get(&symbol).copied().unwrap_or(0));
+            let target = triples_for_orders(expected, &self.widths);
+            let parties = &state.sessions[&symbol];
+            let mut failed = false;
+            while parties[0].stock() < target {
+                let chunk = PREPROCESS_CHUNK.min(target - parties[0].stock());
+                let [a, b, c] = parties.each_ref().map(|party| party.preprocess(chunk));
+                if let (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) = tokio::join!(a, b, c) {
+                    warn!(
+                        "Preprocessing for {} failed: {}; its sessions will be set up again",
+                        symbol, e
+                    );
+                    failed = true;
+                    break;
+                }
+                report(state.sessions.values().map(|p| p[0].stock()).sum());
+            }
+            if failed {
+                state.sessions.remove(&symbol);
+                report(state.sessions.values().map(|p| p[0].stock()).sum());
+            }
+        }
+    }
+
+    /// Match every symbol's book of `orders` at once, over the sessions of
+    /// earlier batches where they are still up and over new ones established
+    /// under `instance` otherwise. Sessions of symbols where a party failed
+    /// are dropped. Each symbol's results come back on their own, so a
+    /// failure on one symbol does not hold back the others.
+    pub async fn run(
+        &self,
+        batch_id: &str,
+        instance: [u8; 32],
+        orders: &[BookOrder],
+    ) -> Result<BatchOutputs> {
+        let (a, b, c) = tokio::try_join!(
+            self.check(0, orders),
+            self.check(1, orders),
+            self.check(2, orders)
+        )?;
+        let undecryptable: BTreeSet<u64> = [a, b, c].into_iter().flatten().collect();
+        let orders: Vec<BookOrder> = orders
+            .iter()
+            .filter(|order| !undecryptable.contains(&order.id))
+            .cloned()
+            .collect();
+
+        let mut state = self.state.lock().await;
+        let mut tasks = JoinSet::new();
+        for (symbol, orders) in partition(&orders) {
+            if !self.trading(&symbol) {
+                info!(
+                    "Leaving {} orders of {} out of batch {}: not trading",
+                    orders.len(),
+                    symbol,
+                    batch_id
+                );
+                continue;
+            }
+            let largest = state.largest.entry(symbol.clone()).or_default();
+            *largest = (*largest).max(orders.len());
+            let epoch = book_instance(&instance, &symbol, &orders);
+            let parties = match state.sessions.remove(&symbol) {
+                Some(parties) => parties,
+                None => self.spawn(&symbol, epoch),
+            };
+            // Each party decrypts its own share of every order
+            let book_batch_id = book_batch_id(batch_id, &symbol);
+            let [a, b, c] = parties
+                .each_ref()
+                .map(|party| party.run_epoch(epoch, book_batch_id.clone(), orders.clone()));
+            tasks.spawn(async move {
+                let outputs = tokio::join!(a, b, c);
+                (symbol, epoch, parties, [outputs.0, outputs.1, outputs.2])
+            });
+        }
+
+        let mut books = BTreeMap::new();
+        while let Some(book) = tasks.join_next().await {
+            let (symbol, epoch, parties, outputs) = book.context("Symbol session panicked")?;
+            books.insert(symbol, (epoch, parties, outputs));
+        }
+
+        let mut results = BTreeMap::new();
+        let mut incidents = vec![];
+        for (symbol, (epoch, parties, mut outputs)) in books {
+            let mut accounts = [Account::Passed, Account::Passed, Account::Passed];
+            for (account, output) in accounts.iter_mut().zip(&mut outputs) {
+                if let Err(e) = output {
+                    *account = match e {
+                        EpochError::Failed {
+                            evidence: signed, ..
+                        } => signed.take().map_or(Account::Silent, Account::Testified),
+                        _ => Account::Silent,
+                    };
+                }
+            }
+            let failed = outputs.iter().any(Result::is_err);
+            if accounts
+                .iter()
+                .any(|account| matches!(account, Account::Testified(_)))
+            {
+                incidents.push(Incident {
+                    symbol: symbol.clone(),
+                    instance: epoch,
+                    accounts,
+                });
+            }
+            // A failed session cannot be reused; the next attempt sets up new ones.
+            if !failed {
+                state.sessions.insert(symbol.clone(), parties);
+            }
+            let outputs = outputs.map(|output| output.map_err(|e| format!("{}: {}", symbol, e)));
+            results.insert(symbol, outputs);
+        }
+        Ok(BatchOutputs {
+            books: results,
+            incidents,
+            undecryptable: undecryptable.into_iter().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+
+    use crate::scheduler::{book_instance, partition, room_url};
+    use crate::sealed_order::{PartyKey, PartySecret, SealedOrder};
+    use crate::types::{BookOrder, Order};
+    use crate::validate::Validator;
+
+    fn order(id: u64, symbol: &str, keys: &[PartyKey; 3]) -> BookOrder {
+        BookOrder {
+            id,
+            trader: None,
+            order: SealedOrder::seal(
+                &Order {
+                    o_type: id % 2 == 1,
+                    symbol: symbol.to_owned(),
+                    quantity: 100,
+                    price: Decimal::from(200),
+                    min_execution: 50,
+                    client_key: None,
+                },
+                keys,
+                &Validator::default(),
+            )
+            .unwrap(),
+            timestamp: Utc::now(),
+            auth: None,
+        }
+    }
+
+    #[test]
+    fn test_partitions_by_symbol_in_batch_order() {
+        let keys = [0, 1, 2].map(|_| PartySecret::generate().public_key());
+        let orders: Vec<BookOrder> = [(1, "xyz"), (2, "abc"), (3, "xyz"), (4, "abc")]
+            .into_iter()
+            .map(|(id, symbol)| order(id, symbol, &keys))
+            .collect();
+
+        let books = partition(&orders);
+        let ids: Vec<(&str, Vec<u64>)> = books
+            .iter()
+            .map(|(symbol, book)| (symbol.as_str(), book.iter().map(|o| o.id).collect()))
+            .collect();
+        assert_eq!(ids, [("abc", vec![2, 4]), ("xyz", vec![1, 3])]);
+
+        let instance = [9; 32];
+        let abc = book_instance(&instance, "abc", &books["abc"]);
+        assert_ne!(abc, book_instance(&instance, "xyz", &books["abc"]));
+        assert_ne!(abc, book_instance(&instance, "abc", &books["abc"][..1]));
+        assert_ne!(abc, book_instance(&[8; 32], "abc", &books["abc"]));
+
+        assert_eq!(
+            room_url("ws://localhost:9007", "abc").unwrap(),
+            "ws://localhost:9007/abc"
+        );
+        assert_eq!(
+            room_url("wss://relay.example/mpc/", "BRK B").unwrap(),
+            "wss://relay.example/mpc/BRK%20B"
+        );
+    }
+}
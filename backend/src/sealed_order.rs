@@ -0,0 +1,427 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sl_compute::types::ArithmeticShare;
+use std::path::Path;
+
+use crate::ecies::{self, Envelope};
+use crate::fill::{ClientKey, share_from_words};
+use crate::types::{BookOrder, Order, OrderShare};
+use crate::validate::{FRACTION_BITS, RejectCode, Validator};
+
+/// HKDF info of order share envelopes.
+const ORDER_INFO: &[u8] = b"darkpool order share";
+
+/// X25519 public key a party publishes for traders to encrypt order shares to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartyKey(#[serde(with = "hex::serde")] pub [u8; 32]);
+
+/// A party's side of its `PartyKey`.
+pub struct PartySecret([u8; 32]);
+
+impl PartySecret {
+    pub fn generate() -> Self {
+        PartySecret(rand::random())
+    }
+
+    /// Read a secret stored as 64 hex digits.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut secret = [0u8; 32];
+        hex::decode_to_slice(text.trim(), &mut secret)
+            .with_context(|| format!("Invalid party key in {}", path.display()))?;
+        Ok(PartySecret(secret))
+    }
+
+    pub fn public_key(&self) -> PartyKey {
+        PartyKey(ecies::public_key(&self.0))
+    }
+
+    /// Seed of the randomness the party preprocesses with in the session
+    /// established under `instance`. Only the party knows it, and a replay
+    /// with the party's key draws the same values again.
+    pub fn preprocessing_seed(&self, instance: &[u8; 32]) -> [u8; 32] {
+        Sha256::new()
+            .chain_update(b"darkpool preprocessing seed")
+            .chain_update(self.0)
+            .chain_update(instance)
+            .finalize()
+            .into()
+    }
+}
+
+/// Split `value` into a fresh random sharing, one share per party.
+///
+/// Uses the fixed-point encoding and layout of `ArithmeticShare::from_constant`:
+/// the parties' words form an additive sharing of the encoded value, each
+/// party holding its share in both words.
+pub fn share_value(value: u64) -> [ArithmeticShare; 3] {
+    let encoded = value.wrapping_shl(FRACTION_BITS);
+    let (x0, x1): (u64, u64) = (rand::random(), rand::random());
+    let x2 = encoded.wrapping_sub(x0).wrapping_sub(x1);
+    [x0, x1, x2].map(|x| share_from_words([x, x]))
+}
+
+/// Version byte of `OrderSharePayload::to_bytes`.
+const PAYLOAD_VERSION: u8 = 2;
+
+/// What one party learns of an order: its share of the private terms. Each
+/// share is one word, which `share_value` puts in both words of the
+/// `ArithmeticShare`. The parties take the terms' bits from the shares
+/// themselves (see `run_range_check_v1`).
+#[derive(Debug, PartialEq, Eq)]
+struct OrderSharePayload {
+    quantity: u64,
+    price: u64,
+    min_execution: u64,
+}
+
+impl OrderSharePayload {
+    /// `PAYLOAD_VERSION`, then the quantity, price and minimum execution
+    /// shares as big-endian `u64`s.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![PAYLOAD_VERSION];
+        for word in [self.quantity, self.price, self.min_execution] {
+            out.extend_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let [PAYLOAD_VERSION, rest @ ..] = bytes else {
+            return None;
+        };
+        if rest.len() != 24 {
+            return None;
+        }
+        let word = |k: usize| u64::from_be_bytes(rest[8 * k..8 * (k + 1)].try_into().unwrap());
+        Some(OrderSharePayload {
+            quantity: word(0),
+            price: word(1),
+            min_execution: word(2),
+        })
+    }
+}
+
+pub(crate) fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// The public fields of an order: `o_type` as one byte, the symbol as a
+/// big-endian `u32` length and UTF-8 bytes, and the client key as a presence
+/// byte followed by its 32 bytes.
+fn header_bytes(o_type: bool, symbol: &str, client_key: Option<&ClientKey>) -> Vec<u8> {
+    let mut out = vec![o_type as u8];
+    put_bytes(&mut out, symbol.as_bytes());
+    match client_key {
+        Some(key) => {
+            out.push(1);
+            out.extend_from_slice(&key.0);
+        }
+        None => out.push(0),
+    }
+    out
+}
+
+/// Each share is bound to the public fields and its party, so shares cannot
+/// be moved between orders or parties.
+fn associated_data(header: &[u8], party: usize) -> Vec<u8> {
+    let mut aad = header.to_vec();
+    aad.push(party as u8);
+    aad
+}
+
+/// An order as the trader submits it: side, symbol and client key in the
+/// clear, and the quantity, price and minimum execution secret-shared with
+/// one share encrypted to each party, quantities in lots and the price in
+/// ticks. Nothing before the parties sees the
+/// terms, not the intake nor the relay.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SealedOrder {
+    pub o_type: bool, // false for buy, true for sell
+    pub symbol: String,
+    /// Where the trader wants its fills delivered (see `fill`).
+    #[serde(default)]
+    pub client_key: Option<ClientKey>,
+    /// Share of each party, in participant order.
+    pub shares: [Envelope; 3],
+}
+
+impl SealedOrder {
+    /// Client side: check the order as the backend would have in the clear,
+    /// then share and encrypt it to the parties.
+    pub fn seal(
+        order: &Order,
+        keys: &[PartyKey; 3],
+        validator: &Validator,
+    ) -> Result<Self, RejectCode> {
+        validator.check(order)?;
+        let price = validator.encode_price(order)?;
+        let [quantity, min_execution] = validator.encode_quantities(order);
+        let [quantity, price, min_execution] = [quantity, price, min_execution].map(share_value);
+        let header = header_bytes(order.o_type, &order.symbol, order.client_key.as_ref());
+        let word = |share: &ArithmeticShare| u64::from(share.value1);
+        let shares = [0, 1, 2].map(|party| {
+            let payload = OrderSharePayload {
+                quantity: word(&quantity[party]),
+                price: word(&price[party]),
+                min_execution: word(&min_execution[party]),
+            };
+            ecies::seal(
+                &keys[party].0,
+                ORDER_INFO,
+                &associated_data(&header, party),
+                &payload.to_bytes(),
+            )
+        });
+        let [Some(first), Some(second), Some(third)] = shares else {
+            return Err(RejectCode::WeakKey);
+        };
+        Ok(SealedOrder {
+            o_type: order.o_type,
+            symbol: order.symbol.clone(),
+            client_key: order.client_key,
+            shares: [first, second, third],
+        })
+    }
+
+    /// The public fields in canonical form (see `header_bytes`).
+    pub fn header_bytes(&self) -> Vec<u8> {
+        header_bytes(self.o_type, &self.symbol, self.client_key.as_ref())
+    }
+
+    /// Every field in canonical form: the header, then each envelope's
+    /// ephemeral key, nonce and length-prefixed ciphertext.
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut out = self.header_bytes();
+        for share in &self.shares {
+            out.extend_from_slice(&share.ephemeral_key);
+            out.extend_from_slice(&share.nonce);
+            put_bytes(&mut out, &share.ciphertext);
+        }
+        out
+    }
+
+    /// Party side: decrypt this party's share of the terms.
+    fn open(&self, party: usize, secret: &PartySecret) -> Option<OrderSharePayload> {
+        let plaintext = ecies::open(
+            &secret.0,
+            &self.shares[party],
+            ORDER_INFO,
+            &associated_data(&self.header_bytes(), party),
+        )?;
+        OrderSharePayload::from_bytes(&plaintext)
+    }
+}
+
+/// A party's share as `share_value` lays it out, in both words.
+fn share_from_word(word: u64) -> ArithmeticShare {
+    share_from_words([word, word])
+}
+
+/// Orders whose share for `party` does not decrypt under its key.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UndecryptableShares {
+    pub party: usize,
+    pub orders: Vec<u64>,
+}
+
+impl std::fmt::Display for UndecryptableShares {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "party {} cannot decrypt its share of orders {:?}",
+            self.party, self.orders
+        )
+    }
+}
+
+impl std::error::Error for UndecryptableShares {}
+
+/// Party side: this party's shares of a batch of orders.
+///
+/// Fails if any share does not decrypt, naming every such order: the batch
+/// must be matched without them, since the other two shares alone could add
+/// up to anything.
+pub fn open_order_shares(
+    party: usize,
+    secret: &PartySecret,
+    orders: &[BookOrder],
+) -> Result<Vec<OrderShare>, UndecryptableShares> {
+    let mut shares = Vec::with_capacity(orders.len());
+    let mut undecryptable = vec![];
+    for BookOrder {
+        id,
+        order,
+        timestamp,
+        ..
+    } in orders
+    {
+        let Some(payload) = order.open(party, secret) else {
+            undecryptable.push(*id);
+            continue;
+        };
+        shares.push(OrderShare {
+            order_id: *id,
+            o_type: order.o_type,
+            symbol: order.symbol.clone(),
+            quantity: share_from_word(payload.quantity),
+            price: share_from_word(payload.price),
+            min_execution: share_from_word(payload.min_execution),
+            timestamp: *timestamp,
+            client_key: order.client_key,
+        });
+    }
+    if !undecryptable.is_empty() {
+        return Err(UndecryptableShares {
+            party,
+            orders: undecryptable,
+        });
+    }
+    Ok(shares)
+}
+
+/// Every party's shares of a batch, in the layout expected by
+/// `setup_handle_orders_sock_v1`. Only for tests and tools that hold all
+/// three secrets; a party process opens its own share alone.
+pub fn open_all_shares(
+    secrets: &[PartySecret; 3],
+    orders: &[BookOrder],
+) -> Result<[[Vec<OrderShare>; 1]; 3], UndecryptableShares> {
+    let [a, b, c] = [0, 1, 2].map(|party| open_order_shares(party, &secrets[party], orders));
+    Ok([[a?], [b?], [c?]])
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+
+    use crate::fill::reconstruct;
+    use crate::sealed_order::{
+        OrderSharePayload, PartySecret, SealedOrder, UndecryptableShares, open_all_shares,
+        open_order_shares,
+    };
+    use crate::types::{BookOrder, Order};
+    use crate::validate::{RejectCode, Validator};
+
+    #[test]
+    fn test_each_party_opens_only_its_share() {
+        let secrets = [0, 1, 2].map(|_| PartySecret::generate());
+        let keys = secrets.each_ref().map(PartySecret::public_key);
+        let order = Order {
+            o_type: true,
+            symbol: "MSFT".into(),
+            quantity: 100,
+            price: Decimal::from(150),
+            min_execution: 50,
+            client_key: None,
+        };
+        let sealed = SealedOrder::seal(&order, &keys, &Validator::default()).unwrap();
+        let book_order = BookOrder {
+            id: 4,
+            trader: None,
+            order: sealed.clone(),
+            timestamp: Utc::now(),
+            auth: None,
+        };
+
+        let [[p0], [p1], [p2]] =
+            open_all_shares(&secrets, std::slice::from_ref(&book_order)).unwrap();
+        assert_eq!(p0[0].order_id, 4);
+        assert_eq!(reconstruct([&p0[0].price, &p1[0].price, &p2[0].price]), 150);
+        assert_eq!(
+            reconstruct([&p0[0].quantity, &p1[0].quantity, &p2[0].quantity]),
+            100
+        );
+        assert_eq!(
+            reconstruct([
+                &p0[0].min_execution,
+                &p1[0].min_execution,
+                &p2[0].min_execution
+            ]),
+            50
+        );
+        // One share alone says nothing about the price.
+        assert!(sealed.open(0, &secrets[1]).is_none());
+
+        let mut moved = sealed;
+        moved.symbol = "AAPL".into();
+        assert!(moved.open(0, &secrets[0]).is_none());
+
+        let invalid = Order {
+            min_execution: 101,
+            ..order
+        };
+        assert_eq!(
+            SealedOrder::seal(&invalid, &keys, &Validator::default()).unwrap_err(),
+            RejectCode::MinExecutionAboveQuantity
+        );
+    }
+
+    #[test]
+    fn test_share_payload_is_three_words() {
+        let secrets = [0, 1, 2].map(|_| PartySecret::generate());
+        let keys = secrets.each_ref().map(PartySecret::public_key);
+        let order = Order {
+            o_type: false,
+            symbol: "MSFT".into(),
+            quantity: 1000,
+            price: Decimal::from(300),
+            min_execution: 1,
+            client_key: None,
+        };
+        let sealed = SealedOrder::seal(&order, &keys, &Validator::default()).unwrap();
+        assert!(serde_json::to_string(&sealed).unwrap().len() < 1024);
+
+        let payload = sealed.open(2, &secrets[2]).unwrap();
+        let bytes = payload.to_bytes();
+        assert_eq!(bytes.len(), 1 + 3 * 8);
+        assert_eq!(OrderSharePayload::from_bytes(&bytes), Some(payload));
+        assert_eq!(
+            OrderSharePayload::from_bytes(&bytes[..bytes.len() - 1]),
+            None
+        );
+        let mut other_version = bytes;
+        other_version[0] = 1;
+        assert_eq!(OrderSharePayload::from_bytes(&other_version), None);
+    }
+
+    #[test]
+    fn test_undecryptable_share_rejects_the_order() {
+        let secrets = [0, 1, 2].map(|_| PartySecret::generate());
+        let keys = secrets.each_ref().map(PartySecret::public_key);
+        let mut other_keys = keys;
+        other_keys[1] = PartySecret::generate().public_key();
+        let order = Order {
+            o_type: false,
+            symbol: "MSFT".into(),
+            quantity: 10,
+            price: Decimal::from(20),
+            min_execution: 1,
+            client_key: None,
+        };
+        let orders: Vec<BookOrder> = [(1, &keys), (2, &other_keys), (3, &keys)]
+            .into_iter()
+            .map(|(id, keys)| BookOrder {
+                id,
+                trader: None,
+                order: SealedOrder::seal(&order, keys, &Validator::default()).unwrap(),
+                timestamp: Utc::now(),
+                auth: None,
+            })
+            .collect();
+
+        assert_eq!(open_order_shares(0, &secrets[0], &orders).unwrap().len(), 3);
+        assert_eq!(
+            open_order_shares(1, &secrets[1], &orders).unwrap_err(),
+            UndecryptableShares {
+                party: 1,
+                orders: vec![2],
+            }
+        );
+        assert!(open_all_shares(&secrets, &orders).is_err());
+    }
+}
@@ -0,0 +1,308 @@
+//! Long-lived party sessions, one batch per epoch.
+//!
+//! Each party runs in its own task that connects to its relay once,
+//! establishes a `v1::Session` and then matches every batch it is handed
+//! over that session, re-keying it every `rekey_epochs` epochs. A party whose
+//! epoch fails ends its task; the backend then drops the session at all three
+//! parties and sets up new ones for its next attempt.
+//!
+//! Between batches the backend has the parties preprocess AND triples for
+//! the next one (see `v1::run_preprocess_v1`). Every command goes through one
+//! queue per party, so all three run the same steps in the same order.
+//!
+//! The runners live in the party processes (see `party`); the backend drives
+//! them over control connections through `RemoteRunner`, which has the same
+//! interface.
+
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use sl_mpc_mate::coord::Relay;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::ClientConfig;
+use tracing::{info, warn};
+
+use crate::capture::{CaptureRecord, CaptureWriter};
+use crate::evidence::{Evidence, Witness};
+use crate::timeout_relay::TimeoutRelay;
+use crate::types::{BookOrder, OrderShare};
+use crate::v1::{Abort, PartyOutput, Session, rekey_instance, setup_party_v1};
+use crate::validate::BitWidths;
+use crate::websocket_relay::WebSocketRelay;
+
+/// Why a party produced no output for an epoch, or failed to preprocess.
+#[derive(Debug)]
+pub enum EpochError {
+    Aborted(Box<Abort>),
+    /// A remote party failed, with the evidence it signed if its epoch
+    /// failed verification.
+    Failed {
+        error: String,
+        evidence: Option<Box<Evidence>>,
+    },
+    /// The party's task is gone: its session failed before, or it panicked.
+    Ended,
+}
+
+impl std::fmt::Display for EpochError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EpochError::Aborted(abort) => write!(f, "{}", abort),
+            EpochError::Failed { error, .. } => write!(f, "{}", error),
+            EpochError::Ended => write!(f, "party session ended"),
+        }
+    }
+}
+
+impl std::error::Error for EpochError {}
+
+enum Command {
+    Epoch {
+        instance: [u8; 32],
+        orders: Vec<BookOrder>,
+        share: Vec<OrderShare>,
+        reply: oneshot::Sender<Result<PartyOutput, Abort>>,
+    },
+    Preprocess {
+        triples: usize,
+        reply: oneshot::Sender<Result<(), Abort>>,
+    },
+}
+
+impl Command {
+    fn fail(self, abort: Abort) {
+        match self {
+            Command::Epoch { reply, .. } => {
+                let _ = reply.send(Err(abort));
+            }
+            Command::Preprocess { reply, .. } => {
+                let _ = reply.send(Err(abort));
+            }
+        }
+    }
+}
+
+/// Where and how a party runs its session.
+pub struct PartyOptions {
+    pub party: usize,
+    /// Connection ID of the party's records in the capture, unique among
+    /// the sessions sharing it.
+    pub conn: u64,
+    /// Instance the session is established under.
+    pub instance: [u8; 32],
+    pub url: String,
+    pub tls: Option<Arc<ClientConfig>>,
+    pub capture: Option<CaptureWriter>,
+    /// Signs the party's evidence when an epoch fails verification.
+    pub witness: Witness,
+    /// How long a receive waits for a frame (see `timeout_relay`).
+    pub recv_timeout: Duration,
+    /// Epochs between re-keys; never re-keyed when zero.
+    pub rekey_epochs: u64,
+    /// Seed of the party's preprocessing randomness, secret to the party.
+    pub preprocessing_seed: [u8; 32],
+    /// Widths every epoch's orders are range-checked against.
+    pub widths: BitWidths,
+    /// Smallest quantity the range check lets through (see `instruments`).
+    pub min_quantity: u64,
+}
+
+/// Handle of one party's session task. Dropping it ends the session.
+pub struct PartyRunner {
+    commands: mpsc::UnboundedSender<Command>,
+    /// AND triples the party has in stock.
+    stock: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for PartyRunner {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl PartyRunner {
+    pub fn spawn(options: PartyOptions) -> Self {
+        let (commands, requests) = mpsc::unbounded_channel();
+        let stock = Arc::new(AtomicUsize::new(0));
+        let gauge = stock.clone();
+        let task = tokio::spawn(async move {
+            // Reconnect loop until WebSocketRelay is ready
+            let relay = loop {
+                match WebSocketRelay::connect_with_tls(&options.url, options.tls.clone()).await {
+                    Ok(r) => break r,
+                    Err(_) => {
+                        warn!(
+                            "Party {}: WS connect to {} failed; retrying in 2s…",
+                            options.party, options.url,
+                        );
+                        tokio::time::sleep(Duration::from_secs(2)).await;
+                    }
+                }
+            };
+            let timeout = options.recv_timeout;
+            match options.capture.clone() {
+                Some(capture) => {
+                    let relay = TimeoutRelay::new(relay.record(capture, options.conn), timeout);
+                    run_party(options, relay, requests, gauge).await
+                }
+                None => {
+                    let relay = TimeoutRelay::new(relay, timeout);
+                    run_party(options, relay, requests, gauge).await
+                }
+            }
+        });
+        PartyRunner {
+            commands,
+            stock,
+            task,
+        }
+    }
+
+    /// AND triples left after the party's last finished command.
+    pub fn stock(&self) -> usize {
+        self.stock.load(Ordering::Relaxed)
+    }
+
+    /// Preprocess `triples` more AND triples. The command is queued before
+    /// this returns, so dropping the future does not take it back: the
+    /// party still runs it before any later command.
+    pub fn preprocess(&self, triples: usize) -> impl Future<Output = Result<(), EpochError>> {
+        let (reply, result) = oneshot::channel();
+        let sent = self.commands.send(Command::Preprocess { triples, reply });
+        async move {
+            sent.map_err(|_| EpochError::Ended)?;
+            result
+                .await
+                .map_err(|_| EpochError::Ended)?
+                .map_err(|abort| EpochError::Aborted(Box::new(abort)))
+        }
+    }
+
+    /// Match one batch over the party's session. `instance` identifies the
+    /// epoch in evidence and captures.
+    pub async fn run_epoch(
+        &self,
+        instance: [u8; 32],
+        orders: Vec<BookOrder>,
+        share: Vec<OrderShare>,
+    ) -> Result<PartyOutput, EpochError> {
+        let (reply, result) = oneshot::channel();
+        let command = Command::Epoch {
+            instance,
+            orders,
+            share,
+            reply,
+        };
+        self.commands.send(command).map_err(|_| EpochError::Ended)?;
+        result
+            .await
+            .map_err(|_| EpochError::Ended)?
+            .map_err(|abort| EpochError::Aborted(Box::new(abort)))
+    }
+}
+
+async fn run_party<R: Relay>(
+    options: PartyOptions,
+    relay: R,
+    mut requests: mpsc::UnboundedReceiver<Command>,
+    stock: Arc<AtomicUsize>,
+) {
+    let PartyOptions {
+        party,
+        conn,
+        instance,
+        capture,
+        witness,
+        rekey_epochs,
+        preprocessing_seed,
+        widths,
+        min_quantity,
+        ..
+    } = options;
+    let mut rng = ChaCha20Rng::from_seed(preprocessing_seed);
+    let write = |record: CaptureRecord| {
+        if let Some(capture) = &capture {
+            capture.write(&record).expect("Failed to write capture");
+        }
+    };
+
+    let fresh = rand::random();
+    write(CaptureRecord::Session {
+        conn,
+        instance,
+        party,
+        fresh,
+        orders: vec![],
+        widths,
+        min_quantity,
+    });
+    let (setup, seed) = setup_party_v1(instance, party, fresh);
+    let mut session = match Session::establish(setup, seed, relay).await {
+        Ok(session) => session.with_widths(widths).with_min_quantity(min_quantity),
+        Err(abort) => {
+            if let Some(command) = requests.recv().await {
+                command.fail(abort);
+            }
+            return;
+        }
+    };
+    info!("Party {}: session established", party);
+
+    let mut generation = 0;
+    while let Some(command) = requests.recv().await {
+        let failed = match command {
+            Command::Preprocess { triples, reply } => {
+                write(CaptureRecord::Preprocess { conn, triples });
+                let result = session.preprocess(triples, &mut rng).await;
+                let failed = result.is_err();
+                stock.store(session.stock(), Ordering::Relaxed);
+                let _ = reply.send(result);
+                failed
+            }
+            Command::Epoch {
+                instance: epoch,
+                orders,
+                share,
+                reply,
+            } => {
+                if rekey_epochs > 0 && session.epochs() >= rekey_epochs {
+                    generation += 1;
+                    let instance = rekey_instance(&instance, generation);
+                    let fresh = rand::random();
+                    write(CaptureRecord::Rekey {
+                        conn,
+                        instance,
+                        fresh,
+                    });
+                    let (setup, seed) = setup_party_v1(instance, party, fresh);
+                    if let Err(abort) = session.rekey(setup, seed).await {
+                        let _ = reply.send(Err(abort));
+                        return;
+                    }
+                    info!("Party {}: session re-keyed ({})", party, generation);
+                }
+
+                write(CaptureRecord::Epoch {
+                    conn,
+                    instance: epoch,
+                    orders,
+                });
+                let witness = witness.for_instance(epoch);
+                let result = session.run_epoch(&share, &witness).await;
+                let failed = result.is_err();
+                stock.store(session.stock(), Ordering::Relaxed);
+                let _ = reply.send(result);
+                failed
+            }
+        };
+        if failed {
+            return;
+        }
+    }
+    session.close().await;
+}
@@ -0,0 +1,133 @@
+use bytes::Bytes;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use sl_mpc_mate::coord::{MessageSendError, Relay};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{ClientConfig, pki_types::ServerName};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// Upper bound on a single frame accepted by the length-delimited codec.
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Wrap a byte stream in the framing shared by `SocketRelay` and the relay
+/// server: a 4-byte big-endian length prefix followed by the frame payload.
+pub fn framed<T>(io: T) -> Framed<T, LengthDelimitedCodec>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    framed_with_limit(io, DEFAULT_MAX_FRAME_LENGTH)
+}
+
+/// Like `framed`, rejecting any frame longer than `max_frame_length` as
+/// soon as its length prefix is read.
+pub fn framed_with_limit<T>(io: T, max_frame_length: usize) -> Framed<T, LengthDelimitedCodec>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    LengthDelimitedCodec::builder()
+        .max_frame_length(max_frame_length)
+        .new_framed(io)
+}
+
+/// A `Relay` over a plain stream socket (TCP or Unix domain) using
+/// length-prefixed frames instead of WebSocket messages.
+pub struct SocketRelay {
+    sink: Pin<Box<dyn Sink<Vec<u8>, Error = MessageSendError> + Send>>,
+    stream: Pin<Box<dyn Stream<Item = Result<Vec<u8>, MessageSendError>> + Send>>,
+}
+impl SocketRelay {
+    /// Build a relay on top of an already connected stream.
+    pub fn new<T>(io: T) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (frame_sink, frame_stream) = framed(io).split();
+        let send_sink = frame_sink
+            .with(|frame: Vec<u8>| async move { Ok::<_, std::io::Error>(Bytes::from(frame)) })
+            .sink_map_err(|_| MessageSendError);
+        let recv_stream = frame_stream
+            .map(|frame| {
+                frame
+                    .map(|bytes| bytes.to_vec())
+                    .map_err(|_| MessageSendError)
+            })
+            .boxed();
+        SocketRelay {
+            sink: Box::pin(send_sink),
+            stream: Box::pin(recv_stream),
+        }
+    }
+
+    /// Connect to a relay server's TCP listener.
+    pub async fn connect_tcp<A: ToSocketAddrs>(addr: A) -> Result<Self, MessageSendError> {
+        Ok(Self::new(tcp_connect(addr).await?))
+    }
+
+    /// Connect to a relay server's TCP listener over TLS. The server must
+    /// present a certificate for `server_name` that chains to a root of `tls`.
+    pub async fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        tls: Arc<ClientConfig>,
+    ) -> Result<Self, MessageSendError> {
+        let server_name =
+            ServerName::try_from(server_name.to_owned()).map_err(|_| MessageSendError)?;
+        let stream = TlsConnector::from(tls)
+            .connect(server_name, tcp_connect(addr).await?)
+            .await
+            .map_err(|_| MessageSendError)?;
+        Ok(Self::new(stream))
+    }
+
+    /// Connect to a relay server's Unix domain socket.
+    #[cfg(unix)]
+    pub async fn connect_unix<P: AsRef<std::path::Path>>(
+        path: P,
+    ) -> Result<Self, MessageSendError> {
+        let stream = tokio::net::UnixStream::connect(path)
+            .await
+            .map_err(|_| MessageSendError)?;
+        Ok(Self::new(stream))
+    }
+}
+async fn tcp_connect<A: ToSocketAddrs>(addr: A) -> Result<TcpStream, MessageSendError> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(|_| MessageSendError)?;
+    // MPC rounds are latency bound; never wait to coalesce small frames.
+    stream.set_nodelay(true).map_err(|_| MessageSendError)?;
+    Ok(stream)
+}
+
+impl Sink<Vec<u8>> for SocketRelay {
+    type Error = MessageSendError;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().sink.as_mut().poll_ready(cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        self.get_mut().sink.as_mut().start_send(item)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().sink.as_mut().poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().sink.as_mut().poll_close(cx)
+    }
+}
+impl Stream for SocketRelay {
+    type Item = Vec<u8>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.get_mut().stream.as_mut().poll_next(cx) {
+            Poll::Ready(Some(Ok(vec))) => Poll::Ready(Some(vec)),
+            // treat Err(_) as end‐of‐stream
+            Poll::Ready(Some(Err(_))) => Poll::Ready(None),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+impl Relay for SocketRelay {}
@@ -0,0 +1,151 @@
+//! Retrying failed batches.
+//!
+//! An MPC run aborts as a whole when any party fails, at any step from
+//! initialization to `run_verify`, and may also hang on a stuck relay. The
+//! supervisor bounds every attempt with a timeout and re-runs the same orders
+//! under a fresh instance ID, and so fresh seeds, up to a retry limit. Each
+//! attempt is recorded with its cause of failure.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+/// How often and how long a batch is attempted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Attempts per batch, the first included.
+    pub max_attempts: u32,
+    /// Seconds an attempt may take before it is abandoned.
+    pub attempt_timeout_secs: u64,
+    /// Seconds to wait between attempts.
+    pub backoff_secs: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            attempt_timeout_secs: 300,
+            backoff_secs: 2,
+        }
+    }
+}
+
+/// One run of the MPC protocol on a batch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Attempt {
+    /// Instance ID of the run, hex.
+    pub instance: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// Why the attempt failed; `None` if it succeeded.
+    pub error: Option<String>,
+}
+
+/// Run `attempt` with a fresh random instance ID until it succeeds or
+/// `policy` gives up. `record` sees every attempt as it finishes. Returns the
+/// result of the last attempt.
+pub async fn supervise<T, F, Fut>(
+    policy: &RetryPolicy,
+    mut attempt: F,
+    mut record: impl FnMut(Attempt),
+) -> anyhow::Result<T>
+where
+    F: FnMut([u8; 32]) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let timeout = Duration::from_secs(policy.attempt_timeout_secs);
+    let mut n = 1;
+    loop {
+        let instance: [u8; 32] = rand::random();
+        let started_at = Utc::now();
+        let result = match tokio::time::timeout(timeout, attempt(instance)).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!(
+                "timed out after {} seconds",
+                timeout.as_secs()
+            )),
+        };
+        record(Attempt {
+            instance: hex::encode(instance),
+            started_at,
+            finished_at: Utc::now(),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        });
+        match result {
+            Err(e) if n < policy.max_attempts => {
+                warn!(
+                    "Attempt {} of {} failed: {:#}; retrying with a new instance",
+                    n, policy.max_attempts, e
+                );
+                tokio::time::sleep(Duration::from_secs(policy.backoff_secs)).await;
+                n += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::future::pending;
+
+    use crate::supervisor::{RetryPolicy, supervise};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_with_fresh_instances_until_the_limit() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            attempt_timeout_secs: 10,
+            backoff_secs: 1,
+        };
+        let mut attempts = vec![];
+        let mut calls = 0;
+        let result = supervise(
+            &policy,
+            |_| {
+                calls += 1;
+                let call = calls;
+                async move {
+                    match call {
+                        1 => anyhow::bail!("abort in run_verify"),
+                        2 => pending().await,
+                        _ => Ok(call),
+                    }
+                }
+            },
+            |attempt| attempts.push(attempt),
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), 3);
+        let errors: Vec<_> = attempts.iter().map(|a| a.error.as_deref()).collect();
+        assert_eq!(
+            errors,
+            [
+                Some("abort in run_verify"),
+                Some("timed out after 10 seconds"),
+                None
+            ]
+        );
+        let instances: HashSet<_> = attempts.iter().map(|a| &a.instance).collect();
+        assert_eq!(instances.len(), 3);
+
+        let mut failures = 0;
+        let result: anyhow::Result<()> = supervise(
+            &RetryPolicy {
+                max_attempts: 2,
+                ..policy
+            },
+            |_| async { anyhow::bail!("relay unreachable") },
+            |_| failures += 1,
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(failures, 2);
+    }
+}
@@ -0,0 +1,103 @@
+use futures_util::{Sink, Stream};
+use sl_mpc_mate::coord::{MessageSendError, Relay};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Sleep, sleep};
+use tracing::warn;
+
+/// How long a party waits for the next frame by default before it gives up
+/// on the step it is in.
+pub const DEFAULT_RECV_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wraps any `Relay` and ends its stream once a receive has waited longer
+/// than `timeout` for a frame, so the protocol step waiting on a lost frame
+/// fails with a `ProtocolError` instead of hanging. The clock only runs
+/// while someone is waiting: an idle session between epochs never times out.
+/// After a timeout the relay stays ended.
+pub struct TimeoutRelay<R> {
+    inner: R,
+    timeout: Duration,
+    /// When the receive in progress gives up; unset between frames.
+    deadline: Option<Pin<Box<Sleep>>>,
+    expired: bool,
+}
+
+impl<R: Relay> TimeoutRelay<R> {
+    pub fn new(inner: R, timeout: Duration) -> Self {
+        TimeoutRelay {
+            inner,
+            timeout,
+            deadline: None,
+            expired: false,
+        }
+    }
+}
+
+impl<R: Relay> Sink<Vec<u8>> for TimeoutRelay<R> {
+    type Error = MessageSendError;
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        Pin::new(&mut self.get_mut().inner).start_send(item)
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+impl<R: Relay> Stream for TimeoutRelay<R> {
+    type Item = Vec<u8>;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.expired {
+            return Poll::Ready(None);
+        }
+        if let Poll::Ready(next) = Pin::new(&mut this.inner).poll_next(cx) {
+            this.deadline = None;
+            return Poll::Ready(next);
+        }
+        let timeout = this.timeout;
+        let deadline = this
+            .deadline
+            .get_or_insert_with(|| Box::pin(sleep(timeout)));
+        if deadline.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        warn!("No frame received for {:?}; closing the relay", timeout);
+        this.expired = true;
+        this.deadline = None;
+        Poll::Ready(None)
+    }
+}
+impl<R: Relay> Relay for TimeoutRelay<R> {}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use std::time::Duration;
+
+    use crate::socket_relay::SocketRelay;
+    use crate::timeout_relay::TimeoutRelay;
+
+    #[tokio::test]
+    async fn test_receive_times_out_only_while_waiting() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let mut sender = SocketRelay::new(a);
+        let mut receiver = TimeoutRelay::new(SocketRelay::new(b), Duration::from_millis(100));
+
+        // Idle time before a receive starts does not count.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        sender.send(vec![1]).await.unwrap();
+        assert_eq!(receiver.next().await, Some(vec![1]));
+
+        // A frame that never comes ends the stream, and it stays ended.
+        assert_eq!(receiver.next().await, None);
+        sender.send(vec![2]).await.unwrap();
+        assert_eq!(receiver.next().await, None);
+    }
+}
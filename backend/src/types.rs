@@ -1,8 +1,49 @@
 use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 use sl_compute::types::ArithmeticShare;
 
+use crate::fill::ClientKey;
+use crate::keystore::OrderAuth;
+use crate::sealed_order::SealedOrder;
+
+/// An order in the clear, as the trader enters it. Never leaves the
+/// trader: it is sealed to the parties (see `SealedOrder`) before submission.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Order {
+    pub o_type: bool, // false for buy, true for sell
+    pub symbol: String,
+    pub quantity: u64,
+    /// Limit price, sealed as whole ticks of the symbol's `PriceScale`.
+    pub price: Decimal,
+    pub min_execution: u64,
+    /// Where the trader wants its fills delivered. Orders without a key
+    /// still match, but their owner cannot learn the trade terms.
+    #[serde(default)]
+    pub client_key: Option<ClientKey>,
+}
+
+/// An order accepted by the intake, stamped with its arrival time.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BookOrder {
+    /// Assigned by the backend when the order is accepted.
+    pub id: u64,
+    /// The authenticated trader who placed the order, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trader: Option<String>,
+    #[serde(flatten)]
+    pub order: SealedOrder,
+    pub timestamp: DateTime<Utc>,
+    /// The trader's signature over the order, if it came in signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth: Option<OrderAuth>,
+}
+
 #[derive(Clone, Debug)]
 pub struct OrderShare {
+    /// `BookOrder::id` of the order this is a share of.
+    pub order_id: u64,
+
     /// 0/false for buy and 1/true for sell
     pub o_type: bool,
 
@@ -15,4 +56,6 @@ pub struct OrderShare {
     pub min_execution: ArithmeticShare,
 
     pub timestamp: DateTime<Utc>,
+
+    pub client_key: Option<ClientKey>,
 }
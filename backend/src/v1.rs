@@ -1,3 +1,5 @@
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 use sl_compute::{
     comparison::compare_ge::run_compare_ge,
     conversion::a_to_b::run_batch_arithmetic_to_boolean,
@@ -13,200 +15,1032 @@ use sl_compute::{
         types::ProtocolError,
         utils::{Seed, TagOffsetCounter},
     },
-    types::ServerState,
+    types::{ArithmeticShare, BinaryShare, FieldElement, ServerState},
 };
 use sl_mpc_mate::coord::Relay;
+use std::collections::VecDeque;
 
+use crate::evidence::{self, Evidence, TranscriptRelay, Witness};
+use crate::fill::{FillShare, share_from_words};
 use crate::types::OrderShare;
+use crate::validate::{BitWidths, FRACTION_BITS};
+
+/// Orders that went through `run_range_check_v1`, still secret-shared.
+pub struct RangeChecked {
+    /// Whether each order passed.
+    pub in_range: Vec<BinaryShare>,
+    /// Each order's terms as boolean shares of their bits. Only meaningful
+    /// for orders that passed.
+    pub bits: Vec<OrderBits>,
+}
 
-pub async fn run_handle_orders_v1<T, R>(
+/// An order's terms as boolean shares of their integer bits, lowest first,
+/// `BitWidths::quantity_integer_bits` of the quantity and minimum execution
+/// and `BitWidths::price_integer_bits` of the price.
+pub struct OrderBits {
+    pub quantity: Vec<BinaryShare>,
+    pub price: Vec<BinaryShare>,
+    pub min_execution: Vec<BinaryShare>,
+}
+
+/// Check in MPC that every order's shares encode values the clear-text
+/// validation would have accepted under `widths` and the instrument's
+/// `min_quantity` (at least 1): `min_quantity <= quantity <= max_quantity`,
+/// `1 <= price <= max_price` and `0 <= min_execution <= quantity`.
+/// Quantities are sealed in lots and prices in ticks, so any values in
+/// range are whole lots at whole ticks.
+///
+/// Every term goes through one batched `run_batch_arithmetic_to_boolean`,
+/// and its bits are read off the result: the integer bits of its width are
+/// the bits matching compares the orders and selects the executed quantity
+/// on, and every other bit must be clear, the fraction bits for a whole
+/// number and the bits above the width for a value that fits it and is not
+/// negative. A share with a fractional part or past the sign bit therefore
+/// fails here instead of slipping through on bits that disagree with it.
+/// The bounds are then checked on the integer bits with
+/// `run_compare_bits_v1`, and all checks of an order are folded together
+/// with `run_and_all_v1`, every order at once and without a single
+/// `run_compare_ge`. Only the conversion runs at the full field width,
+/// since sl_compute's `run_batch_arithmetic_to_boolean` takes no width.
+///
+/// Orders shared by the backend pass by construction; this catches shares
+/// supplied by a client, which could otherwise encode a value that wraps
+/// around the comparisons or does not fit the declared widths. Nothing is
+/// opened: whether each order passed is opened with the matches once the
+/// batch is verified. ANDs take triples from `pool` first.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_range_check_v1<T, R>(
     setup: &T,
     mpc_encryption: &mut MPCEncryption,
     tag_offset_counter: &mut TagOffsetCounter,
     relay: &mut FilteredMsgRelay<R>,
     orders: &[OrderShare],
+    widths: &BitWidths,
+    min_quantity: u64,
+    pool: &mut TriplePool,
     serverstate: &mut ServerState,
-) -> Result<Vec<(OrderShare, OrderShare)>, ProtocolError>
+) -> Result<RangeChecked, ProtocolError>
 where
     T: CommonSetupMessage,
     R: Relay,
 {
-    let mut queue_buy: Vec<OrderShare> = Vec::new();
-    let mut queue_sell = Vec::new();
+    if orders.is_empty() {
+        return Ok(RangeChecked {
+            in_range: Vec::new(),
+            bits: Vec::new(),
+        });
+    }
 
-    for i in orders {
-        if i.o_type {
-            queue_sell.push(Some(i.clone()));
-        } else {
-            queue_buy.push(i.clone());
-        }
+    let party = setup.participant_index();
+    let mut values = vec![ArithmeticShare::from_constant(
+        &FieldElement::from(1u64),
+        party,
+    )];
+    values.extend(
+        orders
+            .iter()
+            .flat_map(|order| [order.quantity, order.price, order.min_execution]),
+    );
+    let words = run_batch_arithmetic_to_boolean(
+        setup,
+        mpc_encryption,
+        tag_offset_counter,
+        relay,
+        &values,
+        serverstate,
+    )
+    .await?;
+    // Comparisons on bits need a sharing of the constant one bit: the lowest
+    // integer bit of the constant one.
+    let one = *pool
+        .one
+        .get_or_insert_with(|| words[0].get_binary_share(FRACTION_BITS as usize));
+
+    let (quantity_width, price_width) =
+        (widths.quantity_integer_bits(), widths.price_integer_bits());
+    let mut checks = Vec::with_capacity(orders.len());
+    let mut order_bits = Vec::with_capacity(orders.len());
+    for terms in words[1..].chunks(3) {
+        let mut ok = Vec::with_capacity(3 * WORD_BITS + 3);
+        let [quantity, price, min_execution] = [
+            (&terms[0], quantity_width),
+            (&terms[1], price_width),
+            (&terms[2], quantity_width),
+        ]
+        .map(|(word, width)| {
+            let integer = FRACTION_BITS as usize..FRACTION_BITS as usize + width;
+            ok.extend(
+                (0..WORD_BITS)
+                    .filter(|k| !integer.contains(k))
+                    .map(|k| xor_shares(&one, &word.get_binary_share(k))),
+            );
+            integer
+                .map(|k| word.get_binary_share(k))
+                .collect::<Vec<_>>()
+        });
+        checks.push(ok);
+        order_bits.push(OrderBits {
+            quantity,
+            price,
+            min_execution,
+        });
     }
 
-    let mut result = Vec::new();
+    // The bounds, on the bits at the declared widths. Terms that fit their
+    // width are at most its maximum and at least zero by construction.
+    let zero = xor_shares(&one, &one);
+    let public = |value: u64, width: usize| -> Vec<BinaryShare> {
+        (0..width)
+            .map(|k| if (value >> k) & 1 == 1 { one } else { zero })
+            .collect()
+    };
+    let (min_quantity, min_price) = (public(min_quantity, quantity_width), public(1, price_width));
+    let bounds: Vec<_> = order_bits
+        .iter()
+        .flat_map(|order: &OrderBits| {
+            [
+                (&order.quantity[..], &min_quantity[..]),
+                (&order.price[..], &min_price[..]),
+                (&order.quantity[..], &order.min_execution[..]),
+            ]
+        })
+        .collect();
+    let bounds = run_compare_bits_v1(
+        setup,
+        mpc_encryption,
+        tag_offset_counter,
+        relay,
+        &bounds,
+        pool,
+        serverstate,
+    )
+    .await?;
+    for (ok, bounds) in checks.iter_mut().zip(bounds.chunks(3)) {
+        ok.extend_from_slice(bounds);
+    }
+    let in_range = run_and_all_v1(
+        setup,
+        mpc_encryption,
+        tag_offset_counter,
+        relay,
+        checks,
+        pool,
+        serverstate,
+    )
+    .await?;
+    Ok(RangeChecked {
+        in_range,
+        bits: order_bits,
+    })
+}
 
-    for buy_order in queue_buy {
-        #[allow(clippy::needless_range_loop)]
-        for sell_id in 0..queue_sell.len() {
-            if let Some(sell_order) = &queue_sell[sell_id] {
-                let outputs = run_batch_arithmetic_to_boolean(
-                    setup,
-                    mpc_encryption,
-                    tag_offset_counter,
-                    relay,
-                    &[
-                        buy_order.price,
-                        sell_order.price,
-                        sell_order.quantity,
-                        buy_order.min_execution,
-                        buy_order.quantity,
-                        sell_order.min_execution,
-                    ],
-                    serverstate,
-                )
-                .await?;
+/// Whether each buy matched each sell, still secret-shared.
+///
+/// Pair `(i, j)` of `buys[i]` and `sells[j]` is at `i * sells.len() + j` in
+/// both vectors.
+pub struct MatchShares {
+    pub buys: Vec<OrderShare>,
+    pub sells: Vec<OrderShare>,
+    pub matched: Vec<BinaryShare>,
+    /// Bits of the executed quantity of each pair, the smaller of the two
+    /// order quantities, lowest first.
+    pub fill_quantity: Vec<Vec<BinaryShare>>,
+    /// Whether each pair would fill the buy and the sell in full, in that
+    /// order.
+    pub filled: Vec<[BinaryShare; 2]>,
+}
 
-                let comp1 = run_compare_ge(
-                    setup,
-                    mpc_encryption,
-                    tag_offset_counter,
-                    relay,
-                    &outputs[0],
-                    &outputs[1],
-                    serverstate,
-                )
-                .await?;
+/// A match opened after verification. Only the match itself is opened; its
+/// executed quantity and whether each side filled in full stay shared.
+#[derive(Clone, Debug)]
+pub struct OpenedMatch {
+    pub buy: OrderShare,
+    pub sell: OrderShare,
+    pub quantity: Vec<BinaryShare>,
+    pub filled: [BinaryShare; 2],
+}
 
-                let comp2 = run_compare_ge(
-                    setup,
-                    mpc_encryption,
-                    tag_offset_counter,
-                    relay,
-                    &outputs[2],
-                    &outputs[3],
-                    serverstate,
-                )
-                .await?;
+fn xor_shares(a: &BinaryShare, b: &BinaryShare) -> BinaryShare {
+    BinaryShare {
+        value1: a.value1 ^ b.value1,
+        value2: a.value2 ^ b.value2,
+    }
+}
 
-                let comp3 = run_compare_ge(
-                    setup,
-                    mpc_encryption,
-                    tag_offset_counter,
-                    relay,
-                    &outputs[4],
-                    &outputs[5],
-                    serverstate,
-                )
-                .await?;
+/// Bits of a boolean-shared field element.
+const WORD_BITS: usize = u64::BITS as usize;
 
-                let temp = run_and_binary_shares(
-                    setup,
-                    mpc_encryption,
-                    tag_offset_counter,
-                    relay,
-                    &comp1,
-                    &comp2,
-                    serverstate,
-                )
-                .await?;
+/// A triple of random shared bits with `c = a AND b`.
+#[derive(Clone, Copy, Debug)]
+pub struct AndTriple {
+    pub a: BinaryShare,
+    pub b: BinaryShare,
+    pub c: BinaryShare,
+}
+
+/// AND triples precomputed between batches (see `run_preprocess_v1`).
+///
+/// Every party consumes its triples in the same order, so the pools of the
+/// three parties stay in step.
+#[derive(Default)]
+pub struct TriplePool {
+    /// A sharing of the constant one bit, for adding public bits.
+    one: Option<BinaryShare>,
+    triples: VecDeque<AndTriple>,
+    /// Masked bits opened with triples since the parties last compared
+    /// them. `run_verify` does not cover these openings, so the epoch
+    /// compares them with `run_check_view_v1` before opening anything else.
+    view: OpenedView,
+}
+
+impl TriplePool {
+    pub fn len(&self) -> usize {
+        self.triples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triples.is_empty()
+    }
+}
+
+/// AND triples matching a batch of `orders` orders takes, for an even split
+/// into buys and sells: those folding each order's range checks together
+/// and one per bit of its three bound comparisons, which come to one per
+/// bit of its three terms' words and two more whatever the widths, then per buy/sell pair
+/// one per bit of its five comparisons and of its executed quantity, and
+/// four more.
+pub fn triples_for_orders(orders: usize, widths: &BitWidths) -> usize {
+    let (quantity, price) = (widths.quantity_integer_bits(), widths.price_integer_bits());
+    let buys = orders / 2;
+    orders * (3 * WORD_BITS + 2) + (4 + 5 * quantity + price) * buys * (orders - buys)
+}
+
+/// Precompute `count` AND triples into `pool`, before the orders they will
+/// be used on are known.
+///
+/// Random bits come from comparing two random sharings that each party
+/// contributes a share drawn from `rng` to, so no party knows them; `c` is
+/// computed with `run_and_binary_shares`, and is checked by the next
+/// `run_verify`. The range check and matching compare orders with these
+/// triples rather than `run_compare_ge` (see `run_compare_bits_v1`), so
+/// their comparisons are preprocessed too and nothing online runs
+/// `run_compare_ge`.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_preprocess_v1<T, R>(
+    setup: &T,
+    mpc_encryption: &mut MPCEncryption,
+    tag_offset_counter: &mut TagOffsetCounter,
+    relay: &mut FilteredMsgRelay<R>,
+    pool: &mut TriplePool,
+    count: usize,
+    rng: &mut impl RngCore,
+    serverstate: &mut ServerState,
+) -> Result<(), ProtocolError>
+where
+    T: CommonSetupMessage,
+    R: Relay,
+{
+    let party = setup.participant_index();
+    let constant = |value: u64| ArithmeticShare::from_constant(&FieldElement::from(value), party);
+    let mut values = vec![constant(0), constant(1)];
+    values.extend((0..4 * count).map(|_| {
+        let x = rng.next_u64();
+        share_from_words([x, x])
+    }));
+    let bits = run_batch_arithmetic_to_boolean(
+        setup,
+        mpc_encryption,
+        tag_offset_counter,
+        relay,
+        &values,
+        serverstate,
+    )
+    .await?;
+
+    let mut compare = async |a, b| {
+        run_compare_ge(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            a,
+            b,
+            serverstate,
+        )
+        .await
+    };
+    if pool.one.is_none() {
+        pool.one = Some(compare(&bits[1], &bits[0]).await?);
+    }
+    let mut random = Vec::with_capacity(2 * count);
+    for pair in bits[2..].chunks(2) {
+        random.push(compare(&pair[0], &pair[1]).await?);
+    }
+    for ab in random.chunks(2) {
+        let c = run_and_binary_shares(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            &ab[0],
+            &ab[1],
+            serverstate,
+        )
+        .await?;
+        pool.triples.push_back(AndTriple {
+            a: ab[0],
+            b: ab[1],
+            c,
+        });
+    }
+    Ok(())
+}
 
-                let compres = run_and_binary_shares(
+/// AND every pair of bits in one round. Pairs are multiplied with triples
+/// from `pool` while they last, opening only the masked inputs `x ^ a` and
+/// `y ^ b`, which reveal nothing; the rest go through
+/// `run_and_binary_shares`.
+async fn run_and_layer_v1<T, R>(
+    setup: &T,
+    mpc_encryption: &mut MPCEncryption,
+    tag_offset_counter: &mut TagOffsetCounter,
+    relay: &mut FilteredMsgRelay<R>,
+    pairs: &[(BinaryShare, BinaryShare)],
+    pool: &mut TriplePool,
+    serverstate: &mut ServerState,
+) -> Result<Vec<BinaryShare>, ProtocolError>
+where
+    T: CommonSetupMessage,
+    R: Relay,
+{
+    let mut out = Vec::with_capacity(pairs.len());
+    let (precomputed, online) = match pool.one {
+        Some(one) => {
+            let n = pairs.len().min(pool.triples.len());
+            let triples: Vec<AndTriple> = pool.triples.drain(..n).collect();
+            let masked: Vec<BinaryShare> = pairs
+                .iter()
+                .zip(&triples)
+                .flat_map(|((x, y), t)| [xor_shares(x, &t.a), xor_shares(y, &t.b)])
+                .collect();
+            if n > 0 {
+                let opened = run_batch_open_binary_share(
                     setup,
                     mpc_encryption,
                     tag_offset_counter,
                     relay,
-                    &temp,
-                    &comp3,
+                    &masked,
                     serverstate,
                 )
                 .await?;
+                pool.view.record(&opened);
+                // x & y = c ^ (d & b) ^ (e & a) ^ (d & e) for d = x ^ a, e = y ^ b
+                for (t, de) in triples.iter().zip(opened.chunks(2)) {
+                    let mut z = t.c;
+                    if de[0] {
+                        z = xor_shares(&z, &t.b);
+                    }
+                    if de[1] {
+                        z = xor_shares(&z, &t.a);
+                    }
+                    if de[0] && de[1] {
+                        z = xor_shares(&z, &one);
+                    }
+                    out.push(z);
+                }
+            }
+            pairs.split_at(n)
+        }
+        None => pairs.split_at(0),
+    };
+    debug_assert_eq!(out.len(), precomputed.len());
+    for (x, y) in online {
+        out.push(
+            run_and_binary_shares(
+                setup,
+                mpc_encryption,
+                tag_offset_counter,
+                relay,
+                x,
+                y,
+                serverstate,
+            )
+            .await?,
+        );
+    }
+    Ok(out)
+}
 
-                let comp = run_batch_open_binary_share(
-                    setup,
-                    mpc_encryption,
-                    tag_offset_counter,
-                    relay,
-                    &[compres],
-                    serverstate,
-                )
-                .await?[0];
+/// AND together the bits of each group, all groups at once, halving every
+/// group per round.
+async fn run_and_all_v1<T, R>(
+    setup: &T,
+    mpc_encryption: &mut MPCEncryption,
+    tag_offset_counter: &mut TagOffsetCounter,
+    relay: &mut FilteredMsgRelay<R>,
+    mut groups: Vec<Vec<BinaryShare>>,
+    pool: &mut TriplePool,
+    serverstate: &mut ServerState,
+) -> Result<Vec<BinaryShare>, ProtocolError>
+where
+    T: CommonSetupMessage,
+    R: Relay,
+{
+    debug_assert!(groups.iter().all(|group| !group.is_empty()));
+    while groups.iter().any(|group| group.len() > 1) {
+        let layer: Vec<_> = groups
+            .iter()
+            .flat_map(|group| group.chunks_exact(2).map(|pair| (pair[0], pair[1])))
+            .collect();
+        let mut anded = run_and_layer_v1(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            &layer,
+            pool,
+            serverstate,
+        )
+        .await?
+        .into_iter();
+        for group in &mut groups {
+            let odd = (group.len() % 2 == 1).then(|| group[group.len() - 1]);
+            let halved: Vec<_> = anded.by_ref().take(group.len() / 2).chain(odd).collect();
+            *group = halved;
+        }
+    }
+    Ok(groups.into_iter().map(|group| group[0]).collect())
+}
 
-                if comp {
-                    result.push((buy_order, sell_order.clone()));
-                    queue_sell[sell_id] = None;
-                    break;
-                }
+/// Whether `a >= b` for each pair of bit vectors, lowest bit first, missing
+/// bits taken as zero. Every pair is compared at once, in one AND layer per
+/// bit of the widest, so the comparisons cost triples from `pool` and no
+/// randomness of their own.
+///
+/// Scanning up from the lowest bit, `ge` takes `a`'s bit where the two bits
+/// differ and keeps its value where they agree, `ge ^ ((a ^ b) & (a ^ ge))`,
+/// starting from 1 for equal values. Needs the pool's one bit, which
+/// `run_range_check_v1` shares.
+async fn run_compare_bits_v1<T, R>(
+    setup: &T,
+    mpc_encryption: &mut MPCEncryption,
+    tag_offset_counter: &mut TagOffsetCounter,
+    relay: &mut FilteredMsgRelay<R>,
+    pairs: &[(&[BinaryShare], &[BinaryShare])],
+    pool: &mut TriplePool,
+    serverstate: &mut ServerState,
+) -> Result<Vec<BinaryShare>, ProtocolError>
+where
+    T: CommonSetupMessage,
+    R: Relay,
+{
+    let one = pool.one.expect("run_range_check_v1 shares the one bit");
+    let zero = xor_shares(&one, &one);
+    let width = |(a, b): &(&[BinaryShare], &[BinaryShare])| a.len().max(b.len());
+    let mut ge = vec![one; pairs.len()];
+    for k in 0..pairs.iter().map(width).max().unwrap_or(0) {
+        let bit = |bits: &[BinaryShare]| bits.get(k).copied().unwrap_or(zero);
+        let active: Vec<usize> = (0..pairs.len()).filter(|&n| k < width(&pairs[n])).collect();
+        let layer: Vec<_> = active
+            .iter()
+            .map(|&n| {
+                let (a, b) = (bit(pairs[n].0), bit(pairs[n].1));
+                (xor_shares(&a, &b), xor_shares(&a, &ge[n]))
+            })
+            .collect();
+        let anded = run_and_layer_v1(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            &layer,
+            pool,
+            serverstate,
+        )
+        .await?;
+        for (n, flip) in active.into_iter().zip(anded) {
+            ge[n] = xor_shares(&ge[n], &flip);
+        }
+    }
+    Ok(ge)
+}
+
+/// Match every buy with the first sell after it in order that crosses it,
+/// without opening anything.
+///
+/// The greedy allocation is evaluated obliviously: every pair is compared,
+/// and a sell that is taken or a buy that is filled is cleared from the
+/// running availability bits by XOR with the pair's match bit, which implies
+/// both. Availability starts as the order's range-check bit, so orders that
+/// failed it never match. A pair only depends on the pairs before it in its
+/// buy's row and its sell's column, so the pairs of each anti-diagonal are
+/// decided together, two AND layers each. The executed quantity of every
+/// pair is selected bit by bit from the two quantities' bits by the
+/// buy/sell comparison, `buy ^ (buy_ge_sell & (buy ^ sell))`, so the
+/// comparison is never opened. Each side is filled in full when the other's
+/// quantity is at least its own; those bits stay shared too (see
+/// `PartyResult::filled`). Open the result with `run_open_matches_v1` only
+/// once `run_verify` has passed. ANDs take triples from `pool` first.
+///
+/// Orders are compared on the bits `run_range_check_v1` produced, at the
+/// widths of `BitWidths`, with `run_compare_bits_v1`.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_handle_orders_v1<T, R>(
+    setup: &T,
+    mpc_encryption: &mut MPCEncryption,
+    tag_offset_counter: &mut TagOffsetCounter,
+    relay: &mut FilteredMsgRelay<R>,
+    orders: &[OrderShare],
+    checked: &RangeChecked,
+    pool: &mut TriplePool,
+    serverstate: &mut ServerState,
+) -> Result<MatchShares, ProtocolError>
+where
+    T: CommonSetupMessage,
+    R: Relay,
+{
+    let mut buys = Vec::new();
+    let mut sells = Vec::new();
+    for ((order, in_range), bits) in orders.iter().zip(&checked.in_range).zip(&checked.bits) {
+        if order.o_type {
+            sells.push((order.clone(), *in_range, bits));
+        } else {
+            buys.push((order.clone(), *in_range, bits));
+        }
+    }
+
+    let mut matched = Vec::new();
+    let mut fill_quantity = Vec::with_capacity(buys.len() * sells.len());
+    let mut filled = Vec::with_capacity(buys.len() * sells.len());
+    if !buys.is_empty() && !sells.is_empty() {
+        // Prices cross, each quantity covers the other side's min_execution,
+        // and which quantity is the smaller, both ways, for every pair.
+        let mut pairs = Vec::with_capacity(5 * buys.len() * sells.len());
+        for (.., buy) in &buys {
+            for (.., sell) in &sells {
+                pairs.extend([
+                    (&buy.price[..], &sell.price[..]),
+                    (&sell.quantity[..], &buy.min_execution[..]),
+                    (&buy.quantity[..], &sell.min_execution[..]),
+                    (&buy.quantity[..], &sell.quantity[..]),
+                    (&sell.quantity[..], &buy.quantity[..]),
+                ]);
+            }
+        }
+        let comps = run_compare_bits_v1(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            &pairs,
+            pool,
+            serverstate,
+        )
+        .await?;
+        let mut conditions = Vec::with_capacity(buys.len() * sells.len());
+        let mut buy_ge_sell = Vec::with_capacity(buys.len() * sells.len());
+        for comp in comps.chunks(5) {
+            conditions.push(comp[..3].to_vec());
+            buy_ge_sell.push(comp[3]);
+            filled.push([comp[4], comp[3]]);
+        }
+
+        // Whether each pair crosses does not depend on the allocation, so it
+        // is computed for all pairs at once.
+        let crosses = run_and_all_v1(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            conditions,
+            pool,
+            serverstate,
+        )
+        .await?;
+
+        // So is the executed quantity of each pair, were it to match.
+        let mut layer = Vec::new();
+        let mut n = 0;
+        for (.., buy) in &buys {
+            for (.., sell) in &sells {
+                layer.extend(
+                    buy.quantity
+                        .iter()
+                        .zip(&sell.quantity)
+                        .map(|(b, s)| (buy_ge_sell[n], xor_shares(b, s))),
+                );
+                n += 1;
+            }
+        }
+        let mut selected = run_and_layer_v1(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            &layer,
+            pool,
+            serverstate,
+        )
+        .await?
+        .into_iter();
+        for (.., buy) in &buys {
+            for _ in &sells {
+                fill_quantity.push(
+                    buy.quantity
+                        .iter()
+                        .zip(selected.by_ref())
+                        .map(|(b, d)| xor_shares(b, &d))
+                        .collect(),
+                );
+            }
+        }
+
+        // Pair (i, j) matches if it crosses while sell j is still available
+        // and buy i still open, after the pairs before it in its column and
+        // row: those lie on earlier anti-diagonals i + j.
+        let columns = sells.len();
+        let mut available: Vec<BinaryShare> = sells.iter().map(|(_, ok, _)| *ok).collect();
+        let mut open: Vec<BinaryShare> = buys.iter().map(|(_, ok, _)| *ok).collect();
+        matched = crosses;
+        for diagonal in 0..buys.len() + columns - 1 {
+            let cells: Vec<(usize, usize)> = (diagonal.saturating_sub(columns - 1)
+                ..buys.len().min(diagonal + 1))
+                .map(|i| (i, diagonal - i))
+                .collect();
+            let layer: Vec<_> = cells
+                .iter()
+                .map(|&(i, j)| (matched[i * columns + j], available[j]))
+                .collect();
+            let bits = run_and_layer_v1(
+                setup,
+                mpc_encryption,
+                tag_offset_counter,
+                relay,
+                &layer,
+                pool,
+                serverstate,
+            )
+            .await?;
+            let layer: Vec<_> = cells
+                .iter()
+                .zip(bits)
+                .map(|(&(i, _), bit)| (bit, open[i]))
+                .collect();
+            let bits = run_and_layer_v1(
+                setup,
+                mpc_encryption,
+                tag_offset_counter,
+                relay,
+                &layer,
+                pool,
+                serverstate,
+            )
+            .await?;
+            for (&(i, j), bit) in cells.iter().zip(bits) {
+                available[j] = xor_shares(&available[j], &bit);
+                open[i] = xor_shares(&open[i], &bit);
+                matched[i * columns + j] = bit;
             }
         }
     }
 
-    Ok(result)
+    Ok(MatchShares {
+        buys: buys.into_iter().map(|(order, ..)| order).collect(),
+        sells: sells.into_iter().map(|(order, ..)| order).collect(),
+        matched,
+        fill_quantity,
+        filled,
+    })
 }
 
-pub fn setup_handle_orders_sock_v1(
-    instance: Option<[u8; 32]>,
-    shares: &[[Vec<OrderShare>; 1]; 3],
-) -> Vec<(SetupMessage, [u8; 32], [Vec<OrderShare>; 1])> {
-    use sha2::{Digest, Sha256};
-    use sl_compute::transport::setup::{NoSigningKey, NoVerifyingKey, ProtocolParticipant};
-    use sl_mpc_mate::message::InstanceId;
-    use std::time::Duration;
+/// Open the matches found by `run_handle_orders_v1`. Call only after
+/// `run_verify` has passed, so a cheating party is caught before any match
+/// is revealed, and compare `view`, which gets the opened bits, with
+/// `run_check_view_v1` before using the result.
+///
+/// Only the match bits of all pairs are opened, in one batch: the nodes
+/// learn which orders matched, not which side was larger nor how much was
+/// executed.
+pub async fn run_open_matches_v1<T, R>(
+    setup: &T,
+    mpc_encryption: &mut MPCEncryption,
+    tag_offset_counter: &mut TagOffsetCounter,
+    relay: &mut FilteredMsgRelay<R>,
+    shares: &MatchShares,
+    view: &mut OpenedView,
+    serverstate: &mut ServerState,
+) -> Result<Vec<OpenedMatch>, ProtocolError>
+where
+    T: CommonSetupMessage,
+    R: Relay,
+{
+    if shares.matched.is_empty() {
+        return Ok(Vec::new());
+    }
 
-    let instance = instance.unwrap_or_else(rand::random);
+    let matched = run_batch_open_binary_share(
+        setup,
+        mpc_encryption,
+        tag_offset_counter,
+        relay,
+        &shares.matched,
+        serverstate,
+    )
+    .await?;
+    view.record(&matched);
+    let sells = shares.sells.len();
+    Ok((0..matched.len())
+        .filter(|&n| matched[n])
+        .map(|n| OpenedMatch {
+            buy: shares.buys[n / sells].clone(),
+            sell: shares.sells[n % sells].clone(),
+            quantity: shares.fill_quantity[n].clone(),
+            filled: shares.filled[n],
+        })
+        .collect())
+}
 
-    // a signing key for each party.
-    let party_sk: Vec<NoSigningKey> = std::iter::repeat_with(|| NoSigningKey)
-        .take(3usize)
-        .collect();
+/// A hash of the values a party opened since the parties last compared
+/// them.
+#[derive(Clone)]
+pub struct OpenedView(Sha256);
 
-    let party_vk: Vec<NoVerifyingKey> = party_sk
+impl Default for OpenedView {
+    fn default() -> Self {
+        OpenedView(Sha256::new_with_prefix(b"darkpool opened view"))
+    }
+}
+
+impl OpenedView {
+    pub fn record(&mut self, opened: &[bool]) {
+        self.0.update((opened.len() as u64).to_be_bytes());
+        self.0
+            .update(opened.iter().map(|&bit| bit as u8).collect::<Vec<_>>());
+    }
+}
+
+/// Compare `view` with the other parties' views of the same openings and
+/// fail if they differ.
+///
+/// Every party shares the hash of its view with the hash as both of its
+/// components, so opening that sharing hands each party the hash of a
+/// neighbour, and around the three parties each pair is compared by one of
+/// its members. A party that opens different values to the other two makes
+/// the honest parties' views differ, and the honest one comparing them
+/// aborts; a party lying about its own hash only fails its own comparison.
+/// The other parties may finish, but a batch is only published with all
+/// three parties' results (see `aggregate`).
+pub async fn run_check_view_v1<T, R>(
+    setup: &T,
+    mpc_encryption: &mut MPCEncryption,
+    tag_offset_counter: &mut TagOffsetCounter,
+    relay: &mut FilteredMsgRelay<R>,
+    view: OpenedView,
+    serverstate: &mut ServerState,
+) -> Result<(), Fault>
+where
+    T: CommonSetupMessage,
+    R: Relay,
+{
+    let ours: Vec<bool> = view
+        .0
+        .finalize()
         .iter()
-        .enumerate()
-        .map(|(party_id, _)| NoVerifyingKey::new(party_id))
+        .flat_map(|byte| (0..8).map(move |k| (byte >> k) & 1 == 1))
+        .collect();
+    let shares: Vec<BinaryShare> = ours
+        .iter()
+        .map(|&bit| BinaryShare {
+            value1: bit,
+            value2: bit,
+        })
         .collect();
+    let theirs = run_batch_open_binary_share(
+        setup,
+        mpc_encryption,
+        tag_offset_counter,
+        relay,
+        &shares,
+        serverstate,
+    )
+    .await?;
+    if theirs != ours {
+        return Err(Fault::Diverged);
+    }
+    Ok(())
+}
+
+fn add_shares(a: &ArithmeticShare, b: &ArithmeticShare) -> ArithmeticShare {
+    ArithmeticShare {
+        value1: a.value1.wrapping_add(&b.value1),
+        value2: a.value2.wrapping_add(&b.value2),
+    }
+}
+
+/// This node's share of the terms of every opened match.
+pub fn fill_shares(matches: &[OpenedMatch]) -> Vec<FillShare> {
+    matches
+        .iter()
+        .map(
+            |OpenedMatch {
+                 buy,
+                 sell,
+                 quantity,
+                 filled,
+             }| FillShare {
+                buy_order_id: buy.order_id,
+                sell_order_id: sell.order_id,
+                symbol: buy.symbol.clone(),
+                buy_timestamp: buy.timestamp,
+                sell_timestamp: sell.timestamp,
+                buy_key: buy.client_key,
+                sell_key: sell.client_key,
+                quantity: quantity.clone(),
+                filled: *filled,
+                price_sum: add_shares(&buy.price, &sell.price),
+            },
+        )
+        .collect()
+}
+
+/// The setup message and seed of `party` in protocol instance `instance`.
+///
+/// `fresh` is randomness of the party's own, drawn anew for every session
+/// and re-key. The seed drives the key exchange of `run_init`, so without
+/// it anyone knowing the instance could derive the session keys; with it
+/// every generation exchanges keys no one else can compute.
+pub fn setup_party_v1(
+    instance: [u8; 32],
+    party: usize,
+    fresh: [u8; 32],
+) -> (SetupMessage, [u8; 32]) {
+    use sl_compute::transport::setup::{NoSigningKey, NoVerifyingKey};
+    use sl_mpc_mate::message::InstanceId;
+    use std::time::Duration;
 
-    party_sk
-        .into_iter()
+    let party_vk: Vec<NoVerifyingKey> = (0..3).map(NoVerifyingKey::new).collect();
+    let setup = SetupMessage::new(InstanceId::new(instance), NoSigningKey, party, party_vk)
+        .with_ttl(Duration::from_secs(1000));
+    let mixin = [party as u8 + 1];
+    let seed = Sha256::new()
+        .chain_update(instance)
+        .chain_update(b"party-seed")
+        .chain_update(mixin)
+        .chain_update(fresh)
+        .finalize()
+        .into();
+    (setup, seed)
+}
+
+pub fn setup_handle_orders_sock_v1(
+    instance: Option<[u8; 32]>,
+    shares: &[[Vec<OrderShare>; 1]; 3],
+) -> Vec<(SetupMessage, [u8; 32], [Vec<OrderShare>; 1])> {
+    let instance = instance.unwrap_or_else(rand::random);
+    shares
+        .iter()
         .enumerate()
-        .map(|(party_id, sk)| {
-            SetupMessage::new(InstanceId::new(instance), sk, party_id, party_vk.clone())
-                .with_ttl(Duration::from_secs(1000))
+        .map(|(party, share)| {
+            let (setup, seed) = setup_party_v1(instance, party, rand::random());
+            (setup, seed, share.clone())
         })
-        .zip(shares.iter())
-        .map(|(setup, share)| {
-            let mixin = [setup.participant_index() as u8 + 1];
+        .collect()
+}
 
-            (
-                setup,
-                Sha256::new()
-                    .chain_update(instance)
-                    .chain_update(b"party-seed")
-                    .chain_update(mixin)
-                    .finalize()
-                    .into(),
-                share.clone(),
-            )
+/// The instance a session moves to at its `generation`-th re-key. Every
+/// party derives the same one, so re-keying needs no coordination. The
+/// instance is public; the new keys come from a fresh key exchange seeded
+/// with each party's own randomness (see `setup_party_v1`).
+pub fn rekey_instance(instance: &[u8; 32], generation: u64) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(instance)
+        .chain_update(b"rekey")
+        .chain_update(generation.to_be_bytes())
+        .finalize()
+        .into()
+}
+
+/// What one party takes away from a batch.
+#[derive(Debug)]
+pub struct PartyOutput {
+    pub party: usize,
+    pub fills: Vec<FillShare>,
+    /// Orders that failed `run_range_check_v1` and were left out of matching.
+    pub out_of_range: Vec<u64>,
+}
+
+/// The step of a session that failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    Init,
+    CommonRandomness,
+    Preprocessing,
+    Matching,
+    Verify,
+    Opening,
+}
+
+impl std::fmt::Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Step::Init => "init",
+            Step::CommonRandomness => "common randomness",
+            Step::Preprocessing => "preprocessing",
+            Step::Matching => "matching",
+            Step::Verify => "verification",
+            Step::Opening => "opening",
         })
-        .collect::<Vec<_>>()
+    }
 }
 
-pub async fn test_handle_orders_sock_v1<T, R>(
+/// What went wrong in a step.
+#[derive(Debug)]
+pub enum Fault {
+    Protocol(ProtocolError),
+    /// Another party opened different values than this one.
+    Diverged,
+}
+
+impl From<ProtocolError> for Fault {
+    fn from(error: ProtocolError) -> Self {
+        Fault::Protocol(error)
+    }
+}
+
+impl std::fmt::Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::Protocol(error) => write!(f, "{}", error),
+            Fault::Diverged => write!(f, "parties opened different values"),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
+/// Why a party's session ended without output.
+#[derive(Debug)]
+pub struct Abort {
+    pub step: Step,
+    pub error: Fault,
+    /// The party's signed transcript, when verification or the comparison
+    /// of opened values failed.
+    pub evidence: Option<Evidence>,
+}
+
+impl Abort {
+    fn at(step: Step) -> impl FnOnce(ProtocolError) -> Abort {
+        move |error| Abort {
+            step,
+            error: error.into(),
+            evidence: None,
+        }
+    }
+}
+
+impl std::fmt::Display for Abort {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed: {}", self.step, self.error)
+    }
+}
+
+impl std::error::Error for Abort {}
+
+/// A party's established MPC session, reused for many matching epochs.
+///
+/// `run_init` and `run_common_randomness` run once per key, not per batch:
+/// the session keeps the `MPCEncryption`, `ServerState` and tag counter
+/// alive in between, so the tags of every epoch are fresh. Each epoch ends
+/// with its own `run_verify` checkpoint before anything is opened, and its
+/// openings are compared with `run_check_view_v1` before any output is
+/// released. After an error the session is unusable and must be dropped.
+pub struct Session<T, R> {
     setup: T,
+    relay: FilteredMsgRelay<TranscriptRelay<R>>,
+    frames: evidence::Transcript,
+    mpc_encryption: MPCEncryption,
+    serverstate: ServerState,
+    tag_offset_counter: TagOffsetCounter,
+    pool: TriplePool,
+    /// Openings since the last `run_check_view_v1`.
+    opened: OpenedView,
+    widths: BitWidths,
+    min_quantity: u64,
+    epochs: u64,
+}
+
+/// Run `run_init` and `run_common_randomness` for `setup`.
+async fn run_session_keys_v1<T, R>(
+    setup: &T,
     seed: Seed,
-    share: Vec<OrderShare>,
-    relay: R,
-) -> Result<(usize, Vec<(OrderShare, OrderShare)>), ProtocolError>
+    relay: &mut FilteredMsgRelay<R>,
+) -> Result<(MPCEncryption, ServerState), Abort>
 where
     T: CommonSetupMessage,
     R: Relay,
 {
     use merlin::Transcript;
-    use sl_compute::{
-        mpc::{common_randomness::run_common_randomness, verify::run_verify},
-        transport::init::run_init,
-    };
-    use sl_mpc_mate::coord::SinkExt;
-
-    let mut relay = FilteredMsgRelay::new(relay);
+    use sl_compute::{mpc::common_randomness::run_common_randomness, transport::init::run_init};
 
     let mut init_seed = [0u8; 32];
     let mut common_randomness_seed = [0u8; 32];
@@ -215,62 +1049,323 @@ where
     transcript.challenge_bytes(b"init-seed", &mut init_seed);
     transcript.challenge_bytes(b"common-randomness-seed", &mut common_randomness_seed);
 
-    let (_sid, mut mpc_encryption) = run_init(&setup, init_seed, &mut relay).await?;
+    let (_sid, mut mpc_encryption) = run_init(setup, init_seed, relay)
+        .await
+        .map_err(Abort::at(Step::Init))?;
 
-    let common_randomness = run_common_randomness(
-        &setup,
-        common_randomness_seed,
-        &mut mpc_encryption,
-        &mut relay,
-    )
-    .await?;
+    let common_randomness =
+        run_common_randomness(setup, common_randomness_seed, &mut mpc_encryption, relay)
+            .await
+            .map_err(Abort::at(Step::CommonRandomness))?;
+
+    Ok((mpc_encryption, ServerState::new(common_randomness)))
+}
+
+impl<T, R> Session<T, R>
+where
+    T: CommonSetupMessage,
+    R: Relay,
+{
+    pub async fn establish(setup: T, seed: Seed, relay: R) -> Result<Self, Abort> {
+        let frames = evidence::Transcript::default();
+        let mut relay = FilteredMsgRelay::new(TranscriptRelay::new(relay, frames.clone()));
+        let (mpc_encryption, serverstate) = run_session_keys_v1(&setup, seed, &mut relay).await?;
+        frames.clear();
+        Ok(Session {
+            setup,
+            relay,
+            frames,
+            mpc_encryption,
+            serverstate,
+            tag_offset_counter: TagOffsetCounter::new(),
+            pool: TriplePool::default(),
+            opened: OpenedView::default(),
+            widths: BitWidths::default(),
+            min_quantity: 1,
+            epochs: 0,
+        })
+    }
 
-    let mut serverstate = ServerState::new(common_randomness);
+    /// Range-check every epoch's orders against `widths` instead of the full
+    /// width.
+    pub fn with_widths(mut self, widths: BitWidths) -> Self {
+        self.widths = widths;
+        self
+    }
 
-    let mut tag_offset_counter = TagOffsetCounter::new();
+    /// Hold every epoch's orders to the instrument's minimum quantity
+    /// instead of 1.
+    pub fn with_min_quantity(mut self, min_quantity: u64) -> Self {
+        self.min_quantity = min_quantity;
+        self
+    }
 
-    let result = run_handle_orders_v1(
-        &setup,
-        &mut mpc_encryption,
-        &mut tag_offset_counter,
-        &mut relay,
-        &share,
-        &mut serverstate,
-    )
-    .await;
-
-    run_verify(
-        &setup,
-        &mut mpc_encryption,
-        &mut tag_offset_counter,
-        &mut relay,
-        &mut serverstate,
-    )
-    .await?;
+    /// Replace the session keys and correlated randomness with fresh ones
+    /// for `setup`, a new instance, over the same relay. Preprocessed
+    /// triples are dropped with the old keys.
+    pub async fn rekey(&mut self, setup: T, seed: Seed) -> Result<(), Abort> {
+        let (mpc_encryption, serverstate) =
+            run_session_keys_v1(&setup, seed, &mut self.relay).await?;
+        self.setup = setup;
+        self.mpc_encryption = mpc_encryption;
+        self.serverstate = serverstate;
+        self.tag_offset_counter = TagOffsetCounter::new();
+        self.pool = TriplePool::default();
+        self.opened = OpenedView::default();
+        self.frames.clear();
+        self.epochs = 0;
+        Ok(())
+    }
+
+    /// Epochs run since the session was established or last re-keyed.
+    pub fn epochs(&self) -> u64 {
+        self.epochs
+    }
+
+    /// AND triples left for the next epochs.
+    pub fn stock(&self) -> usize {
+        self.pool.len()
+    }
+
+    /// Add `count` AND triples to the session's stock, between epochs, with
+    /// the party's secret randomness from `rng`.
+    ///
+    /// The triples are checked by the `run_verify` of the next epoch, and
+    /// nothing is opened until then, so evidence of a failed verification
+    /// also covers the preprocessing before it.
+    pub async fn preprocess(&mut self, count: usize, rng: &mut impl RngCore) -> Result<(), Abort> {
+        let Session {
+            setup,
+            relay,
+            mpc_encryption,
+            serverstate,
+            tag_offset_counter,
+            pool,
+            ..
+        } = self;
+        run_preprocess_v1(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            pool,
+            count,
+            rng,
+            serverstate,
+        )
+        .await
+        .map_err(Abort::at(Step::Preprocessing))
+    }
+
+    /// Match one batch. If the epoch's `run_verify` or the comparison of
+    /// its openings fails, the party's transcript of the epoch is signed by
+    /// `witness` and returned with the abort, and nothing of the batch is.
+    pub async fn run_epoch(
+        &mut self,
+        share: &[OrderShare],
+        witness: &Witness,
+    ) -> Result<PartyOutput, Abort> {
+        use sl_compute::mpc::verify::run_verify;
+
+        self.epochs += 1;
+        let Session {
+            setup,
+            relay,
+            mpc_encryption,
+            serverstate,
+            tag_offset_counter,
+            pool,
+            opened: view,
+            widths,
+            min_quantity,
+            ..
+        } = self;
+
+        // Nothing is opened before run_verify: a party caught cheating must
+        // not have learned, or steered, a single match.
+        let result = async {
+            let checked = run_range_check_v1(
+                setup,
+                mpc_encryption,
+                tag_offset_counter,
+                relay,
+                share,
+                widths,
+                *min_quantity,
+                pool,
+                serverstate,
+            )
+            .await?;
+            let matches = run_handle_orders_v1(
+                setup,
+                mpc_encryption,
+                tag_offset_counter,
+                relay,
+                share,
+                &checked,
+                pool,
+                serverstate,
+            )
+            .await?;
+            Ok::<_, ProtocolError>((checked.in_range, matches))
+        }
+        .await;
+
+        if let Err(error) = run_verify(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            serverstate,
+        )
+        .await
+        {
+            let party = setup.participant_index();
+            return Err(Abort {
+                step: Step::Verify,
+                evidence: Some(witness.testify(party, &self.frames, error.to_string())),
+                error: error.into(),
+            });
+        }
+
+        // Nor does it cover the masked bits opened with triples, which a
+        // party could open differently to each of the others to change the
+        // products. Those are compared before anything else is opened.
+        if let Err(error) = run_check_view_v1(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            std::mem::take(&mut pool.view),
+            serverstate,
+        )
+        .await
+        {
+            let party = setup.participant_index();
+            return Err(Abort {
+                step: Step::Verify,
+                evidence: Some(witness.testify(party, &self.frames, error.to_string())),
+                error,
+            });
+        }
 
-    let _ = relay.close().await;
-    match result {
-        Ok(v) => Ok((setup.participant_index(), v)),
-        Err(e) => Err(e),
+        let (in_range, matches) = result.map_err(Abort::at(Step::Matching))?;
+        let in_range = run_batch_open_binary_share(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            &in_range,
+            serverstate,
+        )
+        .await
+        .map_err(Abort::at(Step::Opening))?;
+        view.record(&in_range);
+        let opened = run_open_matches_v1(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            &matches,
+            view,
+            serverstate,
+        )
+        .await
+        .map_err(Abort::at(Step::Opening))?;
+        // run_verify does not cover the openings after it: a party could
+        // open a different bit to each of the others and change the
+        // matches. Nothing leaves the party until the views agree.
+        if let Err(error) = run_check_view_v1(
+            setup,
+            mpc_encryption,
+            tag_offset_counter,
+            relay,
+            std::mem::take(view),
+            serverstate,
+        )
+        .await
+        {
+            let party = setup.participant_index();
+            return Err(Abort {
+                step: Step::Opening,
+                evidence: Some(witness.testify(party, &self.frames, error.to_string())),
+                error,
+            });
+        }
+        // The transcript of an epoch starts after the last verified one, so
+        // that it includes the preprocessing in between.
+        self.frames.clear();
+        Ok(PartyOutput {
+            party: setup.participant_index(),
+            fills: fill_shares(&opened),
+            out_of_range: share
+                .iter()
+                .zip(in_range)
+                .filter(|(_, in_range)| !in_range)
+                .map(|(order, _)| order.order_id)
+                .collect(),
+        })
+    }
+
+    pub async fn close(mut self) {
+        use sl_mpc_mate::coord::SinkExt;
+
+        let _ = self.relay.close().await;
     }
 }
 
+/// Run one party's session on a single batch. If `run_verify` fails, the
+/// party's transcript is signed by `witness` and returned with the abort.
+pub async fn test_handle_orders_sock_v1<T, R>(
+    setup: T,
+    seed: Seed,
+    share: Vec<OrderShare>,
+    relay: R,
+    witness: Witness,
+) -> Result<PartyOutput, Abort>
+where
+    T: CommonSetupMessage,
+    R: Relay,
+{
+    let mut session = Session::establish(setup, seed, relay).await?;
+    let result = session.run_epoch(&share, &witness).await;
+    session.close().await;
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use chrono::Utc;
+    use ed25519_dalek::SigningKey;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
     use sl_compute::{
         transport::{
             proto::FilteredMsgRelay,
-            setup::{CommonSetupMessage, common::SetupMessage},
+            setup::{CommonSetupMessage, ProtocolParticipant, common::SetupMessage},
             types::ProtocolError,
             utils::{Seed, TagOffsetCounter},
         },
         types::{ArithmeticShare, FieldElement, ServerState},
     };
     use sl_mpc_mate::coord::{MessageRelayService, Relay, SimpleMessageRelay};
+    use std::time::Duration;
     use tokio::task::JoinSet;
 
-    use crate::{types::OrderShare, v1::run_handle_orders_v1};
+    use crate::{
+        evidence::Witness,
+        fault_relay::{FaultProfile, FaultyRelay},
+        fill::share_from_words,
+        timeout_relay::TimeoutRelay,
+        types::OrderShare,
+        v1::{
+            Abort, Fault, OpenedView, PartyOutput, Session, Step, TriplePool, rekey_instance,
+            run_check_view_v1, run_handle_orders_v1, run_open_matches_v1, run_preprocess_v1,
+            run_range_check_v1, setup_handle_orders_sock_v1, setup_party_v1,
+            test_handle_orders_sock_v1,
+        },
+        validate::{BitWidths, FRACTION_BITS},
+    };
 
     async fn test_handle_orders_v1<T, R>(
         setup: T,
@@ -312,30 +1407,103 @@ mod tests {
 
         let mut tag_offset_counter = TagOffsetCounter::new();
 
-        let result = run_handle_orders_v1(
+        // A few triples, so that matching runs both with and without them.
+        let mut pool = TriplePool::default();
+        let result = async {
+            run_preprocess_v1(
+                &setup,
+                &mut mpc_encryption,
+                &mut tag_offset_counter,
+                &mut relay,
+                &mut pool,
+                4,
+                &mut ChaCha20Rng::from_seed(seed),
+                &mut serverstate,
+            )
+            .await?;
+            let checked = run_range_check_v1(
+                &setup,
+                &mut mpc_encryption,
+                &mut tag_offset_counter,
+                &mut relay,
+                &share,
+                &BitWidths::default(),
+                1,
+                &mut pool,
+                &mut serverstate,
+            )
+            .await?;
+            run_handle_orders_v1(
+                &setup,
+                &mut mpc_encryption,
+                &mut tag_offset_counter,
+                &mut relay,
+                &share,
+                &checked,
+                &mut pool,
+                &mut serverstate,
+            )
+            .await
+        }
+        .await;
+
+        run_verify(
             &setup,
             &mut mpc_encryption,
             &mut tag_offset_counter,
             &mut relay,
-            &share,
             &mut serverstate,
         )
-        .await;
-
-        run_verify(
+        .await?;
+        let check = |fault: Fault| match fault {
+            Fault::Protocol(error) => error,
+            Fault::Diverged => panic!("honest parties opened different values"),
+        };
+        run_check_view_v1(
             &setup,
             &mut mpc_encryption,
             &mut tag_offset_counter,
             &mut relay,
+            std::mem::take(&mut pool.view),
             &mut serverstate,
         )
-        .await?;
+        .await
+        .map_err(check)?;
+
+        let result = match result {
+            Ok(matches) => {
+                let mut view = OpenedView::default();
+                let opened = run_open_matches_v1(
+                    &setup,
+                    &mut mpc_encryption,
+                    &mut tag_offset_counter,
+                    &mut relay,
+                    &matches,
+                    &mut view,
+                    &mut serverstate,
+                )
+                .await?;
+                run_check_view_v1(
+                    &setup,
+                    &mut mpc_encryption,
+                    &mut tag_offset_counter,
+                    &mut relay,
+                    view,
+                    &mut serverstate,
+                )
+                .await
+                .map_err(check)?;
+                Ok(opened)
+            }
+            Err(e) => Err(e),
+        };
 
         let _ = relay.close().await;
-        match result {
-            Ok(v) => Ok((setup.participant_index(), v)),
-            Err(e) => Err(e),
-        }
+        let matches = result?
+            .into_iter()
+            .map(|opened| (opened.buy, opened.sell))
+            .collect();
+        Ok((setup.participant_index(), matches))
     }
 
     pub fn setup_handle_orders_v1(
@@ -428,8 +1596,7 @@ mod tests {
         sim_parties_handle_orders_v1(parties, coord).await
     }
 
-    #[tokio::test(flavor = "multi_thread")]
-    async fn test_handle_orders_v1_protocol() {
+    fn sample_shares() -> [[Vec<OrderShare>; 1]; 3] {
         let orders = [
             (false, "abc1", 100, 200, 50, Utc::now()),
             (true, "abc2", 100, 200, 50, Utc::now()),
@@ -441,7 +1608,7 @@ mod tests {
         let mut orders_p2 = Vec::new();
         let mut orders_p3 = Vec::new();
 
-        for (typ, symb, quan, pric, mine, ts) in orders {
+        for (id, (typ, symb, quan, pric, mine, ts)) in orders.into_iter().enumerate() {
             let quan1 = ArithmeticShare::from_constant(&FieldElement::from(quan as u64), 0);
             let quan2 = ArithmeticShare::from_constant(&FieldElement::from(quan as u64), 1);
             let quan3 = ArithmeticShare::from_constant(&FieldElement::from(quan as u64), 2);
@@ -455,34 +1622,45 @@ mod tests {
             let mine3 = ArithmeticShare::from_constant(&FieldElement::from(mine as u64), 2);
 
             orders_p1.push(OrderShare {
+                order_id: id as u64,
                 o_type: typ,
                 symbol: symb.to_owned(),
                 quantity: quan1,
                 price: pric1,
                 min_execution: mine1,
                 timestamp: ts,
+                client_key: None,
             });
 
             orders_p2.push(OrderShare {
+                order_id: id as u64,
                 o_type: typ,
                 symbol: symb.to_owned(),
                 quantity: quan2,
                 price: pric2,
                 min_execution: mine2,
                 timestamp: ts,
+                client_key: None,
             });
 
             orders_p3.push(OrderShare {
+                order_id: id as u64,
                 o_type: typ,
                 symbol: symb.to_owned(),
                 quantity: quan3,
                 price: pric3,
                 min_execution: mine3,
                 timestamp: ts,
+                client_key: None,
             });
         }
 
-        let shares = [[orders_p1], [orders_p2], [orders_p3]];
+        [[orders_p1], [orders_p2], [orders_p3]]
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handle_orders_v1_protocol() {
+        let shares = sample_shares();
 
         let results = sim_handle_orders_v1(SimpleMessageRelay::new(), &shares).await;
 
@@ -491,4 +1669,320 @@ mod tests {
             println!("output buy:{} sell: {}", b.symbol, s.symbol);
         }
     }
+
+    /// What one party's run came to under injected faults.
+    #[derive(Debug)]
+    enum Outcome {
+        Completed(PartyOutput),
+        Aborted(Abort),
+        /// A lost frame stalls the receiving party; the harness gives up on it.
+        TimedOut,
+    }
+
+    /// Receive timeout of the parties in `sim_faulty_handle_orders_v1`, well
+    /// above any delay a `FaultProfile` of these tests adds.
+    const RECV_TIMEOUT: Duration = Duration::from_secs(2);
+
+    async fn sim_faulty_handle_orders_v1(
+        shares: &[[Vec<OrderShare>; 1]; 3],
+        profile: &FaultProfile,
+        timeout: Duration,
+    ) -> Vec<Outcome> {
+        let coord = SimpleMessageRelay::new();
+        let mut jset = JoinSet::new();
+        for (conn_id, (setup, seed, share)) in setup_handle_orders_sock_v1(Some([7; 32]), shares)
+            .into_iter()
+            .enumerate()
+        {
+            let relay = FaultyRelay::new(
+                coord.connect().await.unwrap(),
+                profile.for_connection(conn_id as u64),
+            );
+            let relay = TimeoutRelay::new(relay, RECV_TIMEOUT);
+            let index = setup.participant_index();
+            jset.spawn(async move {
+                let witness = Witness::new([7; 32], &SigningKey::from_bytes(&rand::random()));
+                let run = test_handle_orders_sock_v1(setup, seed, share[0].clone(), relay, witness);
+                let outcome = match tokio::time::timeout(timeout, run).await {
+                    Ok(Ok(output)) => Outcome::Completed(output),
+                    Ok(Err(err)) => Outcome::Aborted(err),
+                    Err(_) => Outcome::TimedOut,
+                };
+                (index, outcome)
+            });
+        }
+
+        let mut outcomes = vec![];
+        while let Some(fini) = jset.join_next().await {
+            // A panic in any party fails the test here.
+            outcomes.push(fini.unwrap());
+        }
+        outcomes.sort_by_key(|o| o.0);
+        outcomes.into_iter().map(|o| o.1).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_session_runs_epochs_across_rekeys() {
+        let coord = SimpleMessageRelay::new();
+        let instance = [4; 32];
+        let shares = sample_shares();
+        let mut jset = JoinSet::new();
+        for (party, share) in shares.into_iter().enumerate() {
+            let relay = coord.connect().await.unwrap();
+            jset.spawn(async move {
+                let (setup, seed) = setup_party_v1(instance, party, rand::random());
+                let mut session = Session::establish(setup, seed, relay).await.unwrap();
+                let witness = Witness::new(instance, &SigningKey::from_bytes(&rand::random()));
+                let mut fills = vec![];
+                for epoch in 0..3 {
+                    if epoch == 2 {
+                        assert_eq!(session.epochs(), 2);
+                        let (setup, seed) =
+                            setup_party_v1(rekey_instance(&instance, 1), party, rand::random());
+                        session.rekey(setup, seed).await.unwrap();
+                    }
+                    let output = session.run_epoch(&share[0], &witness).await.unwrap();
+                    fills.push(output.fills.len());
+                }
+                session.close().await;
+                fills
+            });
+        }
+
+        while let Some(fini) = jset.join_next().await {
+            assert_eq!(fini.unwrap(), [2, 2, 2]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_diverged_openings_withhold_matches() {
+        let coord = SimpleMessageRelay::new();
+        let instance = [5; 32];
+        let mut jset = JoinSet::new();
+        for (party, share) in sample_shares().into_iter().enumerate() {
+            let relay = coord.connect().await.unwrap();
+            jset.spawn(async move {
+                let (setup, seed) = setup_party_v1(instance, party, rand::random());
+                let mut session = Session::establish(setup, seed, relay).await.unwrap();
+                // As if party 1 had been opened a different bit than the others.
+                if party == 1 {
+                    session.opened.record(&[true]);
+                }
+                let witness = Witness::new(instance, &SigningKey::from_bytes(&rand::random()));
+                let run = session.run_epoch(&share[0], &witness);
+                match tokio::time::timeout(Duration::from_secs(60), run).await {
+                    Ok(Ok(output)) => Outcome::Completed(output),
+                    Ok(Err(abort)) => Outcome::Aborted(abort),
+                    Err(_) => Outcome::TimedOut,
+                }
+            });
+        }
+
+        let mut outcomes = vec![];
+        while let Some(fini) = jset.join_next().await {
+            outcomes.push(fini.unwrap());
+        }
+        // Whoever compares against party 1 refuses its output, with its
+        // signed transcript, so the batch never has all three results.
+        assert!(outcomes.iter().any(|outcome| matches!(
+            outcome,
+            Outcome::Aborted(Abort {
+                step: Step::Opening,
+                error: Fault::Diverged,
+                evidence: Some(_),
+            })
+        )));
+        assert!(
+            !outcomes
+                .iter()
+                .all(|outcome| matches!(outcome, Outcome::Completed(_)))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tampered_masked_opening_aborts_epoch() {
+        let coord = SimpleMessageRelay::new();
+        let instance = [6; 32];
+        let mut jset = JoinSet::new();
+        for (party, share) in sample_shares().into_iter().enumerate() {
+            let relay = coord.connect().await.unwrap();
+            jset.spawn(async move {
+                let (setup, seed) = setup_party_v1(instance, party, rand::random());
+                let mut session = Session::establish(setup, seed, relay).await.unwrap();
+                session
+                    .preprocess(64, &mut ChaCha20Rng::from_seed(seed))
+                    .await
+                    .unwrap();
+                // As if party 2 had been opened a different masked bit.
+                if party == 2 {
+                    session.pool.view.record(&[false]);
+                }
+                let witness = Witness::new(instance, &SigningKey::from_bytes(&rand::random()));
+                let run = session.run_epoch(&share[0], &witness);
+                match tokio::time::timeout(Duration::from_secs(60), run).await {
+                    Ok(Ok(output)) => Outcome::Completed(output),
+                    Ok(Err(abort)) => Outcome::Aborted(abort),
+                    Err(_) => Outcome::TimedOut,
+                }
+            });
+        }
+
+        let mut outcomes = vec![];
+        while let Some(fini) = jset.join_next().await {
+            outcomes.push(fini.unwrap());
+        }
+        // The views are compared right after run_verify, so the epoch
+        // aborts before a single range check or match is opened.
+        assert!(outcomes.iter().any(|outcome| matches!(
+            outcome,
+            Outcome::Aborted(Abort {
+                step: Step::Verify,
+                error: Fault::Diverged,
+                evidence: Some(_),
+            })
+        )));
+        assert!(
+            outcomes
+                .iter()
+                .all(|outcome| !matches!(outcome, Outcome::Completed(_)))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_out_of_range_share_is_not_matched() {
+        // A client-supplied share whose encoding lands past the sign bit.
+        let mut shares = sample_shares();
+        for (party, share) in shares.iter_mut().enumerate() {
+            share[0][0].quantity =
+                ArithmeticShare::from_constant(&FieldElement::from((1u64 << 53) + 5), party);
+        }
+
+        let outcomes = sim_faulty_handle_orders_v1(
+            &shares,
+            &FaultProfile::default(),
+            Duration::from_secs(120),
+        )
+        .await;
+
+        for outcome in outcomes {
+            let Outcome::Completed(output) = outcome else {
+                panic!("range check must not abort: {:?}", outcome);
+            };
+            assert_eq!(output.out_of_range, [0]);
+            assert_eq!(output.fills.len(), 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fractional_share_is_not_matched() {
+        // A client-supplied quantity of 5 lots and a fraction of one: its
+        // integer bits alone would read as a valid 5.
+        let mut shares = sample_shares();
+        for (party, share) in shares.iter_mut().enumerate() {
+            let word = if party == 0 {
+                (5 << FRACTION_BITS) + 100
+            } else {
+                0
+            };
+            share[0][0].quantity = share_from_words([word, word]);
+        }
+
+        let outcomes = sim_faulty_handle_orders_v1(
+            &shares,
+            &FaultProfile::default(),
+            Duration::from_secs(120),
+        )
+        .await;
+
+        for outcome in outcomes {
+            let Outcome::Completed(output) = outcome else {
+                panic!("range check must not abort: {:?}", outcome);
+            };
+            assert_eq!(output.out_of_range, [0]);
+            assert_eq!(output.fills.len(), 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handle_orders_v1_with_latency_and_reordering() {
+        let profile = FaultProfile {
+            seed: 1,
+            latency: Duration::from_millis(2),
+            jitter: Duration::from_millis(5),
+            reorder_rate: 0.2,
+            reorder_window: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        let outcomes =
+            sim_faulty_handle_orders_v1(&sample_shares(), &profile, Duration::from_secs(120)).await;
+
+        assert_eq!(outcomes.len(), 3);
+        for outcome in &outcomes {
+            match outcome {
+                Outcome::Completed(output) => assert_eq!(output.fills.len(), 2),
+                other => panic!("delays alone must not break the protocol: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handle_orders_v1_tolerates_duplication() {
+        for seed in 1..=3 {
+            let profile = FaultProfile {
+                seed,
+                duplicate_rate: 0.05,
+                ..Default::default()
+            };
+
+            let outcomes =
+                sim_faulty_handle_orders_v1(&sample_shares(), &profile, Duration::from_secs(10))
+                    .await;
+
+            // Duplicate frames are ignored by the filtered relay.
+            assert_eq!(outcomes.len(), 3);
+            for outcome in &outcomes {
+                match outcome {
+                    Outcome::Completed(output) => assert_eq!(output.fills.len(), 2),
+                    other => panic!("seed {}: duplicates must be tolerated: {:?}", seed, other),
+                }
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handle_orders_v1_times_out_on_loss() {
+        for seed in 1..=3 {
+            let profile = FaultProfile {
+                seed,
+                drop_rate: 0.05,
+                ..Default::default()
+            };
+
+            let outcomes =
+                sim_faulty_handle_orders_v1(&sample_shares(), &profile, Duration::from_secs(10))
+                    .await;
+
+            // Nothing retransmits a lost frame, so its receiver gives up after
+            // RECV_TIMEOUT and fails the step it is in. A party that got all
+            // its frames may still finish, and then with the right matches.
+            assert_eq!(outcomes.len(), 3);
+            let mut timed_out = 0;
+            for outcome in &outcomes {
+                match outcome {
+                    Outcome::Completed(output) => assert_eq!(output.fills.len(), 2),
+                    Outcome::Aborted(Abort {
+                        error: Fault::Protocol(_),
+                        ..
+                    }) => timed_out += 1,
+                    other => panic!("seed {}: loss must fail the step: {:?}", seed, other),
+                }
+            }
+            assert!(
+                timed_out > 0,
+                "seed {}: a lost frame must time out its receiver",
+                seed
+            );
+        }
+    }
 }
@@ -0,0 +1,383 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::ecies;
+use crate::fill::ClientKey;
+use crate::instruments::{Instrument, InstrumentMaster, TradingStatus};
+use crate::price::PriceScale;
+use crate::types::Order;
+
+/// Fractional bits `ArithmeticShare::from_constant` adds to every value.
+pub const FRACTION_BITS: u32 = 10;
+
+/// Bits an order value may use. Encoded values must stay clear of the sign
+/// bit of the 64-bit comparison, with one bit of headroom because the fill
+/// adds the two limit prices.
+pub const VALUE_BITS: u32 = 64 - FRACTION_BITS - 2;
+
+/// Largest quantity, price or minimum execution an order may carry.
+pub const MAX_VALUE: u64 = (1 << VALUE_BITS) - 1;
+
+/// Bits the fixed-point encoding of each order field may use, its
+/// `FRACTION_BITS` included: 32-bit quantities and 40-bit prices hold
+/// quantities up to 2^22 - 1 and prices up to 2^30 - 1. The range check in
+/// MPC holds every order to these widths, so comparisons of its fields only
+/// depend on that many low bits. Both default to the full width of
+/// `MAX_VALUE`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BitWidths {
+    /// Width of quantities and minimum executions.
+    pub quantity_bits: u32,
+    pub price_bits: u32,
+}
+
+impl Default for BitWidths {
+    fn default() -> Self {
+        BitWidths {
+            quantity_bits: FRACTION_BITS + VALUE_BITS,
+            price_bits: FRACTION_BITS + VALUE_BITS,
+        }
+    }
+}
+
+impl BitWidths {
+    /// Whether every width leaves room for one integer bit and fits in the
+    /// full width.
+    pub fn is_valid(&self) -> bool {
+        [self.quantity_bits, self.price_bits]
+            .iter()
+            .all(|bits| (FRACTION_BITS + 1..=FRACTION_BITS + VALUE_BITS).contains(bits))
+    }
+
+    /// Integer bits of a quantity at this width, which matching works on.
+    pub fn quantity_integer_bits(&self) -> usize {
+        (self.quantity_bits - FRACTION_BITS) as usize
+    }
+
+    /// Integer bits of a price at this width.
+    pub fn price_integer_bits(&self) -> usize {
+        (self.price_bits - FRACTION_BITS) as usize
+    }
+
+    /// Largest quantity or minimum execution an order may carry.
+    pub fn max_quantity(&self) -> u64 {
+        (1 << (self.quantity_bits - FRACTION_BITS)) - 1
+    }
+
+    pub fn max_price(&self) -> u64 {
+        (1 << (self.price_bits - FRACTION_BITS)) - 1
+    }
+}
+
+/// Why an order was refused. Serialized as a stable snake_case code for
+/// clients to act on; `Display` gives the human-readable reason.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectCode {
+    ZeroQuantity,
+    /// The quantity is below the instrument's minimum order size.
+    BelowMinimum,
+    /// The quantity is not a whole number of lots.
+    OddLot,
+    ZeroPrice,
+    /// The price is not a multiple of its symbol's tick size.
+    OffTick,
+    MinExecutionAboveQuantity,
+    /// A value does not fit in its `BitWidths`, or a client-supplied share
+    /// failed the range check in MPC.
+    OutOfRange,
+    /// A party could not decrypt its share of the order.
+    BadShare,
+    UnknownSymbol,
+    /// The instrument is halted.
+    Halted,
+    /// The client key, or a party key the order is sealed to, is a
+    /// low-order point, so anyone could read what is encrypted to it.
+    WeakKey,
+    /// The order is signed with a key that is not in the keystore.
+    UnknownKey,
+    BadSignature,
+    /// The key's nonce was used by an earlier order.
+    ReusedNonce,
+    /// The nonce is not a timestamp within the book's nonce window.
+    StaleNonce,
+    /// The signing key belongs to another trader than the one logged in.
+    WrongTrader,
+}
+
+impl std::fmt::Display for RejectCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectCode::ZeroQuantity => write!(f, "quantity must be positive"),
+            RejectCode::BelowMinimum => write!(f, "quantity is below the minimum order size"),
+            RejectCode::OddLot => write!(f, "quantity is not a multiple of the lot size"),
+            RejectCode::ZeroPrice => write!(f, "price must be positive"),
+            RejectCode::OffTick => write!(f, "price is not a multiple of the tick size"),
+            RejectCode::MinExecutionAboveQuantity => {
+                write!(f, "min_execution exceeds quantity")
+            }
+            RejectCode::OutOfRange => write!(f, "value exceeds its bit width"),
+            RejectCode::BadShare => write!(f, "a party's share does not decrypt"),
+            RejectCode::UnknownSymbol => write!(f, "unknown symbol"),
+            RejectCode::Halted => write!(f, "instrument is halted"),
+            RejectCode::WeakKey => write!(f, "key is a low-order point"),
+            RejectCode::UnknownKey => write!(f, "unknown signing key"),
+            RejectCode::BadSignature => write!(f, "invalid signature"),
+            RejectCode::ReusedNonce => write!(f, "nonce already used"),
+            RejectCode::StaleNonce => write!(f, "nonce is not a current timestamp"),
+            RejectCode::WrongTrader => write!(f, "signing key belongs to another trader"),
+        }
+    }
+}
+
+/// Checks orders in the clear before they are sealed, so nothing malformed
+/// is ever secret-shared. The backend only sees the public fields of a sealed
+/// order and checks those with `check_symbol`; the range check in MPC covers
+/// the rest.
+#[derive(Clone, Debug, Default)]
+pub struct Validator {
+    /// Tradable symbols; any symbol is accepted when empty.
+    symbols: BTreeSet<String>,
+    widths: BitWidths,
+    /// Price encoding per symbol; whole units without a tick for the rest.
+    price_scales: BTreeMap<String, PriceScale>,
+    /// Lot sizes, minimum sizes and status of the instrument master, if any.
+    instruments: BTreeMap<String, Instrument>,
+}
+
+impl Validator {
+    pub fn new(symbols: BTreeSet<String>) -> Self {
+        Validator {
+            symbols,
+            widths: BitWidths::default(),
+            price_scales: BTreeMap::new(),
+            instruments: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_widths(mut self, widths: BitWidths) -> Self {
+        self.widths = widths;
+        self
+    }
+
+    pub fn with_price_scales(mut self, price_scales: BTreeMap<String, PriceScale>) -> Self {
+        self.price_scales = price_scales;
+        self
+    }
+
+    /// Accept only the instruments of `master`, with their price scales.
+    pub fn with_instruments(mut self, master: &InstrumentMaster) -> Self {
+        self.symbols = master.instruments.keys().cloned().collect();
+        self.price_scales = master
+            .instruments
+            .iter()
+            .map(|(symbol, instrument)| (symbol.clone(), instrument.price_scale()))
+            .collect();
+        self.instruments = master.instruments.clone();
+        self
+    }
+
+    pub fn instruments(&self) -> &BTreeMap<String, Instrument> {
+        &self.instruments
+    }
+
+    pub fn widths(&self) -> BitWidths {
+        self.widths
+    }
+
+    pub fn price_scales(&self) -> &BTreeMap<String, PriceScale> {
+        &self.price_scales
+    }
+
+    pub fn price_scale(&self, symbol: &str) -> PriceScale {
+        self.price_scales.get(symbol).copied().unwrap_or_default()
+    }
+
+    /// The integer ticks `order` is sealed with for its price.
+    pub fn encode_price(&self, order: &Order) -> Result<u64, RejectCode> {
+        self.price_scale(&order.symbol).encode(order.price)
+    }
+
+    /// Lot size of `symbol`: its instrument's, or 1.
+    pub fn lot_size(&self, symbol: &str) -> u64 {
+        self.instruments
+            .get(symbol)
+            .map_or(1, |instrument| instrument.lot_size)
+    }
+
+    /// The whole lots `order` is sealed with for its quantity and minimum
+    /// execution, the latter rounded up since fills come in lots. Every
+    /// whole number of lots is a valid quantity, so the range check holds
+    /// client-supplied quantities to the lot size too.
+    pub fn encode_quantities(&self, order: &Order) -> [u64; 2] {
+        let lot = self.lot_size(&order.symbol);
+        [order.quantity / lot, order.min_execution.div_ceil(lot)]
+    }
+
+    pub fn check(&self, order: &Order) -> Result<(), RejectCode> {
+        if order.quantity == 0 {
+            return Err(RejectCode::ZeroQuantity);
+        }
+        let price = self.encode_price(order)?;
+        let [quantity, min_execution] = self.encode_quantities(order);
+        if quantity > self.widths.max_quantity()
+            || min_execution > self.widths.max_quantity()
+            || price > self.widths.max_price()
+        {
+            return Err(RejectCode::OutOfRange);
+        }
+        if order.min_execution > order.quantity {
+            return Err(RejectCode::MinExecutionAboveQuantity);
+        }
+        check_client_key(order.client_key.as_ref())?;
+        self.check_symbol(&order.symbol)?;
+        match self.instruments.get(&order.symbol) {
+            Some(instrument) => instrument.check(order),
+            None => Ok(()),
+        }
+    }
+
+    pub fn check_symbol(&self, symbol: &str) -> Result<(), RejectCode> {
+        if !self.symbols.is_empty() && !self.symbols.contains(symbol) {
+            return Err(RejectCode::UnknownSymbol);
+        }
+        if self
+            .instruments
+            .get(symbol)
+            .is_some_and(|instrument| instrument.status == TradingStatus::Halted)
+        {
+            return Err(RejectCode::Halted);
+        }
+        Ok(())
+    }
+}
+
+/// Refuse a client key that fills could not be kept secret under. The
+/// backend calls this on sealed orders too, since their client key is public.
+pub fn check_client_key(key: Option<&ClientKey>) -> Result<(), RejectCode> {
+    match key {
+        Some(key) if ecies::is_low_order(&key.0) => Err(RejectCode::WeakKey),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use crate::fill::ClientKey;
+    use crate::instruments::{Instrument, InstrumentMaster, TradingStatus};
+    use crate::price::PriceScale;
+    use crate::types::Order;
+    use crate::validate::{BitWidths, MAX_VALUE, RejectCode, Validator};
+
+    #[test]
+    fn test_reject_codes() {
+        let validator = Validator::new(BTreeSet::from(["MSFT".to_owned()]));
+        let order = Order {
+            o_type: false,
+            symbol: "MSFT".into(),
+            quantity: 100,
+            price: Decimal::from(150),
+            min_execution: 50,
+            client_key: None,
+        };
+        assert_eq!(validator.check(&order), Ok(()));
+
+        let reject = |change: fn(&mut Order)| {
+            let mut order = order.clone();
+            change(&mut order);
+            validator.check(&order).unwrap_err()
+        };
+        assert_eq!(reject(|o| o.quantity = 0), RejectCode::ZeroQuantity);
+        assert_eq!(reject(|o| o.price = Decimal::ZERO), RejectCode::ZeroPrice);
+        assert_eq!(
+            reject(|o| o.min_execution = 101),
+            RejectCode::MinExecutionAboveQuantity
+        );
+        assert_eq!(
+            reject(|o| o.price = Decimal::from(MAX_VALUE + 1)),
+            RejectCode::OutOfRange
+        );
+        assert_eq!(
+            reject(|o| o.client_key = Some(ClientKey([0; 32]))),
+            RejectCode::WeakKey
+        );
+
+        let widths = BitWidths {
+            quantity_bits: 32,
+            price_bits: 40,
+        };
+        assert!(widths.is_valid());
+        assert!(
+            !BitWidths {
+                price_bits: 10,
+                ..widths
+            }
+            .is_valid()
+        );
+        let narrow = validator.clone().with_widths(widths);
+        let mut order = order.clone();
+        order.price = Decimal::from((1u64 << 30) - 1);
+        assert_eq!(narrow.check(&order), Ok(()));
+        order.price = Decimal::from(1u64 << 30);
+        assert_eq!(narrow.check(&order), Err(RejectCode::OutOfRange));
+        order.price = Decimal::from(150);
+        order.quantity = 1 << 22;
+        assert_eq!(narrow.check(&order), Err(RejectCode::OutOfRange));
+        assert_eq!(
+            reject(|o| o.symbol = "AAPL".into()),
+            RejectCode::UnknownSymbol
+        );
+
+        let cents = validator.with_price_scales(BTreeMap::from([(
+            "MSFT".to_owned(),
+            PriceScale {
+                scale: 2,
+                tick_size: Some("0.05".parse().unwrap()),
+            },
+        )]));
+        order.price = "150.25".parse().unwrap();
+        assert_eq!(cents.encode_price(&order), Ok(3005));
+        order.price = "150.26".parse().unwrap();
+        assert_eq!(cents.check(&order), Err(RejectCode::OffTick));
+
+        let master = InstrumentMaster {
+            instruments: BTreeMap::from([
+                (
+                    "AAPL".to_owned(),
+                    Instrument {
+                        scale: 0,
+                        tick_size: None,
+                        lot_size: 10,
+                        min_quantity: 10,
+                        status: TradingStatus::Active,
+                    },
+                ),
+                (
+                    "GME".to_owned(),
+                    Instrument {
+                        scale: 0,
+                        tick_size: None,
+                        lot_size: 1,
+                        min_quantity: 1,
+                        status: TradingStatus::Halted,
+                    },
+                ),
+            ]),
+        };
+        let listed = Validator::default().with_instruments(&master);
+        assert_eq!(listed.check_symbol("GME"), Err(RejectCode::Halted));
+        assert_eq!(listed.check_symbol("MSFT"), Err(RejectCode::UnknownSymbol));
+        order.symbol = "AAPL".into();
+        order.price = Decimal::from(150);
+        order.quantity = 100;
+        order.min_execution = 15;
+        assert_eq!(listed.check(&order), Ok(()));
+        assert_eq!(listed.encode_quantities(&order), [10, 2]);
+        order.quantity = 105;
+        assert_eq!(listed.check(&order), Err(RejectCode::OddLot));
+    }
+}
@@ -1,19 +1,35 @@
 use futures_util::{Sink, SinkExt, Stream, StreamExt};
 use sl_mpc_mate::coord::{MessageSendError, Relay};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_tungstenite::{
+    Connector, connect_async_tls_with_config, tungstenite::Message as WsMessage,
+};
 use url::Url;
+
+use crate::capture::{CaptureWriter, RecordingRelay};
 pub struct WebSocketRelay {
     sink: Pin<Box<dyn Sink<Vec<u8>, Error = MessageSendError> + Send>>,
     stream: Pin<Box<dyn Stream<Item = Result<Vec<u8>, MessageSendError>> + Send>>,
 }
 impl WebSocketRelay {
     pub async fn connect(url: &str) -> Result<Self, MessageSendError> {
+        Self::connect_with_tls(url, None).await
+    }
+
+    /// Connect using `tls` for `wss://` URLs instead of the default web PKI
+    /// configuration.
+    pub async fn connect_with_tls(
+        url: &str,
+        tls: Option<Arc<ClientConfig>>,
+    ) -> Result<Self, MessageSendError> {
         let url = Url::parse(url).map_err(|_| MessageSendError)?;
-        let (ws_stream, _) = connect_async(url.as_str())
-            .await
-            .map_err(|_| MessageSendError)?;
+        let (ws_stream, _) =
+            connect_async_tls_with_config(url.as_str(), None, false, tls.map(Connector::Rustls))
+                .await
+                .map_err(|_| MessageSendError)?;
         let (ws_sink, ws_stream) = ws_stream.split();
         let send_sink = ws_sink
             .with(|frame: Vec<u8>| async move {
@@ -35,6 +51,11 @@ impl WebSocketRelay {
             stream: Box::pin(recv_stream),
         })
     }
+
+    /// Record every frame sent and received on this connection to `capture`.
+    pub fn record(self, capture: CaptureWriter, conn: u64) -> RecordingRelay<Self> {
+        RecordingRelay::new(self, capture, conn)
+    }
 }
 impl Sink<Vec<u8>> for WebSocketRelay {
     type Error = MessageSendError;
@@ -1,10 +1,11 @@
 import subprocess
 import time
-from trading_agent import order
+from trading_agent import order, write_keystore, write_party_configs
 
 def kill_existing_servers():
     print("Killing any existing server processes...")
     subprocess.run(['pkill', '-f', 'relay_server'])
+    subprocess.run(['pkill', '-f', 'target/debug/party'])
     subprocess.run(['pkill', '-f', 'backend'])
     time.sleep(1)
 
@@ -24,16 +25,25 @@ def main():
     from pathlib import Path
     current_dir = Path(__file__).parent
     backend_dir = current_dir.parent.parent / 'backend'
+    write_keystore(backend_dir / 'keystore.toml')
+    write_party_configs(backend_dir)
     print("Starting relay server in a new terminal...")
     open_terminal_and_run({
         'cwd': str(backend_dir),
         'cmd': 'cargo run --bin relay_server'
     }, "Relay Server")
     time.sleep(2)
+    for party in range(3):
+        print(f"Starting party {party} in a new terminal...")
+        open_terminal_and_run({
+            'cwd': str(backend_dir),
+            'cmd': f'cargo run --bin party -- --config party{party}.toml'
+        }, f"Party {party}")
+    time.sleep(2)
     print("Starting main server in a new terminal...")
     open_terminal_and_run({
         'cwd': str(backend_dir),
-        'cmd': 'cargo run --bin backend'
+        'cmd': 'cargo run --bin backend -- --config backend.toml'
     }, "Main Server")
     time.sleep(2)
     print("\nSubmitting orders...")
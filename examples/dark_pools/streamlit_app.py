@@ -10,7 +10,7 @@ import time
 from collections import defaultdict, deque
 from dark_pools_agent import DarkPoolsAgent
 from market_data_agent import MarketDataAgent
-from trading_agent import TradingAgent
+from trading_agent import TradingAgent, write_keystore, write_party_configs
 import asyncio
 from a2a.types import Message, Role, TextPart, Part
 import requests
@@ -105,25 +105,34 @@ with trading_tab:
     with col1:
         if st.button("Start Servers", key="start_servers"):
             try:
-                relay_cmd = f'cd {os.path.abspath("../../backend")}; cargo run --bin relay_server'
-                backend_cmd = f'cd {os.path.abspath("../../backend")}; cargo run --bin backend'
+                backend_dir = os.path.abspath("../../backend")
+                write_keystore(os.path.join(backend_dir, "keystore.toml"))
+                write_party_configs(backend_dir)
+                relay_cmd = f'cd {backend_dir}; cargo run --bin relay_server'
+                party_cmds = [f'cd {backend_dir}; cargo run --bin party -- --config party{party}.toml' for party in range(3)]
+                backend_cmd = f'cd {backend_dir}; cargo run --bin backend -- --config backend.toml'
                 osa_script = f'''
                 tell application "Terminal"
                     do script "{relay_cmd}"
                     delay 1
+                    do script "{party_cmds[0]}"
+                    do script "{party_cmds[1]}"
+                    do script "{party_cmds[2]}"
+                    delay 1
                     do script "{backend_cmd}"
                 end tell
                 '''
                 subprocess.Popen(["osascript", "-e", osa_script])
                 context_id = get_current_session()
                 trading_agent.update_session(context_id, event="Servers started", state="servers_started")
-                st.success("Servers started in new Terminal windows (relay_server and backend).")
+                st.success("Servers started in new Terminal windows (relay_server, three parties and backend).")
             except Exception as e:
                 st.error(f"Error starting servers: {e}")
     with col2:
         if st.button("Stop Servers", key="stop_servers"):
             try:
                 subprocess.run(["pkill", "-f", "relay_server"])
+                subprocess.run(["pkill", "-f", "target/debug/party"])
                 subprocess.run(["pkill", "-f", "backend"])
                 context_id = get_current_session()
                 trading_agent.update_session(context_id, event="Servers stopped", state="servers_stopped")
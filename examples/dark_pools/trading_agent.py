@@ -1,6 +1,154 @@
+import os
 import socket
 import json
+import struct
+import time
 from collections import defaultdict
+from decimal import Decimal
+from pathlib import Path
+from cryptography.hazmat.primitives import hashes
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+from cryptography.hazmat.primitives.asymmetric.x25519 import X25519PrivateKey, X25519PublicKey
+from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+from cryptography.hazmat.primitives.kdf.hkdf import HKDF
+from cryptography.hazmat.primitives.serialization import Encoding, PublicFormat
+
+# Orders are signed with a key registered in the backend's keystore.
+KEY_ID = os.environ.get("DARKPOOL_KEY_ID", "demo-1")
+DEMO_KEY_FILE = Path(__file__).parent / "demo_signing_key.hex"
+
+def load_signing_key():
+    """The Ed25519 key from DARKPOOL_SIGNING_KEY (hex seed), or a demo key kept next to this file."""
+    seed = os.environ.get("DARKPOOL_SIGNING_KEY")
+    if seed is None:
+        if not DEMO_KEY_FILE.exists():
+            DEMO_KEY_FILE.write_text(os.urandom(32).hex())
+        seed = DEMO_KEY_FILE.read_text().strip()
+    return Ed25519PrivateKey.from_private_bytes(bytes.fromhex(seed))
+
+def write_keystore(path, trader="demo"):
+    """Register the signing key under KEY_ID in a backend keystore file."""
+    public_key = load_signing_key().public_key().public_bytes(Encoding.Raw, PublicFormat.Raw)
+    Path(path).write_text(f'[keys.{KEY_ID}]\ntrader = "{trader}"\npublic_key = "{public_key.hex()}"\n')
+
+def write_party_configs(backend_dir):
+    """Write party0-2.toml, one per party process, and backend.toml driving them.
+
+    Each party keeps its own secret key in party<n>.key, created once and reused so
+    that orders sealed before a restart can still be opened, and its evidence
+    signing key in party<n>-evidence.key.
+    """
+    backend_dir = Path(backend_dir)
+    links = []
+    for party in range(3):
+        key_file = backend_dir / f"party{party}.key"
+        evidence_file = backend_dir / f"party{party}-evidence.key"
+        for path in (key_file, evidence_file):
+            if not path.exists():
+                path.touch(mode=0o600)
+                path.write_text(os.urandom(32).hex())
+        token = os.urandom(16).hex()
+        port = 9101 + party
+        (backend_dir / f"party{party}.toml").write_text(
+            f'party = {party}\nsecret_key = "{key_file.name}"\n'
+            f'evidence_key = "{evidence_file.name}"\n'
+            f'control_listen = "127.0.0.1:{port}"\ncontrol_token = "{token}"\n'
+        )
+        links.append(f'[[parties]]\ncontrol_url = "tcp://127.0.0.1:{port}"\ntoken = "{token}"\n')
+    (backend_dir / "backend.toml").write_text('keystore = "keystore.toml"\n\n' + "\n".join(links))
+
+def length_prefixed(data):
+    return struct.pack(">I", len(data)) + data
+
+def header_bytes(order):
+    """Public order fields, as `header_bytes` in backend/src/sealed_order.rs."""
+    out = bytes([int(order["o_type"])]) + length_prefixed(order["symbol"].encode())
+    client_key = order.get("client_key")
+    return out + (b"\x01" + bytes.fromhex(client_key) if client_key else b"\x00")
+
+def share_value(value):
+    """Additive shares of the fixed-point value, one word per party."""
+    encoded = (value << 10) % 2**64
+    x0, x1 = (int.from_bytes(os.urandom(8), "big") for _ in range(2))
+    return [x0, x1, (encoded - x0 - x1) % 2**64]
+
+# Fractional bits of the fixed-point encoding, as `FRACTION_BITS` in backend/src/validate.rs.
+FRACTION_BITS = 10
+
+def check_width(name, value, bits):
+    """The largest value of the field's bit width, as `BitWidths` in backend/src/validate.rs."""
+    if value >= 2 ** (bits - FRACTION_BITS):
+        raise ValueError(f"{name} {value} does not fit in {bits} bits")
+
+def share_payload(terms):
+    """One party's shares, as `OrderSharePayload::to_bytes` in backend/src/sealed_order.rs."""
+    return bytes([2]) + struct.pack(">3Q", *terms)
+
+def encode_price(price, scale):
+    """Whole ticks of the symbol's price scale, one unit when it has no tick size, as `PriceScale::encode` in backend/src/price.rs."""
+    price = Decimal(str(price))
+    if price <= 0:
+        raise ValueError(f"price must be positive: {price}")
+    tick = Decimal(str(scale.get("tick_size") or Decimal(1).scaleb(-scale.get("scale", 0))))
+    if price % tick != 0:
+        raise ValueError(f"price {price} is not a multiple of the tick size {tick}")
+    return int(price / tick)
+
+def check_quantity(quantity, instrument):
+    """Lot and minimum size of the instrument master, which the parties also check on the sealed lots."""
+    if quantity < instrument.get("min_quantity", 1):
+        raise ValueError(f"quantity {quantity} is below the minimum order size")
+    if quantity % instrument.get("lot_size", 1) != 0:
+        raise ValueError(f"quantity {quantity} is not a multiple of the lot size")
+
+def seal_envelope(recipient, info, aad, plaintext):
+    """X25519 + HKDF-SHA256 + AES-256-GCM, as backend/src/ecies.rs."""
+    ephemeral = X25519PrivateKey.generate()
+    ephemeral_key = ephemeral.public_key().public_bytes(Encoding.Raw, PublicFormat.Raw)
+    shared = ephemeral.exchange(X25519PublicKey.from_public_bytes(recipient))
+    key = HKDF(hashes.SHA256(), 32, ephemeral_key + recipient, info).derive(shared)
+    nonce = os.urandom(12)
+    ciphertext = AESGCM(key).encrypt(nonce, plaintext, aad)
+    return {"ephemeral_key": ephemeral_key.hex(), "nonce": nonce.hex(), "ciphertext": ciphertext.hex()}
+
+def seal_order(order, party_keys, price_scales, instruments, widths):
+    """Secret-share quantity, price and min_execution and encrypt each party's share to it."""
+    instrument = instruments.get(order["symbol"], {})
+    check_quantity(order["quantity"], instrument)
+    header = header_bytes(order)
+    # Quantities are sealed in lots, as `Validator::encode_quantities` in backend/src/validate.rs.
+    lot = instrument.get("lot_size", 1)
+    values = {
+        "quantity": order["quantity"] // lot,
+        "price": encode_price(order["price"], price_scales.get(order["symbol"], {})),
+        "min_execution": -(-order["min_execution"] // lot),
+    }
+    # The parties' range check refuses values past the widths, so do not seal them.
+    for name, value in values.items():
+        check_width(name, value, widths["price_bits" if name == "price" else "quantity_bits"])
+    terms = [share_value(value) for value in values.values()]
+    shares = []
+    for party, key in enumerate(party_keys):
+        payload = share_payload([term[party] for term in terms])
+        shares.append(seal_envelope(bytes.fromhex(key), b"darkpool order share", header + bytes([party]), payload))
+    sealed = {"o_type": order["o_type"], "symbol": order["symbol"], "shares": shares}
+    if order.get("client_key"):
+        sealed["client_key"] = order["client_key"]
+    return sealed
+
+def signing_bytes(key_id, nonce, sealed):
+    """Canonical order encoding, as `signing_bytes` in backend/src/keystore.rs."""
+    out = b"darkpool order v1\0" + length_prefixed(key_id.encode()) + struct.pack(">Q", nonce)
+    out += header_bytes(sealed)
+    for share in sealed["shares"]:
+        out += bytes.fromhex(share["ephemeral_key"]) + bytes.fromhex(share["nonce"])
+        out += length_prefixed(bytes.fromhex(share["ciphertext"]))
+    return out
+
+def sign_order(sealed):
+    nonce = time.time_ns()
+    signature = load_signing_key().sign(signing_bytes(KEY_ID, nonce, sealed))
+    return {"key_id": KEY_ID, "nonce": nonce, "order": sealed, "signature": signature.hex()}
 
 def send_order(order):
     # Create a TCP/IP socket
@@ -10,10 +158,24 @@ def send_order(order):
         server_address = ('127.0.0.1', 8080)
         print(f'Connecting to {server_address[0]}:{server_address[1]}')
         sock.connect(server_address)
-        # Send data
-        message = json.dumps(order).encode()
+        # One JSON request per line; the server answers each in order
+        replies = sock.makefile()
+        sock.sendall(b'{"type":"party_keys"}\n')
+        party_keys = json.loads(replies.readline())["keys"]
+        sock.sendall(b'{"type":"price_scales"}\n')
+        price_scales = json.loads(replies.readline())["scales"]
+        sock.sendall(b'{"type":"instruments"}\n')
+        instruments = json.loads(replies.readline())["instruments"]
+        sock.sendall(b'{"type":"widths"}\n')
+        widths = json.loads(replies.readline())["widths"]
+        # Only the parties can read the terms: each gets its share encrypted to its key
+        message = json.dumps({"type": "submit", **sign_order(seal_order(order, party_keys, price_scales, instruments, widths))}) + "\n"
         print(f'Sending order: {order}')
-        sock.sendall(message)
+        sock.sendall(message.encode())
+        sock.shutdown(socket.SHUT_WR)
+        reply = json.loads(replies.readline())
+        print(f'Server replied: {reply}')
+        return reply
     finally:
         print('Closing socket')
         sock.close()
@@ -26,7 +188,7 @@ def order(o_type, symbol, quantity, price, min_execution):
         "price": price,
         "min_execution": min_execution
     }
-    send_order(order)
+    return send_order(order)
 
 class TradingAgent:
     def __init__(self, name):